@@ -0,0 +1,453 @@
+//! Procedural macros for the `apache-nimble` crate. They're re-exported from there, and documented
+//! along with the traits they implement.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::meta::ParseNestedMeta;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields, Ident, Lit};
+
+/// Implements `apache_nimble::host::gatt::GattService` for a struct. See the trait for the
+/// attributes it accepts.
+#[proc_macro_derive(GattService, attributes(service, characteristic, descriptor))]
+pub fn derive_gatt_service(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    gatt_service(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+enum Uuid {
+    Uuid16(u16),
+    Uuid32(u32),
+    Uuid128(u128),
+}
+
+impl Uuid {
+    /// Parses `uuid = 0x1234` or `uuid = "6e400001-b5a3-f393-e0a9-e50e24dcca9e"`.
+    fn parse(meta: &ParseNestedMeta) -> syn::Result<Self> {
+        match meta.value()?.parse()? {
+            Lit::Int(lit) => {
+                let value: u128 = lit.base10_parse()?;
+                Ok(if let Ok(value) = u16::try_from(value) {
+                    Uuid::Uuid16(value)
+                } else if let Ok(value) = u32::try_from(value) {
+                    Uuid::Uuid32(value)
+                } else {
+                    Uuid::Uuid128(value)
+                })
+            }
+            Lit::Str(lit) => {
+                let digits: String = lit.value().chars().filter(|c| *c != '-').collect();
+                if digits.len() != 32 {
+                    return Err(Error::new(lit.span(), "expected a 128-bit UUID"));
+                }
+                u128::from_str_radix(&digits, 16)
+                    .map(Uuid::Uuid128)
+                    .map_err(|_| Error::new(lit.span(), "expected a 128-bit UUID"))
+            }
+            lit => Err(Error::new(lit.span(), "expected an integer or string UUID")),
+        }
+    }
+
+    /// A static holding the UUID in NimBLE's format, and an expression pointing to it.
+    fn to_static(&self, name: &Ident) -> (TokenStream2, TokenStream2) {
+        let definition = match self {
+            Uuid::Uuid16(value) => quote!(static #name: raw::ble_uuid16_t = p::uuid16(#value);),
+            Uuid::Uuid32(value) => quote!(static #name: raw::ble_uuid32_t = p::uuid32(#value);),
+            Uuid::Uuid128(value) => {
+                quote!(static #name: raw::ble_uuid128_t = p::uuid128(#value);)
+            }
+        };
+        let pointer = quote!(&#name as *const _ as *const raw::ble_uuid_t);
+        (definition, pointer)
+    }
+}
+
+struct Descriptor {
+    uuid: Uuid,
+    value: TokenStream2,
+    att_flags: Vec<Ident>,
+    min_key_size: u8,
+}
+
+struct CharacteristicAttr {
+    field: Ident,
+    uuid: Uuid,
+    flags: Vec<Ident>,
+    min_key_size: u8,
+    descriptors: Vec<Descriptor>,
+}
+
+fn parse_service(input: &DeriveInput) -> syn::Result<Uuid> {
+    let mut uuid = None;
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("service")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("uuid") {
+                uuid = Some(Uuid::parse(&meta)?);
+                Ok(())
+            } else {
+                Err(meta.error("unknown service attribute"))
+            }
+        })?;
+    }
+    uuid.ok_or_else(|| Error::new_spanned(&input.ident, "missing #[service(uuid = ...)]"))
+}
+
+fn parse_characteristic(field: &syn::Field) -> syn::Result<Option<CharacteristicAttr>> {
+    let Some(attr) = field
+        .attrs
+        .iter()
+        .find(|a| a.path().is_ident("characteristic"))
+    else {
+        return Ok(None);
+    };
+    let ident = field
+        .ident
+        .clone()
+        .ok_or_else(|| Error::new_spanned(field, "expected a named field"))?;
+
+    let mut uuid = None;
+    let mut flags = Vec::new();
+    let mut min_key_size = 0;
+    attr.parse_nested_meta(|meta| {
+        let flag = match meta.path.get_ident().map(|i| i.to_string()).as_deref() {
+            Some("uuid") => {
+                uuid = Some(Uuid::parse(&meta)?);
+                return Ok(());
+            }
+            Some("min_key_size") => {
+                min_key_size = meta.value()?.parse::<syn::LitInt>()?.base10_parse()?;
+                return Ok(());
+            }
+            Some("read") => "BLE_GATT_CHR_F_READ",
+            Some("write") => "BLE_GATT_CHR_F_WRITE",
+            Some("write_without_response") => "BLE_GATT_CHR_F_WRITE_NO_RSP",
+            Some("notify") => "BLE_GATT_CHR_F_NOTIFY",
+            Some("indicate") => "BLE_GATT_CHR_F_INDICATE",
+            Some("read_encrypted") => "BLE_GATT_CHR_F_READ_ENC",
+            Some("read_authenticated") => "BLE_GATT_CHR_F_READ_AUTHEN",
+            Some("read_authorized") => "BLE_GATT_CHR_F_READ_AUTHOR",
+            Some("write_encrypted") => "BLE_GATT_CHR_F_WRITE_ENC",
+            Some("write_authenticated") => "BLE_GATT_CHR_F_WRITE_AUTHEN",
+            Some("write_authorized") => "BLE_GATT_CHR_F_WRITE_AUTHOR",
+            _ => return Err(meta.error("unknown characteristic attribute")),
+        };
+        flags.push(format_ident!("{}", flag));
+        Ok(())
+    })?;
+    let uuid = uuid.ok_or_else(|| Error::new_spanned(attr, "missing uuid"))?;
+
+    let mut descriptors = Vec::new();
+    for attr in field
+        .attrs
+        .iter()
+        .filter(|a| a.path().is_ident("descriptor"))
+    {
+        let mut uuid = None;
+        let mut value = None;
+        let mut att_flags = vec![format_ident!("BLE_ATT_F_READ")];
+        let mut min_key_size = 0;
+        attr.parse_nested_meta(|meta| {
+            match meta.path.get_ident().map(|i| i.to_string()).as_deref() {
+                Some("uuid") => uuid = Some(Uuid::parse(&meta)?),
+                Some("value") => {
+                    value = Some(match meta.value()?.parse()? {
+                        Lit::Str(lit) => quote!(#lit.as_bytes()),
+                        Lit::ByteStr(lit) => quote!(#lit),
+                        lit => return Err(Error::new(lit.span(), "expected a string")),
+                    })
+                }
+                Some("min_key_size") => {
+                    min_key_size = meta.value()?.parse::<syn::LitInt>()?.base10_parse()?
+                }
+                Some("read_encrypted") => att_flags.push(format_ident!("BLE_ATT_F_READ_ENC")),
+                Some("read_authenticated") => {
+                    att_flags.push(format_ident!("BLE_ATT_F_READ_AUTHEN"))
+                }
+                _ => return Err(meta.error("unknown descriptor attribute")),
+            }
+            Ok(())
+        })?;
+        descriptors.push(Descriptor {
+            uuid: uuid.ok_or_else(|| Error::new_spanned(attr, "missing uuid"))?,
+            value: value.ok_or_else(|| Error::new_spanned(attr, "missing value"))?,
+            att_flags,
+            min_key_size,
+        });
+    }
+
+    Ok(Some(CharacteristicAttr {
+        field: ident,
+        uuid,
+        flags,
+        min_key_size,
+        descriptors,
+    }))
+}
+
+/// ORs the given `raw` flag constants together.
+fn flags(flags: &[Ident], ty: TokenStream2) -> TokenStream2 {
+    if flags.is_empty() {
+        quote!(0)
+    } else {
+        quote!(#(raw::#flags as #ty)|*)
+    }
+}
+
+fn gatt_service(input: DeriveInput) -> syn::Result<TokenStream2> {
+    if !input.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &input.generics,
+            "GATT services can't be generic",
+        ));
+    }
+    let Data::Struct(data) = &input.data else {
+        return Err(Error::new_spanned(&input.ident, "expected a struct"));
+    };
+    let Fields::Named(fields) = &data.fields else {
+        return Err(Error::new_spanned(&input.ident, "expected named fields"));
+    };
+
+    let service_uuid = parse_service(&input)?;
+    let characteristics = fields
+        .named
+        .iter()
+        .filter_map(|f| parse_characteristic(f).transpose())
+        .collect::<syn::Result<Vec<_>>>()?;
+
+    let mut statics = Vec::new();
+    let mut fills = Vec::new();
+
+    for (i, chr) in characteristics.iter().enumerate() {
+        let (uuid_static, uuid_ptr) = chr.uuid.to_static(&format_ident!("CHR_UUID_{}", i));
+        statics.push(uuid_static);
+
+        let min_key_size = chr.min_key_size;
+        let descriptors = if chr.descriptors.is_empty() {
+            quote!(::core::ptr::null_mut())
+        } else {
+            let table = format_ident!("DSCS_{}", i);
+            // terminated by an empty entry
+            let len = chr.descriptors.len() + 1;
+            statics.push(quote! {
+                static #table: p::Static<[raw::ble_gatt_dsc_def; #len]> = p::Static::zeroed();
+            });
+            for (j, dsc) in chr.descriptors.iter().enumerate() {
+                let (uuid_static, uuid_ptr) =
+                    dsc.uuid.to_static(&format_ident!("DSC_UUID_{}_{}", i, j));
+                let value_static = format_ident!("DSC_VALUE_{}_{}", i, j);
+                let value = &dsc.value;
+                let att_flags = flags(&dsc.att_flags, quote!(u8));
+                let dsc_min_key_size = dsc.min_key_size;
+                statics.push(uuid_static);
+                statics.push(quote!(static #value_static: &[u8] = #value;));
+                fills.push(quote! {
+                    (*#table.get())[#j] =
+                        p::descriptor(#uuid_ptr, #att_flags, #dsc_min_key_size, &#value_static);
+                });
+            }
+            quote!((*#table.get()).as_mut_ptr())
+        };
+
+        let field = &chr.field;
+        let chr_flags = flags(&chr.flags, quote!(u16));
+        fills.push(quote! {
+            (*CHRS.get())[#i] = p::characteristic(
+                #uuid_ptr,
+                &self.#field,
+                #chr_flags,
+                #min_key_size,
+                #descriptors,
+            );
+        });
+    }
+
+    let (service_uuid_static, service_uuid_ptr) =
+        service_uuid.to_static(&format_ident!("SERVICE_UUID"));
+    let chrs_len = characteristics.len() + 1;
+    let name = &input.ident;
+
+    Ok(quote! {
+        unsafe impl ::apache_nimble::host::gatt::GattService for #name {
+            fn definitions(
+                &'static self,
+            ) -> ::core::option::Option<*const ::apache_nimble::raw::ble_gatt_svc_def> {
+                use ::apache_nimble::host::gatt::__private as p;
+                use ::apache_nimble::raw;
+
+                #service_uuid_static
+                #(#statics)*
+                static CHRS: p::Static<[raw::ble_gatt_chr_def; #chrs_len]> = p::Static::zeroed();
+                static SERVICES: p::Static<[raw::ble_gatt_svc_def; 2]> = p::Static::zeroed();
+
+                // the tables point into `self`, so they can only be filled in now, for the first
+                // instance registered
+                let filled = self.registration().fill_once(self, || unsafe {
+                    #(#fills)*
+                    (*SERVICES.get())[0] =
+                        p::service(#service_uuid_ptr, (*CHRS.get()).as_ptr());
+                });
+                filled.then(|| unsafe { (*SERVICES.get()).as_ptr() })
+            }
+
+            fn registration(
+                &'static self,
+            ) -> &'static ::apache_nimble::host::gatt::__private::Registration {
+                static REGISTRATION: ::apache_nimble::host::gatt::__private::Registration =
+                    ::apache_nimble::host::gatt::__private::Registration::new();
+                &REGISTRATION
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::parse_quote;
+
+    fn service_uuid(input: DeriveInput) -> Uuid {
+        parse_service(&input).unwrap()
+    }
+
+    fn error(input: DeriveInput) -> String {
+        gatt_service(input).err().unwrap().to_string()
+    }
+
+    #[test]
+    fn integer_uuids_take_the_smallest_size() {
+        let uuid = service_uuid(parse_quote!(
+            #[service(uuid = 0xffff)]
+            struct S;
+        ));
+        assert!(matches!(uuid, Uuid::Uuid16(0xffff)));
+        let uuid = service_uuid(parse_quote!(
+            #[service(uuid = 0x1_0000)]
+            struct S;
+        ));
+        assert!(matches!(uuid, Uuid::Uuid32(0x1_0000)));
+        let uuid = service_uuid(parse_quote!(
+            #[service(uuid = 0xffff_ffff)]
+            struct S;
+        ));
+        assert!(matches!(uuid, Uuid::Uuid32(0xffff_ffff)));
+        let uuid = service_uuid(parse_quote!(
+            #[service(uuid = 0x1_0000_0000)]
+            struct S;
+        ));
+        assert!(matches!(uuid, Uuid::Uuid128(0x1_0000_0000)));
+    }
+
+    #[test]
+    fn string_uuids_are_128_bit() {
+        let uuid = service_uuid(parse_quote! {
+            #[service(uuid = "6e400001-b5a3-f393-e0a9-e50e24dcca9e")]
+            struct S;
+        });
+        assert!(matches!(
+            uuid,
+            Uuid::Uuid128(0x6e400001_b5a3_f393_e0a9_e50e24dcca9e)
+        ));
+
+        let input: DeriveInput = parse_quote! {
+            #[service(uuid = "6e400001-b5a3-f393-e0a9-e50e24dcca")]
+            struct S;
+        };
+        let err = parse_service(&input).err().unwrap();
+        assert_eq!(err.to_string(), "expected a 128-bit UUID");
+        let input: DeriveInput = parse_quote! {
+            #[service(uuid = "6e400001-b5a3-f393-e0a9-e50e24dccaxx")]
+            struct S;
+        };
+        let err = parse_service(&input).err().unwrap();
+        assert_eq!(err.to_string(), "expected a 128-bit UUID");
+    }
+
+    #[test]
+    fn rejects_generic_structs() {
+        let input = parse_quote! {
+            #[service(uuid = 0x180f)]
+            struct S<T> {
+                #[characteristic(uuid = 0x2a19, read)]
+                level: T,
+            }
+        };
+        assert_eq!(error(input), "GATT services can't be generic");
+    }
+
+    #[test]
+    fn rejects_tuple_structs() {
+        let input = parse_quote! {
+            #[service(uuid = 0x180f)]
+            struct S(u8);
+        };
+        assert_eq!(error(input), "expected named fields");
+    }
+
+    #[test]
+    fn rejects_missing_uuids() {
+        let input = parse_quote! {
+            struct S {
+                #[characteristic(uuid = 0x2a19, read)]
+                level: u8,
+            }
+        };
+        assert_eq!(error(input), "missing #[service(uuid = ...)]");
+        let input = parse_quote! {
+            #[service(uuid = 0x180f)]
+            struct S {
+                #[characteristic(read)]
+                level: u8,
+            }
+        };
+        assert_eq!(error(input), "missing uuid");
+    }
+
+    #[test]
+    fn rejects_unknown_attributes() {
+        let input = parse_quote! {
+            #[service(uuid = 0x180f, primary)]
+            struct S {}
+        };
+        assert_eq!(error(input), "unknown service attribute");
+        let input = parse_quote! {
+            #[service(uuid = 0x180f)]
+            struct S {
+                #[characteristic(uuid = 0x2a19, readable)]
+                level: u8,
+            }
+        };
+        assert_eq!(error(input), "unknown characteristic attribute");
+        let input = parse_quote! {
+            #[service(uuid = 0x180f)]
+            struct S {
+                #[characteristic(uuid = 0x2a19, read)]
+                #[descriptor(uuid = 0x2901, value = "level", write)]
+                level: u8,
+            }
+        };
+        assert_eq!(error(input), "unknown descriptor attribute");
+    }
+
+    #[test]
+    fn expands_a_service() {
+        let input = parse_quote! {
+            #[service(uuid = 0x180f)]
+            struct Battery {
+                #[characteristic(uuid = 0x2a19, read, notify)]
+                #[descriptor(uuid = 0x2901, value = "level")]
+                level: u8,
+                other: u8,
+            }
+        };
+        let output = gatt_service(input).unwrap().to_string();
+        assert!(output.contains("GattService for Battery"));
+        assert!(output.contains("p :: uuid16 (6159u16)"));
+        assert!(output.contains("raw :: BLE_GATT_CHR_F_READ as u16"));
+        assert!(output.contains("raw :: BLE_GATT_CHR_F_NOTIFY as u16"));
+        // one characteristic, and the terminating entry
+        assert!(output.contains("raw :: ble_gatt_chr_def ; 2usize"));
+        assert!(output.contains("raw :: ble_gatt_dsc_def ; 2usize"));
+    }
+}
@@ -1,7 +1,386 @@
+use std::collections::BTreeMap;
 use std::env;
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
 
-fn generate_bindings() {
+const SYSCFG_DEFAULTS: &str = "include/syscfg/syscfg_defaults.h";
+
+/// Environment variable pointing to a `nimble.toml` file with syscfg overrides.
+const CONFIG_ENV: &str = "NIMBLE_CONFIG";
+
+/// Prefix for environment variables overriding a single syscfg value, e.g.
+/// `NIMBLE_SYSCFG_BLE_MAX_CONNECTIONS=4`.
+const SYSCFG_ENV_PREFIX: &str = "NIMBLE_SYSCFG_";
+
+/// Syscfg values set by cargo features. These are applied on top of the defaults, and can still be
+/// overridden by the config file or environment variables.
+const FEATURE_SYSCFG: &[(bool, &[(&str, &str)])] = &[
+    // roles are enabled in the defaults, so we only need to switch off the disabled ones
+    (
+        !cfg!(feature = "role-broadcaster"),
+        &[
+            ("BLE_ROLE_BROADCASTER", "0"),
+            ("BLE_LL_ROLE_BROADCASTER", "0"),
+        ],
+    ),
+    (
+        !cfg!(feature = "role-central"),
+        &[("BLE_ROLE_CENTRAL", "0"), ("BLE_LL_ROLE_CENTRAL", "0")],
+    ),
+    (
+        !cfg!(feature = "role-observer"),
+        &[("BLE_ROLE_OBSERVER", "0"), ("BLE_LL_ROLE_OBSERVER", "0")],
+    ),
+    (
+        !cfg!(feature = "role-peripheral"),
+        &[
+            ("BLE_ROLE_PERIPHERAL", "0"),
+            ("BLE_LL_ROLE_PERIPHERAL", "0"),
+        ],
+    ),
+    (
+        cfg!(feature = "phy-2m"),
+        &[("BLE_PHY_2M", "1"), ("BLE_LL_CFG_FEAT_LE_2M_PHY", "1")],
+    ),
+    (
+        cfg!(feature = "phy-coded"),
+        &[
+            ("BLE_PHY_CODED", "1"),
+            ("BLE_LL_CFG_FEAT_LE_CODED_PHY", "1"),
+        ],
+    ),
+    // the ACL buffers already default to 251 bytes, which DLE lets the LL use in a single PDU
+    (
+        cfg!(feature = "data-length-extension"),
+        &[
+            ("BLE_LL_CFG_FEAT_DATA_LEN_EXT", "1"),
+            ("BLE_LL_MAX_PKT_SIZE", "251"),
+            ("BLE_TRANSPORT_ACL_SIZE", "251"),
+        ],
+    ),
+    // LE ping is enabled along with encryption in the defaults
+    (
+        cfg!(feature = "encryption"),
+        &[("BLE_LL_CFG_FEAT_LE_ENCRYPTION", "1")],
+    ),
+    (
+        cfg!(feature = "privacy"),
+        &[("BLE_LL_CFG_FEAT_LL_PRIVACY", "1")],
+    ),
+    // sync transfer and BIGInfo reports need a newer core spec version than the default
+    (
+        cfg!(feature = "periodic-adv"),
+        &[
+            ("BLE_VERSION", "52"),
+            ("BLE_PERIODIC_ADV", "1"),
+            ("BLE_PERIODIC_ADV_SYNC_TRANSFER", "1"),
+            ("BLE_PERIODIC_ADV_SYNC_BIGINFO_REPORTS", "1"),
+            ("BLE_MAX_PERIODIC_SYNCS", "1"),
+            ("BLE_LL_CFG_FEAT_LL_PERIODIC_ADV", "1"),
+            ("BLE_LL_CFG_FEAT_LL_PERIODIC_ADV_SYNC_CNT", "1"),
+            ("BLE_LL_CFG_FEAT_LL_PERIODIC_ADV_SYNC_LIST_CNT", "1"),
+            ("BLE_LL_CFG_FEAT_LL_PERIODIC_ADV_SYNC_TRANSFER", "1"),
+            ("BLE_LL_PERIODIC_ADV_SYNC_BIGINFO_REPORTS", "1"),
+        ],
+    ),
+    // only enabled by default with BLE_VERSION >= 52, but it's purely a host feature
+    (
+        cfg!(feature = "gatt-notify-multiple"),
+        &[("BLE_GATT_NOTIFY_MULTIPLE", "1")],
+    ),
+    // the pairing parameters are set at runtime, through `ble_hs_cfg`
+    (cfg!(feature = "security"), &[("BLE_SM_LEGACY", "1")]),
+    (cfg!(feature = "security-sc"), &[("BLE_SM_SC", "1")]),
+    // distribute the LTK and the IRK (BLE_SM_PAIR_KEY_DIST_ENC | BLE_SM_PAIR_KEY_DIST_ID)
+    (
+        cfg!(feature = "security-bonding"),
+        &[
+            ("BLE_SM_BONDING", "1"),
+            ("BLE_SM_OUR_KEY_DIST", "3"),
+            ("BLE_SM_THEIR_KEY_DIST", "3"),
+        ],
+    ),
+    // the channels are allocated on top of the fixed ones (BLE_L2CAP_MAX_CHANS)
+    (
+        cfg!(feature = "l2cap-coc"),
+        &[("BLE_L2CAP_COC_MAX_NUM", "2")],
+    ),
+    // the enhanced credit based flow control mode was added in 5.2, and a single request can open
+    // up to 5 channels
+    (
+        cfg!(feature = "l2cap-enhanced-coc"),
+        &[
+            ("BLE_VERSION", "52"),
+            ("BLE_L2CAP_ENHANCED_COC", "1"),
+            ("BLE_L2CAP_COC_MAX_NUM", "5"),
+        ],
+    ),
+];
+
+/// Pairs of `(setting, dependency)`: if `setting` is enabled, `dependency` must be as well.
+const SYSCFG_DEPENDENCIES: &[(&str, &str)] = &[
+    ("BLE_ROLE_CENTRAL", "BLE_ROLE_OBSERVER"),
+    ("BLE_ROLE_PERIPHERAL", "BLE_ROLE_BROADCASTER"),
+    ("BLE_LL_ROLE_CENTRAL", "BLE_LL_ROLE_OBSERVER"),
+    ("BLE_LL_ROLE_PERIPHERAL", "BLE_LL_ROLE_BROADCASTER"),
+    ("BLE_LL_CFG_FEAT_LE_PING", "BLE_LL_CFG_FEAT_LE_ENCRYPTION"),
+    (
+        "BLE_LL_CFG_FEAT_LL_PERIODIC_ADV",
+        "BLE_LL_CFG_FEAT_LL_EXT_ADV",
+    ),
+    ("BLE_PERIODIC_ADV", "BLE_EXT_ADV"),
+    (
+        "BLE_LL_CFG_FEAT_LL_PERIODIC_ADV_SYNC_TRANSFER",
+        "BLE_LL_CFG_FEAT_LL_PERIODIC_ADV",
+    ),
+    (
+        "BLE_LL_PERIODIC_ADV_SYNC_BIGINFO_REPORTS",
+        "BLE_LL_CFG_FEAT_LL_PERIODIC_ADV",
+    ),
+    (
+        "BLE_LL_CFG_FEAT_LL_PERIODIC_ADV_SYNC_CNT",
+        "BLE_LL_ROLE_OBSERVER",
+    ),
+    ("BLE_MULTI_ADV_INSTANCES", "BLE_EXT_ADV"),
+    ("BLE_LL_CFG_FEAT_LE_2M_PHY", "BLE_PHY_2M"),
+    ("BLE_LL_CFG_FEAT_LE_CODED_PHY", "BLE_PHY_CODED"),
+];
+
+/// Inclusive ranges that a syscfg value must fall in.
+const SYSCFG_RANGES: &[(&str, i64, i64)] = &[
+    ("BLE_MAX_CONNECTIONS", 1, 32),
+    ("BLE_EXT_ADV_MAX_SIZE", 31, 1650),
+    ("BLE_MULTI_ADV_INSTANCES", 0, 15),
+    ("MSYS_1_BLOCK_COUNT", 1, u16::MAX as i64),
+    ("BLE_LL_MAX_PKT_SIZE", 27, 251),
+    ("BLE_TRANSPORT_ACL_SIZE", 27, 255),
+    ("BLE_LL_RESOLV_LIST_SIZE", 1, 32),
+    ("BLE_LL_CFG_FEAT_LL_PERIODIC_ADV_SYNC_CNT", 0, 8),
+    ("BLE_LL_CFG_FEAT_LL_PERIODIC_ADV_SYNC_LIST_CNT", 0, 8),
+    ("OS_CPUTIME_TIMER_NUM", 0, 5),
+];
+
+/// A syscfg value, as written in the defaults file.
+struct SyscfgDefault {
+    value: String,
+}
+
+impl SyscfgDefault {
+    fn is_string(&self) -> bool {
+        self.value.starts_with('"')
+    }
+}
+
+/// Parses the `#define MYNEWT_VAL_<NAME> <value>` lines of the defaults header.
+fn parse_syscfg_defaults(path: &Path) -> BTreeMap<String, SyscfgDefault> {
+    let contents = fs::read_to_string(path).expect("could not read syscfg defaults");
+    contents
+        .lines()
+        .filter_map(|line| line.strip_prefix("#define MYNEWT_VAL_"))
+        .filter_map(|line| {
+            // strip trailing comments
+            let line = line.split("//").next().unwrap();
+            let (name, value) = line.split_once(char::is_whitespace)?;
+            Some((
+                name.to_string(),
+                SyscfgDefault {
+                    value: value.trim().to_string(),
+                },
+            ))
+        })
+        .collect()
+}
+
+/// Converts a value from the config file or environment to a C expression. Strings are quoted if
+/// the default value is a string literal, otherwise they are used as-is.
+fn syscfg_value(default: &SyscfgDefault, value: &str) -> String {
+    if default.is_string() && !value.starts_with('"') {
+        format!("\"{value}\"")
+    } else {
+        format!("({value})")
+    }
+}
+
+fn toml_syscfg_value(name: &str, default: &SyscfgDefault, value: &toml::Value) -> String {
+    match value {
+        toml::Value::Boolean(b) => syscfg_value(default, if *b { "1" } else { "0" }),
+        toml::Value::Integer(i) => syscfg_value(default, &i.to_string()),
+        toml::Value::String(s) => syscfg_value(default, s),
+        _ => panic!("unsupported value for syscfg setting {name}: {value}"),
+    }
+}
+
+/// Evaluates a syscfg value if it's an integer, or a reference to another syscfg value. More
+/// complex expressions are left to the C compiler.
+fn resolve_syscfg(
+    name: &str,
+    defaults: &BTreeMap<String, SyscfgDefault>,
+    overrides: &BTreeMap<String, String>,
+) -> Option<i64> {
+    let value = overrides
+        .get(name)
+        .map(String::as_str)
+        .or_else(|| defaults.get(name).map(|d| d.value.as_str()))?;
+    let mut value = value.trim();
+    while let Some(inner) = value.strip_prefix('(').and_then(|v| v.strip_suffix(')')) {
+        value = inner.trim();
+    }
+
+    if let Some(hex) = value.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Ok(i) = value.parse() {
+        Some(i)
+    } else if let Some(other) = value.strip_prefix("MYNEWT_VAL_") {
+        resolve_syscfg(other, defaults, overrides)
+    } else {
+        None
+    }
+}
+
+/// Sets values that depend on other settings, unless they were overridden explicitly.
+fn derive_syscfg(
+    defaults: &BTreeMap<String, SyscfgDefault>,
+    overrides: &mut BTreeMap<String, String>,
+) {
+    // Scale the ACL pools with the number of connections, so that one busy connection can't use up
+    // every buffer. The defaults are enough for a single connection.
+    let connections = resolve_syscfg("BLE_MAX_CONNECTIONS", defaults, overrides).unwrap_or(1);
+    for name in [
+        "BLE_TRANSPORT_ACL_FROM_HS_COUNT",
+        "BLE_TRANSPORT_ACL_FROM_LL_COUNT",
+    ] {
+        let default = resolve_syscfg(name, defaults, &BTreeMap::new()).unwrap_or(0);
+        overrides
+            .entry(name.to_string())
+            .or_insert_with(|| format!("({})", default.max(connections * 8)));
+    }
+
+    // The HAL only compiles in the timers that are enabled, so make sure the one used for cputime
+    // is. Out of range values are reported by `validate_syscfg`.
+    if let Some(timer) = resolve_syscfg("OS_CPUTIME_TIMER_NUM", defaults, overrides) {
+        let name = format!("TIMER_{timer}");
+        if resolve_syscfg(&name, defaults, overrides) == Some(0) {
+            overrides.insert(name, "(1)".to_string());
+        }
+    }
+}
+
+fn validate_syscfg(
+    defaults: &BTreeMap<String, SyscfgDefault>,
+    overrides: &BTreeMap<String, String>,
+) {
+    let resolve = |name| resolve_syscfg(name, defaults, overrides);
+
+    for (setting, dependency) in SYSCFG_DEPENDENCIES {
+        if resolve(setting).is_some_and(|v| v != 0) && resolve(dependency) == Some(0) {
+            panic!("syscfg setting {setting} requires {dependency} to be enabled");
+        }
+    }
+
+    const ROLES: &[&str] = &[
+        "BLE_ROLE_BROADCASTER",
+        "BLE_ROLE_CENTRAL",
+        "BLE_ROLE_OBSERVER",
+        "BLE_ROLE_PERIPHERAL",
+    ];
+    if ROLES.iter().all(|role| resolve(role) == Some(0)) {
+        panic!("at least one BLE role needs to be enabled");
+    }
+
+    for (setting, min, max) in SYSCFG_RANGES {
+        if let Some(v) = resolve(setting) {
+            if v < *min || v > *max {
+                panic!("syscfg setting {setting} must be between {min} and {max}, got {v}");
+            }
+        }
+    }
+}
+
+/// Generates `syscfg/syscfg.h` in `out_dir`, and returns the include directory containing it.
+///
+/// Values are taken from (in increasing order of priority) the defaults header, cargo features,
+/// the `nimble.toml` file pointed to by [`CONFIG_ENV`], and `NIMBLE_SYSCFG_<NAME>` environment
+/// variables.
+fn generate_syscfg(out_dir: &Path) -> PathBuf {
+    let defaults = parse_syscfg_defaults(Path::new(SYSCFG_DEFAULTS));
+    let mut overrides = BTreeMap::new();
+
+    let mut set = |name: &str, value: String| {
+        if !defaults.contains_key(name) {
+            panic!("unknown syscfg setting: {name}");
+        }
+        overrides.insert(name.to_string(), value);
+    };
+
+    // cargo features
+    for (_, settings) in FEATURE_SYSCFG.iter().filter(|(enabled, _)| *enabled) {
+        for (name, value) in *settings {
+            set(name, syscfg_value(&defaults[*name], value));
+        }
+    }
+
+    // config file
+    println!("cargo:rerun-if-env-changed={CONFIG_ENV}");
+    if let Ok(path) = env::var(CONFIG_ENV) {
+        println!("cargo:rerun-if-changed={path}");
+        let contents = fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("could not read nimble config {path}: {e}"));
+        let config: toml::Table = contents
+            .parse()
+            .unwrap_or_else(|e| panic!("could not parse nimble config {path}: {e}"));
+        if let Some(syscfg) = config.get("syscfg") {
+            let syscfg = syscfg
+                .as_table()
+                .expect("`syscfg` in the nimble config should be a table");
+            for (name, value) in syscfg {
+                let default = defaults
+                    .get(name)
+                    .unwrap_or_else(|| panic!("unknown syscfg setting: {name}"));
+                set(name, toml_syscfg_value(name, default, value));
+            }
+        }
+    }
+
+    // environment variables
+    for name in defaults.keys() {
+        println!("cargo:rerun-if-env-changed={SYSCFG_ENV_PREFIX}{name}");
+    }
+    for (key, value) in env::vars() {
+        if let Some(name) = key.strip_prefix(SYSCFG_ENV_PREFIX) {
+            let default = defaults
+                .get(name)
+                .unwrap_or_else(|| panic!("unknown syscfg setting: {name}"));
+            set(name, syscfg_value(default, &value));
+        }
+    }
+
+    derive_syscfg(&defaults, &mut overrides);
+    validate_syscfg(&defaults, &overrides);
+
+    let mut header = String::from(
+        "/* Generated by the apache-nimble-sys build script. Do not edit. */\n\n\
+         #ifndef H_MYNEWT_SYSCFG_\n\
+         #define H_MYNEWT_SYSCFG_\n\n",
+    );
+    for (name, value) in &overrides {
+        header.push_str(&format!("#define MYNEWT_VAL_{name} {value}\n"));
+    }
+    header.push_str("\n#include \"syscfg/syscfg_defaults.h\"\n\n#endif\n");
+
+    let include_dir = out_dir.join("include");
+    let syscfg_dir = include_dir.join("syscfg");
+    fs::create_dir_all(&syscfg_dir).unwrap();
+
+    // avoid touching the file if nothing changed, so dependents aren't rebuilt
+    let path = syscfg_dir.join("syscfg.h");
+    if fs::read_to_string(&path).ok().as_deref() != Some(header.as_str()) {
+        fs::write(&path, header).expect("could not write syscfg.h");
+    }
+
+    include_dir
+}
+
+fn generate_bindings(syscfg_include: &Path) {
     let builder = bindgen::Builder::default()
         .use_core()
         .ctypes_prefix("cty")
@@ -20,6 +399,7 @@ fn generate_bindings() {
         .blocklist_type("ble_npl_event")
         .blocklist_type("ble_npl_eventq")
         .blocklist_function("ble_npl_eventq_init")
+        .blocklist_function("ble_npl_eventq_deinit")
         .blocklist_function("ble_npl_eventq_get")
         .blocklist_function("ble_npl_eventq_put")
         .blocklist_function("ble_npl_eventq_remove")
@@ -66,13 +446,14 @@ fn generate_bindings() {
 
     // headers to always generate bindings for: port layer, hci definitions, syscfg
     let builder = builder
+        .clang_arg(format!("-I{}", syscfg_include.display()))
         .clang_arg("-Iinclude")
         .clang_arg("-I../mynewt-nimble/nimble/include")
         .clang_arg("-I../mynewt-nimble/porting/nimble/include")
         .clang_arg("-I../mynewt-nimble/nimble/transport/include")
         .header("../mynewt-nimble/nimble/include/nimble/hci_common.h")
         .header("../mynewt-nimble/porting/nimble/include/hal/hal_timer.h")
-        .header("include/syscfg/syscfg.h");
+        .header(syscfg_include.join("syscfg/syscfg.h").to_str().unwrap());
 
     // controller bindings
     let builder = if cfg!(feature = "controller") {
@@ -113,5 +494,12 @@ fn generate_bindings() {
 fn main() {
     println!("cargo:rerun-if-changed=include");
 
-    generate_bindings();
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let syscfg_include = generate_syscfg(&out_dir);
+
+    // Exposed to the apache-nimble build script as `DEP_NIMBLE_INCLUDE`, so that the C code is
+    // compiled with the same syscfg values that the bindings were generated with.
+    println!("cargo:include={}", syscfg_include.display());
+
+    generate_bindings(&syscfg_include);
 }
@@ -17,8 +17,8 @@
  * under the License.
  */
 
-#ifndef H_MYNEWT_SYSCFG_
-#define H_MYNEWT_SYSCFG_
+#ifndef H_MYNEWT_SYSCFG_DEFAULTS_
+#define H_MYNEWT_SYSCFG_DEFAULTS_
 
 #define MYNEWT_VAL(_name)                       MYNEWT_VAL_ ## _name
 #define MYNEWT_VAL_CHOICE(_name, _val)          MYNEWT_VAL_ ## _name ## __ ## _val
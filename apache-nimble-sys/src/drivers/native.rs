@@ -0,0 +1,38 @@
+//! Driver used when no chip is selected, to run the unit tests on the machine doing the build.
+//! There are no interrupts to manage: the link layer is replaced by a fake one in the tests.
+
+pub fn set_isr(_irqn: cty::c_int, _addr: ::core::option::Option<unsafe extern "C" fn()>) {}
+
+pub fn disable_interrupts() {}
+
+/// This critical section is used internally by nimble through
+/// [`crate::ble_npl_hw_enter_critical`], and [`crate::ble_npl_hw_exit_critical`]. Nothing can
+/// interrupt nimble here, so it only keeps track of whether a critical section is active.
+pub(crate) mod cs_internal {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    pub static CS_FLAG: AtomicBool = AtomicBool::new(false);
+
+    /// Safety: acquire calls must have a corresponding release, properly nested.
+    pub unsafe fn acquire() -> bool {
+        !CS_FLAG.swap(true, Ordering::SeqCst)
+    }
+
+    /// Safety: release calls must have a corresponding acquire, properly nested.
+    pub unsafe fn release(active: bool) {
+        if active {
+            CS_FLAG.store(false, Ordering::SeqCst);
+        }
+    }
+
+    #[inline]
+    pub unsafe fn with_fn<R>(f: impl FnOnce() -> R) -> R {
+        let active = acquire();
+
+        let r = f();
+
+        release(active);
+
+        r
+    }
+}
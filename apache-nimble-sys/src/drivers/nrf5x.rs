@@ -14,6 +14,25 @@ pub fn set_isr(irqn: cty::c_int, addr: ::core::option::Option<unsafe extern "C"
     }
 }
 
+/// Interrupts used by the NimBLE controller.
+const NIMBLE_IRQS: u32 = (1 << (pac::Interrupt::RADIO as u8))
+    | (1 << (pac::Interrupt::RTC0 as u8))
+    | (1 << (pac::Interrupt::RNG as u8));
+
+/// Disables the interrupts used by the NimBLE controller, clears any pending ones, and removes their
+/// handlers. They are set up again by the controller when it's initialized.
+pub fn disable_interrupts() {
+    unsafe {
+        let nvic = &*cortex_m::peripheral::NVIC::PTR;
+        nvic.icer[0].write(NIMBLE_IRQS);
+        nvic.icpr[0].write(NIMBLE_IRQS);
+
+        RADIO_HANDLER = None;
+        RNG_HANDLER = None;
+        RTC0_HANDLER = None;
+    }
+}
+
 static mut RADIO_HANDLER: ::core::option::Option<unsafe extern "C" fn()> = None;
 
 #[interrupt]
@@ -109,12 +128,9 @@ mod cs_impl {
     use core::arch::asm;
     use core::sync::atomic::{compiler_fence, AtomicBool, Ordering};
 
-    use super::pac::Interrupt;
     use cortex_m::peripheral::NVIC;
 
-    const RESERVED_IRQS: u32 = (1 << (Interrupt::RADIO as u8))
-        | (1 << (Interrupt::RTC0 as u8))
-        | (1 << (Interrupt::RNG as u8));
+    const RESERVED_IRQS: u32 = super::NIMBLE_IRQS;
 
     pub static CS_FLAG: AtomicBool = AtomicBool::new(false);
     static mut CS_MASK: [u32; 2] = [0; 2];
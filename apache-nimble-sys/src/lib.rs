@@ -9,8 +9,11 @@
 compile_error!("Please choose a port layer to use.");
 
 #[cfg_attr(feature = "nrf52840", path = "drivers/nrf5x.rs")]
+#[cfg_attr(not(feature = "nrf52840"), path = "drivers/native.rs")]
 mod driver;
 
+pub use driver::disable_interrupts;
+
 // Note: can't use cfg_attr for the port layers since cbindgen won't be able to parse it
 
 #[cfg(feature = "port-layer-embassy")]
@@ -20,6 +23,9 @@ mod embassy_port;
 #[cfg(feature = "port-layer-embassy")]
 pub use embassy_port::*;
 
+// When building for the machine running the build (to run the unit tests), these come from the
+// system's libc instead.
+#[cfg(target_os = "none")]
 mod interop {
     #[cfg(feature = "host")]
     extern crate alloc;
@@ -70,6 +70,18 @@ pub unsafe extern "C" fn ble_npl_eventq_init(evq: *mut ble_npl_eventq) {
     };
 }
 
+/// Drops any queued events, and returns the queue to the pool so it can be initialized again.
+#[no_mangle]
+pub unsafe extern "C" fn ble_npl_eventq_deinit(evq: *mut ble_npl_eventq) {
+    // trace!("eventq deinit: {}", evq);
+    let q = (*evq).ch;
+    while let Ok(ev) = q.q.try_receive() {
+        (*ev).queued = false;
+    }
+    (*evq).len = 0;
+    q.taken.store(false, Ordering::Release);
+}
+
 /// Async replacement for nimble's ble_npl_eventq_get function.
 ///
 /// We need to yield / context switch to other tasks from this function. Normally, this would be an
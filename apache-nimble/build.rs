@@ -2,16 +2,37 @@ use std::path::{Path, PathBuf};
 use std::{env, fs};
 
 fn add_c_files(builder: &mut cc::Build, path: &str) {
+    add_c_files_except(builder, path, &[]);
+}
+
+/// Same as [`add_c_files`], but skips the files in `excluded`.
+fn add_c_files_except(builder: &mut cc::Build, path: &str, excluded: &[&str]) {
     let path = Path::new(path);
     for f in fs::read_dir(path).unwrap() {
         let f = f.unwrap();
         let path = f.path();
-        if path.extension().is_some_and(|e| e == "c") {
+        if path.extension().is_some_and(|e| e == "c")
+            && !excluded.iter().any(|e| f.file_name() == *e)
+        {
             builder.file(path);
         }
     }
 }
 
+/// Controller sources that are only needed by specific roles. They're left out when none of their
+/// roles is enabled.
+const CONTROLLER_ROLE_SOURCES: &[(bool, &[&str])] = &[
+    (cfg!(feature = "role-broadcaster"), &["ble_ll_adv.c"]),
+    (
+        cfg!(feature = "role-observer"),
+        &["ble_ll_scan.c", "ble_ll_scan_aux.c", "ble_ll_sync.c"],
+    ),
+    (
+        cfg!(any(feature = "role-central", feature = "role-peripheral")),
+        &["ble_ll_conn.c", "ble_ll_conn_hci.c", "ble_ll_ctrl.c"],
+    ),
+];
+
 const PORT_LAYER_CRATE_DIR: &str = "../apache-nimble-sys";
 
 #[cfg(feature = "port-layer-embassy")]
@@ -19,6 +40,14 @@ const DEFINE: &str = "DEFINE_EMBASSY";
 
 const CHIP_FEATURES: &[(bool, &str)] = &[(cfg!(feature = "nrf52840"), "NRF52840_XXAA")];
 
+/// Without a chip feature, NimBLE is built for the machine running the build, so that the unit
+/// tests can run there. The link layer and its driver aren't compiled: the tests replace them with
+/// a fake link layer.
+fn is_native() -> bool {
+    CHIP_FEATURES.iter().all(|(enabled, _)| !*enabled)
+        && env::var("TARGET").unwrap() == env::var("HOST").unwrap()
+}
+
 fn set_target_flags(builder: &mut cc::Build) -> String {
     let target = env::var("TARGET").unwrap();
 
@@ -77,7 +106,7 @@ fn set_target_flags(builder: &mut cc::Build) -> String {
     }
 }
 
-fn compile_nimble(generated_port_layer_types: PathBuf) {
+fn compile_nimble(generated_port_layer_types: PathBuf, syscfg_include: PathBuf) {
     let builder = &mut cc::Build::new();
 
     // Define port layer in use
@@ -99,9 +128,10 @@ fn compile_nimble(generated_port_layer_types: PathBuf) {
         .file("../mynewt-nimble/porting/nimble/src/os_cputime_pwr2.c")
         .file("../mynewt-nimble/porting/nimble/src/os_msys_init.c")
         .file("../mynewt-nimble/porting/nimble/src/mem.c");
-    // Generated types from cbindgen must be first out of the following three includes, so that the
-    // dummy types aren't used.
+    // Generated types from cbindgen must be first out of the following includes, so that the
+    // dummy types aren't used. The generated syscfg.h comes from the apache-nimble-sys build script.
     builder.include(generated_port_layer_types);
+    builder.include(syscfg_include);
     builder.include(format!("{PORT_LAYER_CRATE_DIR}/include"));
     builder.include("../mynewt-nimble/porting/nimble/include");
 
@@ -111,14 +141,27 @@ fn compile_nimble(generated_port_layer_types: PathBuf) {
         .file("../mynewt-nimble/ext/tinycrypt/src/utils.c")
         .file("../mynewt-nimble/ext/tinycrypt/src/ccm_mode.c")
         .include("../mynewt-nimble/ext/tinycrypt/include");
+    // LE Secure Connections need ECDH and AES-CMAC
+    if cfg!(feature = "security-sc") {
+        builder
+            .file("../mynewt-nimble/ext/tinycrypt/src/cmac_mode.c")
+            .file("../mynewt-nimble/ext/tinycrypt/src/ecc.c")
+            .file("../mynewt-nimble/ext/tinycrypt/src/ecc_dh.c");
+    }
 
     // Feature-specific components
     if cfg!(feature = "controller") {
-        builder.file("../mynewt-nimble/porting/nimble/src/hal_timer.c");
-
         builder.define("NIMBLE_CFG_CONTROLLER", Some("1"));
-        add_c_files(builder, "../mynewt-nimble/nimble/controller/src");
         builder.include("../mynewt-nimble/nimble/controller/include");
+        if !is_native() {
+            builder.file("../mynewt-nimble/porting/nimble/src/hal_timer.c");
+            let excluded: Vec<&str> = CONTROLLER_ROLE_SOURCES
+                .iter()
+                .filter(|(enabled, _)| !*enabled)
+                .flat_map(|(_, files)| files.iter().copied())
+                .collect();
+            add_c_files_except(builder, "../mynewt-nimble/nimble/controller/src", &excluded);
+        }
     }
 
     if cfg!(feature = "host") {
@@ -132,6 +175,13 @@ fn compile_nimble(generated_port_layer_types: PathBuf) {
         builder.include("../mynewt-nimble/nimble/host/services/gap/include");
     }
 
+    if is_native() {
+        // the system's libc is linked in already
+        builder.warnings(false).compile("nimble-controller");
+        println!("cargo:rustc-link-lib=static=nimble-controller");
+        return;
+    }
+
     // Target specific compilation flags
     let libc_path = set_target_flags(builder);
 
@@ -164,5 +214,13 @@ fn main() {
     .expect("Unable to generate bindings")
     .write_to_file(npl.into_os_string());
 
-    compile_nimble(target_dir);
+    let syscfg_include = PathBuf::from(
+        env::var("DEP_NIMBLE_INCLUDE").expect("apache-nimble-sys did not generate syscfg.h"),
+    );
+    println!(
+        "cargo:rerun-if-changed={}",
+        syscfg_include.join("syscfg/syscfg.h").display()
+    );
+
+    compile_nimble(target_dir, syscfg_include);
 }
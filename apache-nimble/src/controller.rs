@@ -1,21 +1,171 @@
 use core::fmt::Debug;
+use core::future::Future;
 use core::mem::size_of;
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
 
-use bt_hci::cmd::controller_baseband::HostBufferSize;
+use bt_hci::cmd::controller_baseband::{HostBufferSize, Reset};
+#[cfg(all(
+    feature = "data-length-extension",
+    any(feature = "role-central", feature = "role-peripheral")
+))]
+use bt_hci::cmd::le::LeSetDataLength;
+use bt_hci::cmd::le::LeSetDefaultPhy;
+#[cfg(feature = "privacy")]
+use bt_hci::cmd::le::{
+    LeAddDeviceToResolvingList, LeClearResolvingList, LeReadResolvingListSize,
+    LeRemoveDeviceFromResolvingList, LeSetAddrResolutionEnable, LeSetResolvablePrivateAddrTimeout,
+};
+#[cfg(feature = "data-length-extension")]
+use bt_hci::cmd::le::{
+    LeReadMaxDataLength, LeReadMaxDataLengthReturn, LeWriteSuggestedDefaultDataLength,
+};
+#[cfg(any(feature = "role-central", feature = "role-peripheral"))]
+use bt_hci::cmd::le::{LeReadPhy, LeReadPhyReturn, LeSetPhy};
+#[cfg(feature = "role-broadcaster")]
+use bt_hci::cmd::le::{LeSetExtAdvData, LeSetExtScanResponseData};
 use bt_hci::cmd::{AsyncCmd, Cmd, Error, SyncCmd};
 use bt_hci::controller::{ControllerCmdAsync, ControllerCmdSync};
-use bt_hci::data::{AclPacket, AclPacketHeader};
-use bt_hci::event::{CommandComplete, Event, EventPacketHeader};
+use bt_hci::event::{Event, EventPacketHeader};
 use bt_hci::param::Error as HciError;
-use bt_hci::{ControllerToHostPacket, FromHciBytes, PacketKind, ReadHci, WriteHci};
+#[cfg(feature = "privacy")]
+use bt_hci::param::{AddrKind, BdAddr, Duration};
+#[cfg(feature = "role-broadcaster")]
+use bt_hci::param::{AdvHandle, Operation};
+use bt_hci::param::{AllPhys, PhyMask};
+#[cfg(any(feature = "role-central", feature = "role-peripheral"))]
+use bt_hci::param::{ConnHandle, PhyOptions};
+use bt_hci::{ControllerToHostPacket, FromHciBytes, PacketKind, WriteHci};
 use defmt::{error, trace, Debug2Format};
 use embassy_futures::join;
+use embassy_futures::select::{select, Either};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
-use embassy_sync::channel::Channel;
-use embassy_sync::mutex::Mutex;
+use embassy_sync::channel::{Channel, TrySendError};
+use embassy_sync::mutex::{Mutex, MutexGuard};
+use embassy_sync::signal::Signal;
+use embassy_time::with_timeout;
 
-use crate::{raw, ready_to_send, OsError, OsMbuf};
+use crate::stats::{self, Pool};
+pub use crate::Phy;
+use crate::{is_current, raw, ready_to_send, Mbuf, OsError, TaskControl};
+
+/// Opcode of the command that `data` responds to, if it's a command complete or status event.
+fn cmd_response_opcode(data: &[u8]) -> Option<u16> {
+    match ControllerToHostPacket::from_hci_bytes_with_kind(PacketKind::Event, data) {
+        Ok((ControllerToHostPacket::Event(Event::CommandComplete(c)), _)) => {
+            Some(c.cmd_opcode.to_raw())
+        }
+        Ok((ControllerToHostPacket::Event(Event::CommandStatus(c)), _)) => {
+            Some(c.cmd_opcode.to_raw())
+        }
+        _ => None,
+    }
+}
+
+/// Drops packets that the host hasn't read yet and command responses that haven't been picked up.
+/// The ACL mbufs are freed back to the transport pool, which lets the link layer accept data from
+/// peers again.
+fn flush_queues() {
+    while let Ok(event) = READ_CHANNEL.try_receive() {
+        drop_stale_response(event.cmd_buf);
+    }
+    while let Ok(event) = CMD_SIGNAL.try_receive() {
+        drop_stale_response(event.cmd_buf);
+    }
+    while ACL_CHANNEL.try_receive().is_ok() {}
+}
+
+/// An event from the controller.
+struct EventBuf {
+    data: [u8; HCI_PKT_BUF_SIZE],
+    /// Command complete/status events are written to the buffer of the command they respond to, so
+    /// its address identifies the command. 0 for other events.
+    cmd_buf: usize,
+}
+
+/// Buffer of a command whose `execute_command` future was dropped (or timed out) before its
+/// response arrived, or 0. The buffer belongs to the controller until the response shows up, so it
+/// can only be freed then, by [`retire_cancelled_cmd`].
+///
+/// If the response takes too long, or the controller is reset, the buffer is abandoned instead:
+/// the slot is cleared, and the buffer is freed by [`drop_stale_response`] if the response ever
+/// shows up.
+static CANCELLED_CMD: AtomicUsize = AtomicUsize::new(0);
+static CANCELLED_CMD_RETIRED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Frees the buffer of a cancelled command once its response turns up. Returns true if `cmd_buf`
+/// was such a response, in which case it shouldn't be passed on.
+fn retire_cancelled_cmd(cmd_buf: usize) -> bool {
+    if cmd_buf == 0
+        || CANCELLED_CMD
+            .compare_exchange(cmd_buf, 0, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+    {
+        return false;
+    }
+    trace!("dropping response to a cancelled cmd");
+    unsafe { raw::ble_transport_free(cmd_buf as *mut cty::c_void) };
+    CANCELLED_CMD_RETIRED.signal(());
+    true
+}
+
+/// Drops a command response that nothing is waiting for anymore. Its buffer either belongs to a
+/// cancelled command, or to an abandoned one, and is freed either way.
+fn drop_stale_response(cmd_buf: usize) {
+    if cmd_buf != 0 && !retire_cancelled_cmd(cmd_buf) {
+        trace!("dropping response to an abandoned cmd");
+        unsafe { raw::ble_transport_free(cmd_buf as *mut cty::c_void) };
+    }
+}
+
+/// Stops waiting for the response to the cancelled command. See [`CANCELLED_CMD`].
+fn abandon_cancelled_cmd() {
+    CANCELLED_CMD.store(0, Ordering::Release);
+    CANCELLED_CMD_RETIRED.signal(());
+}
+
+/// A command that was handed to the controller. If this is dropped before the response is
+/// received, the command is marked as cancelled.
+struct PendingCmd {
+    buf: *mut cty::c_void,
+    generation: u32,
+}
+
+impl PendingCmd {
+    /// Frees the command buffer after its response was received.
+    fn complete(self) {
+        unsafe { raw::ble_transport_free(self.buf) };
+        core::mem::forget(self);
+    }
+}
+
+impl Drop for PendingCmd {
+    fn drop(&mut self) {
+        // after a shutdown, the buffer belongs to the old transport pools, which are initialized
+        // again by the next `Nimble::init`
+        if !is_current(self.generation) {
+            return;
+        }
+        // the response might have arrived right after we stopped waiting for it
+        if let Ok(event) = CMD_SIGNAL.try_receive() {
+            if event.cmd_buf == self.buf as usize {
+                unsafe { raw::ble_transport_free(self.buf) };
+                return;
+            }
+            drop_stale_response(event.cmd_buf);
+        }
+        CANCELLED_CMD_RETIRED.reset();
+        CANCELLED_CMD.store(self.buf as usize, Ordering::Release);
+        if !is_current(self.generation) {
+            // shut down in the meantime, after `deinit` cleared the cancelled command
+            let _ = CANCELLED_CMD.compare_exchange(
+                self.buf as usize,
+                0,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            );
+        }
+    }
+}
 
 #[cfg(not(feature = "host"))]
 #[no_mangle]
@@ -31,64 +181,52 @@ extern "C" fn ble_transport_to_hs_evt_impl(buf: *mut cty::c_void) -> cty::c_int
         data[2..(2 + len)].copy_from_slice((*ptr).data.as_slice(len));
     }
 
+    // Command complete/status events reuse the buffer of the command they respond to, which is
+    // freed in `execute_command`. Every other event (including the no-op command complete sent at
+    // startup) comes from the transport's event pools, and needs to be freed here, otherwise
+    // frequent events like advertising or periodic advertising reports exhaust the pool.
+    let cmd_opcode = cmd_response_opcode(&data);
+    let cmd_buf = if cmd_opcode.is_some_and(|op| op != 0) {
+        buf as usize
+    } else {
+        unsafe { raw::ble_transport_free(buf) };
+        0
+    };
+
     // ignore no-op event from the controller
-    if let Ok((
-        ControllerToHostPacket::Event(Event::CommandComplete(CommandComplete {
-            cmd_opcode, ..
-        })),
-        _,
-    )) = ControllerToHostPacket::from_hci_bytes_with_kind(PacketKind::Event, &data)
-    {
-        if cmd_opcode.to_raw() == 0 {
-            return 0;
-        }
+    if cmd_opcode == Some(0) {
+        return 0;
     }
 
-    READ_CHANNEL
-        .try_send((PacketKind::Event, data))
-        .map_or_else(
-            |_| {
-                error!("event to host being overwritten. this should not happen.");
-                OsError::NoMem as i32
-            },
-            |_| 0,
-        )
+    if retire_cancelled_cmd(cmd_buf) {
+        return 0;
+    }
+
+    // The buffer is ours either way (it was freed above, or it's the command's and is freed in
+    // `execute_command`), so this can't return an error: the caller would free it again.
+    if READ_CHANNEL.try_send(EventBuf { data, cmd_buf }).is_err() {
+        error!("event queue to host is full, dropping event. this should not happen.");
+    }
+    0
 }
 
 #[cfg(not(feature = "host"))]
 #[no_mangle]
 extern "C" fn ble_transport_to_hs_acl_impl(om: *mut raw::os_mbuf) -> cty::c_int {
-    let mut data = [0; HCI_PKT_BUF_SIZE];
-
-    let parse_result = AclPacketHeader::read_hci::<OsMbuf>(om.into(), data.as_mut_slice())
-        .map_err(|_| OsError::Invalid)
-        .and_then(|hdr| {
-            let data = &mut data[hdr.size()..(hdr.size() + hdr.data_len())];
-            <OsMbuf as embedded_io::Read>::read_exact(&mut om.into(), data)
-                .map_err(|_| OsError::Invalid)?;
-            AclPacket::from_header_hci_bytes(hdr, data).map_err(|_| OsError::Invalid)
-        });
-
-    if let Err(e) = parse_result {
-        error!(
-            "could not fully parse ACL packet to send to host. dropping packet: {}",
-            Debug2Format(&e)
-        );
-        return OsError::NoMem as i32;
+    let Some(om) = (unsafe { Mbuf::from_raw(om) }) else {
+        return OsError::InvalidParameter as i32;
     };
 
-    READ_CHANNEL
-        .try_send((PacketKind::AclData, data))
-        .map_or_else(
-            |_| {
-                error!("acl to host being overwritten. this should not happen.");
-                OsError::NoMem as i32
-            },
-            |_| unsafe {
-                raw::os_mbuf_free_chain(om);
-                0
-            },
-        )
+    // The mbuf is parsed and freed once the host reads it in `NimbleController::read`.
+    match ACL_CHANNEL.try_send(om) {
+        Ok(()) => 0,
+        Err(TrySendError::Full(om)) => {
+            error!("acl queue to host is full. this should not happen.");
+            // leave it to the caller to free the mbuf
+            om.into_raw();
+            OsError::NoMem as i32
+        }
+    }
 }
 
 // This isn't used in the controller
@@ -102,12 +240,13 @@ extern "C" {
     fn ble_ll_tx_power_round(a: cty::c_int) -> cty::c_int;
 }
 
-/// Reimplementation of nimble's ble_ll_task.
-/// [`NimbleController::new`] instead.
-async unsafe fn ble_ll_task() -> ! {
-    if raw::ble_phy_init() != 0 {
-        panic!("could not initialize phy")
-    };
+/// Initializes the PHY and the transmit power. This is the first part of nimble's ble_ll_task,
+/// done by [`crate::Nimble::init`] so that errors can be reported.
+pub(crate) unsafe fn init_phy() -> Result<(), i32> {
+    let rc = raw::ble_phy_init();
+    if rc != 0 {
+        return Err(rc);
+    }
 
     g_ble_ll_tx_power = ble_ll_tx_power_round(u32::min(
         raw::MYNEWT_VAL_BLE_LL_TX_PWR_DBM,
@@ -117,6 +256,11 @@ async unsafe fn ble_ll_task() -> ! {
     // Note: we can't update g_ble_ll_tx_power_phy_current like in the original function, because
     // it's a C static. Hopefully it's not an issue :)
 
+    Ok(())
+}
+
+/// Reimplementation of the rest of nimble's ble_ll_task.
+async unsafe fn ble_ll_task() -> ! {
     loop {
         let ev = raw::ble_npl_eventq_get(&mut raw::g_ble_ll_data.ll_evq as _, u32::MAX).await;
 
@@ -133,46 +277,110 @@ async unsafe fn ble_ll_task() -> ! {
     }
 }
 
+/// Resets the link layer, turns off the radio, and drops anything that's still queued. The LL task
+/// needs to be stopped first.
+pub(crate) unsafe fn deinit() {
+    if raw::ble_ll_reset() != 0 {
+        error!("could not reset the link layer");
+    }
+    raw::ble_phy_disable();
+    raw::disable_interrupts();
+    raw::hal_timer_deinit(raw::MYNEWT_VAL_OS_CPUTIME_TIMER_NUM as i32);
+
+    flush_queues();
+    // the transport pools are initialized again, so there's nothing left to free
+    CANCELLED_CMD.store(0, Ordering::Release);
+    CMD_ABORT.reset();
+
+    raw::ble_npl_eventq_deinit(core::ptr::addr_of_mut!(raw::g_ble_ll_data.ll_evq));
+}
+
+pub(crate) static LL_TASK: TaskControl = TaskControl::new();
+
+/// Held while a command is being executed. It's shared by every [`NimbleController`], so that a
+/// command sent by a controller from before a shutdown can't overlap with one sent after the next
+/// init (the transport reuses the same buffers).
+static CMD_LOCK: Mutex<CriticalSectionRawMutex, ()> = Mutex::new(());
+/// Signaled by [`crate::Nimble::shutdown`], so that the command holding [`CMD_LOCK`] stops
+/// waiting for its response.
+static CMD_ABORT: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Makes the command in progress (if any) give up, and waits for it to release [`CMD_LOCK`]. The
+/// lock is held until NimBLE is deinitialized, so that no new command can start in the meantime.
+pub(crate) async fn abort_commands() -> MutexGuard<'static, CriticalSectionRawMutex, ()> {
+    CMD_ABORT.signal(());
+    CMD_LOCK.lock().await
+}
+
+/// Waits for `fut`, unless `timeout` expires or NimBLE is shut down first.
+async fn wait_for_controller<T>(
+    timeout: embassy_time::Duration,
+    fut: impl Future<Output = T>,
+) -> Result<T, ControllerError> {
+    match with_timeout(timeout, select(fut, CMD_ABORT.wait())).await {
+        Ok(Either::First(value)) => Ok(value),
+        Ok(Either::Second(())) => Err(ControllerError::NotStarted),
+        Err(_) => Err(ControllerError::Timeout),
+    }
+}
+
 pub struct NimbleController {
-    cmd_lock: Mutex<CriticalSectionRawMutex, ()>,
+    generation: u32,
+    cmd_timeout: embassy_time::Duration,
 }
 
 pub struct NimbleControllerTask {
-    _init: (),
+    generation: u32,
 }
 
 impl NimbleControllerTask {
-    pub async fn run(&self) -> ! {
-        unsafe { ble_ll_task().await }
+    pub(crate) fn new(generation: u32) -> Self {
+        Self { generation }
+    }
+
+    /// Runs the link layer until [`crate::Nimble::shutdown`] is called.
+    pub async fn run(&self) {
+        LL_TASK.run(self.generation, unsafe { ble_ll_task() }).await
+    }
+}
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b {
+        a
+    } else {
+        b
     }
 }
 
-const HCI_PKT_BUF_SIZE: usize = raw::BLE_ACL_MAX_PKT_SIZE as usize
-    + raw::BLE_HCI_DATA_HDR_SZ as usize
-    + size_of::<raw::os_mbuf_pkthdr>()
+/// Large enough to hold either an ACL packet or an event (see `BLE_TRANSPORT_ACL_SIZE` and
+/// `BLE_TRANSPORT_EVT_SIZE` in syscfg).
+const HCI_PKT_BUF_SIZE: usize = max(
+    raw::MYNEWT_VAL_BLE_TRANSPORT_ACL_SIZE as usize + raw::BLE_HCI_DATA_HDR_SZ as usize,
+    raw::MYNEWT_VAL_BLE_TRANSPORT_EVT_SIZE as usize,
+) + size_of::<raw::os_mbuf_pkthdr>()
     + size_of::<raw::ble_mbuf_hdr>()
     + size_of::<raw::os_mbuf>();
 
-static NIMBLE_CONTROLLER_IN_USE: AtomicBool = AtomicBool::new(false);
-static READ_CHANNEL: Channel<CriticalSectionRawMutex, (PacketKind, [u8; HCI_PKT_BUF_SIZE]), 1> =
-    Channel::new();
-static CMD_SIGNAL: Channel<CriticalSectionRawMutex, [u8; HCI_PKT_BUF_SIZE], 1> = Channel::new();
+/// The mbufs come from the transport's ACL pool, so the queue can hold every buffer in the pool,
+/// and never drops packets. When the host falls behind, the pool runs out and the controller stops
+/// accepting data from peers, instead of one connection's packets displacing another's.
+const ACL_QUEUE_DEPTH: usize = raw::MYNEWT_VAL_BLE_TRANSPORT_ACL_FROM_LL_COUNT as usize;
 
-impl NimbleController {
-    pub fn new() -> Self {
-        NIMBLE_CONTROLLER_IN_USE
-            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
-            .expect("attempted to create more than one nimble controller task");
+static READ_CHANNEL: Channel<CriticalSectionRawMutex, EventBuf, 1> = Channel::new();
+static ACL_CHANNEL: Channel<CriticalSectionRawMutex, Mbuf, ACL_QUEUE_DEPTH> = Channel::new();
+static CMD_SIGNAL: Channel<CriticalSectionRawMutex, EventBuf, 1> = Channel::new();
+/// Incremented whenever the host resets the controller, so that commands queued before the reset
+/// can be cancelled.
+static RESET_EPOCH: AtomicU32 = AtomicU32::new(0);
 
+impl NimbleController {
+    pub(crate) fn new(generation: u32, cmd_timeout: embassy_time::Duration) -> Self {
         Self {
-            cmd_lock: Mutex::new(()),
+            generation,
+            cmd_timeout,
         }
     }
 
-    pub fn create_task(&self) -> NimbleControllerTask {
-        NimbleControllerTask { _init: () }
-    }
-
     /// This is a little hack for now to get this to work with the `trouble` crate
     fn transform<C: Cmd>(&self, _cmd: &C, cmd_data: &mut [u8]) {
         if C::OPCODE.to_raw() == HostBufferSize::OPCODE.to_raw() {
@@ -189,17 +397,85 @@ impl NimbleController {
     ///
     /// To wait for a response, we use [`CMD_SIGNAL`]. This only works with the assumption that the
     /// nimble controller can only handle one command at a time.
+    ///
+    /// `HCI_Reset` flushes everything the controller sent before it, and cancels the commands that
+    /// were waiting for their turn (they fail with [`ControllerError::Cancelled`]), so that the
+    /// first command after the reset gets its own response.
+    ///
+    /// If no response arrives within `timeout`, this fails with [`ControllerError::Timeout`].
+    /// Timing out and dropping the future are both safe: the command is marked as cancelled, and
+    /// its response is dropped (and its buffer freed) when it arrives. The next command waits for
+    /// that to happen, within its own timeout, and then gives up on it (its buffer is freed if the
+    /// response shows up later). `HCI_Reset` doesn't wait at all.
+    ///
+    /// The transport only has a single command buffer, so while it's held by a command that the
+    /// controller hasn't answered yet, this waits for it to come back (within `timeout`), and fails
+    /// with [`ControllerError::NoMem`] if it doesn't.
     async fn execute_command<'a, C: Cmd + Debug>(
         &self,
         buf: &'a mut [u8; HCI_PKT_BUF_SIZE],
         cmd: &C,
-    ) -> Result<Event<'a>, Error<OsError>> {
-        // allocate space for cmd
-        let _lock = self.cmd_lock.lock().await;
-        let ptr = unsafe { raw::ble_transport_alloc_cmd() };
-        if core::ptr::eq(ptr, core::ptr::null_mut()) {
-            return Err(Error::Io(OsError::NoMem));
+        timeout: embassy_time::Duration,
+    ) -> Result<Event<'a>, Error<ControllerError>> {
+        if !is_current(self.generation) {
+            return Err(Error::Io(ControllerError::NotStarted));
+        }
+
+        let is_reset = C::OPCODE.to_raw() == Reset::OPCODE.to_raw();
+        let epoch = RESET_EPOCH.load(Ordering::Acquire);
+        let _lock = CMD_LOCK.lock().await;
+        if !is_current(self.generation) {
+            return Err(Error::Io(ControllerError::NotStarted));
+        }
+        if is_reset {
+            // anything the controller sent before the reset is meaningless to the host now,
+            // including the response to a cancelled command
+            RESET_EPOCH.fetch_add(1, Ordering::AcqRel);
+            flush_queues();
+            abandon_cancelled_cmd();
+        } else if RESET_EPOCH.load(Ordering::Acquire) != epoch {
+            trace!(
+                "cancelling cmd queued before a reset: {}",
+                Debug2Format(cmd)
+            );
+            return Err(Error::Io(ControllerError::Cancelled));
+        }
+
+        // The controller still owes a response to a cancelled command. Wait for it, so that its
+        // buffer can be reused, and it isn't mistaken for the response to this command.
+        while CANCELLED_CMD.load(Ordering::Acquire) != 0 {
+            match wait_for_controller(
+                timeout,
+                select(CANCELLED_CMD_RETIRED.wait(), CMD_SIGNAL.receive()),
+            )
+            .await
+            {
+                Ok(Either::First(())) => {}
+                Ok(Either::Second(event)) => drop_stale_response(event.cmd_buf),
+                Err(ControllerError::Timeout) => {
+                    error!("timed out waiting for the response to a cancelled cmd, abandoning it");
+                    abandon_cancelled_cmd();
+                }
+                Err(e) => return Err(Error::Io(e)),
+            }
         }
+
+        // allocate space for cmd. If the buffer is still held by an abandoned command, wait for
+        // its response to free it.
+        let ptr = loop {
+            let ptr = unsafe { raw::ble_transport_alloc_cmd() };
+            if !ptr.is_null() {
+                break ptr;
+            }
+            match wait_for_controller(timeout, CMD_SIGNAL.receive()).await {
+                Ok(event) => drop_stale_response(event.cmd_buf),
+                Err(ControllerError::Timeout) => {
+                    stats::record_alloc_failure(Pool::TransportCmd);
+                    return Err(Error::Io(ControllerError::NoMem));
+                }
+                Err(e) => return Err(Error::Io(e)),
+            }
+        };
         trace!("sending cmd: {}", Debug2Format(cmd));
 
         // serialize cmd
@@ -207,7 +483,7 @@ impl NimbleController {
         if let Err(e) = cmd.write_hci(cmd_data) {
             error!("failed to convert cmd into raw bytes: {}", Debug2Format(&e));
             unsafe { raw::ble_transport_free(ptr) };
-            return Err(Error::Io(OsError::NoMem));
+            return Err(Error::Io(ControllerError::BufferTooSmall));
         }
         self.transform(cmd, unsafe {
             core::slice::from_raw_parts_mut(ptr as *mut u8, cmd.size())
@@ -216,17 +492,46 @@ impl NimbleController {
         // queue the command in the nimble controller
         let ret = unsafe { raw::ble_transport_to_ll_cmd_impl(ptr) };
         if ret != 0 {
-            error!("failed to queue command, dropping command");
+            error!("failed to queue command, dropping command: error {}", ret);
             unsafe { raw::ble_transport_free(ptr) };
-            return Err(Error::Io(OsError::Invalid));
+            return Err(Error::Io(ControllerError::TransportRejected(ret)));
         }
 
-        // wait until we receive a status or command complete
-        let cmd_result = CMD_SIGNAL.receive().await;
-        buf.copy_from_slice(&cmd_result);
+        let pending = PendingCmd {
+            buf: ptr,
+            generation: self.generation,
+        };
+
+        // wait until we receive a status or command complete for this command. Responses to other
+        // commands are stale, and are dropped.
+        loop {
+            match wait_for_controller(timeout, CMD_SIGNAL.receive()).await {
+                Ok(event) if event.cmd_buf == ptr as usize => {
+                    buf.copy_from_slice(&event.data);
+                    break;
+                }
+                Ok(event) => {
+                    trace!("dropping stale cmd response: {}", event.data);
+                    drop_stale_response(event.cmd_buf);
+                }
+                Err(ControllerError::Timeout) => {
+                    error!(
+                        "timed out waiting for a response: cmd {}",
+                        Debug2Format(cmd)
+                    );
+                    return Err(Error::Io(ControllerError::Timeout));
+                }
+                Err(e) => return Err(Error::Io(e)),
+            }
+        }
 
         // free the buffer to let other commands run
-        unsafe { raw::ble_transport_free(ptr) };
+        pending.complete();
+
+        if is_reset {
+            // drop anything that was sent while the link layer was being reset
+            flush_queues();
+        }
 
         // parse the response data
         let hdr = match EventPacketHeader::from_hci_bytes(buf) {
@@ -236,7 +541,7 @@ impl NimbleController {
                     "unexpected error when parsing event header: {}",
                     Debug2Format(&e),
                 );
-                return Err(Error::Hci(HciError::INVALID_HCI_PARAMETERS));
+                return Err(Error::Io(ControllerError::MalformedEvent));
             }
         };
         let buf = &buf[..(size_of::<EventPacketHeader>() + hdr.params_len as usize)];
@@ -255,19 +560,374 @@ impl NimbleController {
                     Debug2Format(&e),
                     buf
                 );
-                Error::Hci(HciError::INVALID_HCI_PARAMETERS)
+                Error::Io(ControllerError::MalformedEvent)
             })
     }
 }
 
-impl Default for NimbleController {
-    fn default() -> Self {
-        Self::new()
+fn phy_mask(phys: &[Phy]) -> PhyMask {
+    phys.iter().fold(PhyMask::new(), |mask, phy| match phy {
+        Phy::Le1M => mask.set_le_1m_preferred(true),
+        #[cfg(feature = "phy-2m")]
+        Phy::Le2M => mask.set_le_2m_preferred(true),
+        #[cfg(feature = "phy-coded")]
+        Phy::LeCoded => mask.set_le_coded_preferred(true),
+    })
+}
+
+/// An empty list of PHYs means the host has no preference.
+fn all_phys(tx: &[Phy], rx: &[Phy]) -> AllPhys {
+    AllPhys::new()
+        .set_has_no_tx_phy_preference(tx.is_empty())
+        .set_has_no_rx_phy_preference(rx.is_empty())
+}
+
+impl NimbleController {
+    /// Sets the PHYs preferred for transmitting and receiving on new connections. Passing an empty
+    /// slice means there is no preference for that direction.
+    pub async fn set_default_phy(
+        &self,
+        tx: &[Phy],
+        rx: &[Phy],
+    ) -> Result<(), Error<ControllerError>> {
+        let cmd = LeSetDefaultPhy::new(all_phys(tx, rx), phy_mask(tx), phy_mask(rx));
+        ControllerCmdSync::exec(self, &cmd).await
+    }
+
+    /// Requests a PHY update on an existing connection. The result is reported to the host through
+    /// a `LE PHY Update Complete` event.
+    #[cfg(any(feature = "role-central", feature = "role-peripheral"))]
+    pub async fn set_phy(
+        &self,
+        handle: ConnHandle,
+        tx: &[Phy],
+        rx: &[Phy],
+        options: PhyOptions,
+    ) -> Result<(), Error<ControllerError>> {
+        let cmd = LeSetPhy::new(
+            handle,
+            all_phys(tx, rx),
+            phy_mask(tx),
+            phy_mask(rx),
+            options,
+        );
+        ControllerCmdAsync::exec(self, &cmd).await
+    }
+
+    /// Reads the PHYs currently used by a connection.
+    #[cfg(any(feature = "role-central", feature = "role-peripheral"))]
+    pub async fn read_phy(
+        &self,
+        handle: ConnHandle,
+    ) -> Result<LeReadPhyReturn, Error<ControllerError>> {
+        ControllerCmdSync::exec(self, &LeReadPhy::new(handle)).await
+    }
+}
+
+/// Valid range for the maximum number of payload octets in a LL data PDU.
+#[cfg(feature = "data-length-extension")]
+const DATA_LEN_OCTETS: core::ops::RangeInclusive<u16> = 0x001B..=0x00FB;
+/// Valid range for the maximum time, in microseconds, to transmit a LL data PDU.
+#[cfg(feature = "data-length-extension")]
+const DATA_LEN_TIME: core::ops::RangeInclusive<u16> = 0x0148..=0x4290;
+
+#[cfg(feature = "data-length-extension")]
+impl NimbleController {
+    fn check_data_length(tx_octets: u16, tx_time: u16) -> Result<(), Error<ControllerError>> {
+        if DATA_LEN_OCTETS.contains(&tx_octets) && DATA_LEN_TIME.contains(&tx_time) {
+            Ok(())
+        } else {
+            Err(Error::Io(ControllerError::InvalidParameter))
+        }
+    }
+
+    /// Suggests the maximum payload size and transmission time to use for a connection. Fails with
+    /// [`ControllerError::InvalidParameter`] if they're out of the allowed ranges.
+    #[cfg(any(feature = "role-central", feature = "role-peripheral"))]
+    pub async fn set_data_length(
+        &self,
+        handle: ConnHandle,
+        tx_octets: u16,
+        tx_time: u16,
+    ) -> Result<(), Error<ControllerError>> {
+        Self::check_data_length(tx_octets, tx_time)?;
+        let cmd = LeSetDataLength::new(handle, tx_octets, tx_time);
+        ControllerCmdSync::exec(self, &cmd).await.map(|_| ())
+    }
+
+    /// Sets the payload size and transmission time used for new connections. Fails with
+    /// [`ControllerError::InvalidParameter`] if they're out of the allowed ranges.
+    pub async fn write_suggested_default_data_length(
+        &self,
+        tx_octets: u16,
+        tx_time: u16,
+    ) -> Result<(), Error<ControllerError>> {
+        Self::check_data_length(tx_octets, tx_time)?;
+        let cmd = LeWriteSuggestedDefaultDataLength::new(tx_octets, tx_time);
+        ControllerCmdSync::exec(self, &cmd).await
+    }
+
+    /// Reads the maximum payload sizes and transmission times supported by the controller.
+    pub async fn read_max_data_length(
+        &self,
+    ) -> Result<LeReadMaxDataLengthReturn, Error<ControllerError>> {
+        ControllerCmdSync::exec(self, &LeReadMaxDataLength::new()).await
+    }
+}
+
+/// Number of advertising sets supported by the controller (`BLE_MULTI_ADV_INSTANCES` + 1).
+#[cfg(feature = "role-broadcaster")]
+pub const MAX_ADV_SETS: usize = raw::MYNEWT_VAL_BLE_MULTI_ADV_INSTANCES as usize + 1;
+
+/// Maximum length of extended advertising or scan response data (`BLE_EXT_ADV_MAX_SIZE`).
+#[cfg(feature = "role-broadcaster")]
+pub const MAX_EXT_ADV_DATA_LEN: usize = raw::MYNEWT_VAL_BLE_EXT_ADV_MAX_SIZE as usize;
+
+/// Maximum amount of advertising data that fits in a single HCI command.
+#[cfg(feature = "role-broadcaster")]
+const EXT_ADV_DATA_FRAGMENT_LEN: usize = 251;
+
+/// Splits `data` into the fragments that need to be sent to the controller, along with the
+/// operation for each one.
+#[cfg(feature = "role-broadcaster")]
+fn adv_data_fragments(data: &[u8]) -> impl Iterator<Item = (Operation, &[u8])> {
+    let count = data.len().div_ceil(EXT_ADV_DATA_FRAGMENT_LEN).max(1);
+    let mut chunks = data.chunks(EXT_ADV_DATA_FRAGMENT_LEN);
+    (0..count).map(move |i| {
+        let op = match (i, count) {
+            (_, 1) => Operation::Complete,
+            (0, _) => Operation::FirstFragment,
+            (i, count) if i == count - 1 => Operation::LastFragment,
+            _ => Operation::IntermediateFragment,
+        };
+        (op, chunks.next().unwrap_or(&[]))
+    })
+}
+
+#[cfg(feature = "role-broadcaster")]
+impl NimbleController {
+    /// Sets the advertising data of an advertising set, splitting it across multiple `LE Set
+    /// Extended Advertising Data` commands if it doesn't fit in one. Fails with
+    /// [`ControllerError::DataTooLong`] if it's longer than [`MAX_EXT_ADV_DATA_LEN`].
+    pub async fn set_ext_adv_data(
+        &self,
+        handle: AdvHandle,
+        data: &[u8],
+    ) -> Result<(), Error<ControllerError>> {
+        if data.len() > MAX_EXT_ADV_DATA_LEN {
+            return Err(Error::Io(ControllerError::DataTooLong));
+        }
+        for (op, fragment) in adv_data_fragments(data) {
+            let cmd = LeSetExtAdvData::new(handle, op, false, fragment);
+            ControllerCmdSync::exec(self, &cmd).await?;
+        }
+        Ok(())
+    }
+
+    /// Sets the scan response data of an advertising set, splitting it across multiple `LE Set
+    /// Extended Scan Response Data` commands if it doesn't fit in one. Fails with
+    /// [`ControllerError::DataTooLong`] if it's longer than [`MAX_EXT_ADV_DATA_LEN`].
+    pub async fn set_ext_scan_response_data(
+        &self,
+        handle: AdvHandle,
+        data: &[u8],
+    ) -> Result<(), Error<ControllerError>> {
+        if data.len() > MAX_EXT_ADV_DATA_LEN {
+            return Err(Error::Io(ControllerError::DataTooLong));
+        }
+        for (op, fragment) in adv_data_fragments(data) {
+            let cmd = LeSetExtScanResponseData::new(handle, op, false, fragment);
+            ControllerCmdSync::exec(self, &cmd).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Encrypts a single 128-bit block with AES-128, using the same hardware block (or software
+/// fallback) that the link layer uses for encryption and address resolution.
+///
+/// Note: as with NimBLE's `ble_hw_encrypt_block`, the key and data are big-endian (most
+/// significant octet first), unlike the `LE Encrypt` HCI command.
+#[cfg(feature = "encryption")]
+pub fn encrypt_block(key: &[u8; 16], plain_text: &[u8; 16]) -> Result<[u8; 16], OsError> {
+    let mut block: raw::ble_encryption_block = unsafe { core::mem::zeroed() };
+    block.key = *key;
+    block.plain_text = *plain_text;
+
+    let ret = unsafe { raw::ble_hw_encrypt_block(&mut block as _) };
+    if ret == 0 {
+        Ok(block.cipher_text)
+    } else {
+        Err(OsError::from(ret as u32))
+    }
+}
+
+/// Resolving list management, used by the controller to resolve and generate resolvable private
+/// addresses.
+#[cfg(feature = "privacy")]
+impl NimbleController {
+    /// Adds a peer's identity address and IRKs to the resolving list. Address resolution needs to
+    /// be disabled while modifying the list.
+    pub async fn add_to_resolving_list(
+        &self,
+        peer_addr_kind: AddrKind,
+        peer_addr: BdAddr,
+        peer_irk: [u8; 16],
+        local_irk: [u8; 16],
+    ) -> Result<(), Error<ControllerError>> {
+        let cmd = LeAddDeviceToResolvingList::new(peer_addr_kind, peer_addr, peer_irk, local_irk);
+        ControllerCmdSync::exec(self, &cmd).await
+    }
+
+    /// Removes a peer from the resolving list.
+    pub async fn remove_from_resolving_list(
+        &self,
+        peer_addr_kind: AddrKind,
+        peer_addr: BdAddr,
+    ) -> Result<(), Error<ControllerError>> {
+        let cmd = LeRemoveDeviceFromResolvingList::new(peer_addr_kind, peer_addr);
+        ControllerCmdSync::exec(self, &cmd).await
+    }
+
+    /// Removes all peers from the resolving list.
+    pub async fn clear_resolving_list(&self) -> Result<(), Error<ControllerError>> {
+        ControllerCmdSync::exec(self, &LeClearResolvingList::new()).await
+    }
+
+    /// Reads the number of entries the resolving list can hold (see `BLE_LL_RESOLV_LIST_SIZE`).
+    pub async fn resolving_list_size(&self) -> Result<u8, Error<ControllerError>> {
+        ControllerCmdSync::exec(self, &LeReadResolvingListSize::new()).await
+    }
+
+    /// Enables or disables address resolution in the controller.
+    pub async fn set_address_resolution(&self, enable: bool) -> Result<(), Error<ControllerError>> {
+        ControllerCmdSync::exec(self, &LeSetAddrResolutionEnable::new(enable)).await
+    }
+
+    /// Sets how often the controller generates a new resolvable private address.
+    pub async fn set_rpa_timeout(&self, timeout: Duration) -> Result<(), Error<ControllerError>> {
+        ControllerCmdSync::exec(self, &LeSetResolvablePrivateAddrTimeout::new(timeout)).await
+    }
+}
+
+/// Errors returned by [`NimbleController`]. Commands wrap these in [`bt_hci::cmd::Error::Io`],
+/// and report HCI status codes returned by the controller as [`bt_hci::cmd::Error::Hci`]; both can
+/// be flattened into a `ControllerError` with `From`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControllerError {
+    /// A transport buffer for a command or outgoing data couldn't be allocated (see
+    /// [`crate::stats`]).
+    NoMem,
+    /// A packet doesn't fit in the buffer it's being written to or read into.
+    BufferTooSmall,
+    /// The controller's transport refused to queue a command or outgoing data, with the given
+    /// NimBLE error code.
+    TransportRejected(i32),
+    /// An event or ACL packet from the controller couldn't be parsed.
+    MalformedEvent,
+    /// The controller responded to a different command than the one that was sent.
+    OpcodeMismatch { expected: u16, received: u16 },
+    /// The controller responded with a command status to a command that expects a command
+    /// complete event, or the other way around.
+    UnexpectedResponse,
+    /// The controller didn't respond within the command timeout.
+    Timeout,
+    /// The command was queued before an `HCI_Reset`, and was cancelled by it.
+    Cancelled,
+    /// NimBLE was shut down since this controller was created.
+    NotStarted,
+    /// A command parameter is out of the range allowed by the specification, so the command
+    /// wasn't sent.
+    InvalidParameter,
+    /// Advertising or scan response data is longer than `MAX_EXT_ADV_DATA_LEN`, so it wasn't sent.
+    DataTooLong,
+    /// The controller responded with an error status.
+    Hci(HciError),
+}
+
+impl From<Error<ControllerError>> for ControllerError {
+    fn from(value: Error<ControllerError>) -> Self {
+        match value {
+            Error::Hci(e) => ControllerError::Hci(e),
+            Error::Io(e) => e,
+        }
+    }
+}
+
+impl core::fmt::Display for ControllerError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ControllerError::NoMem => f.write_str("out of transport buffers"),
+            ControllerError::BufferTooSmall => f.write_str("packet does not fit in the buffer"),
+            ControllerError::TransportRejected(rc) => {
+                write!(f, "transport rejected the packet (error {rc})")
+            }
+            ControllerError::MalformedEvent => f.write_str("malformed packet from the controller"),
+            ControllerError::OpcodeMismatch { expected, received } => write!(
+                f,
+                "response for opcode {received:#06x} while waiting for opcode {expected:#06x}"
+            ),
+            ControllerError::UnexpectedResponse => {
+                f.write_str("unexpected response event from the controller")
+            }
+            ControllerError::Timeout => f.write_str("timed out waiting for the controller"),
+            ControllerError::Cancelled => f.write_str("command cancelled by a reset"),
+            ControllerError::NotStarted => f.write_str("nimble is not running"),
+            ControllerError::InvalidParameter => f.write_str("command parameter out of range"),
+            ControllerError::DataTooLong => f.write_str("advertising data too long"),
+            ControllerError::Hci(e) => write!(f, "controller returned an error: {e:?}"),
+        }
+    }
+}
+
+impl defmt::Format for ControllerError {
+    fn format(&self, fmt: defmt::Formatter) {
+        match self {
+            ControllerError::NoMem => defmt::write!(fmt, "NoMem"),
+            ControllerError::BufferTooSmall => defmt::write!(fmt, "BufferTooSmall"),
+            ControllerError::TransportRejected(rc) => {
+                defmt::write!(fmt, "TransportRejected({})", rc)
+            }
+            ControllerError::MalformedEvent => defmt::write!(fmt, "MalformedEvent"),
+            ControllerError::OpcodeMismatch { expected, received } => defmt::write!(
+                fmt,
+                "OpcodeMismatch {{ expected: {=u16:#06x}, received: {=u16:#06x} }}",
+                expected,
+                received
+            ),
+            ControllerError::UnexpectedResponse => defmt::write!(fmt, "UnexpectedResponse"),
+            ControllerError::Timeout => defmt::write!(fmt, "Timeout"),
+            ControllerError::Cancelled => defmt::write!(fmt, "Cancelled"),
+            ControllerError::NotStarted => defmt::write!(fmt, "NotStarted"),
+            ControllerError::InvalidParameter => defmt::write!(fmt, "InvalidParameter"),
+            ControllerError::DataTooLong => defmt::write!(fmt, "DataTooLong"),
+            ControllerError::Hci(e) => defmt::write!(fmt, "Hci({})", Debug2Format(e)),
+        }
+    }
+}
+
+impl embedded_io::Error for ControllerError {
+    fn kind(&self) -> embedded_io::ErrorKind {
+        use embedded_io::ErrorKind;
+
+        match self {
+            ControllerError::NoMem => ErrorKind::OutOfMemory,
+            ControllerError::BufferTooSmall
+            | ControllerError::InvalidParameter
+            | ControllerError::DataTooLong => ErrorKind::InvalidInput,
+            ControllerError::TransportRejected(_) | ControllerError::Hci(_) => ErrorKind::Other,
+            ControllerError::MalformedEvent
+            | ControllerError::OpcodeMismatch { .. }
+            | ControllerError::UnexpectedResponse => ErrorKind::InvalidData,
+            ControllerError::Timeout => ErrorKind::TimedOut,
+            ControllerError::Cancelled => ErrorKind::Interrupted,
+            ControllerError::NotStarted => ErrorKind::NotConnected,
+        }
     }
 }
 
 impl embedded_io::ErrorType for NimbleController {
-    type Error = OsError;
+    type Error = ControllerError;
 }
 
 impl bt_hci::controller::Controller for NimbleController {
@@ -276,24 +936,27 @@ impl bt_hci::controller::Controller for NimbleController {
         packet: &bt_hci::data::AclPacket<'_>,
     ) -> Result<(), Self::Error> {
         trace!("sending acl to controller");
-        unsafe {
-            let om = raw::ble_transport_alloc_acl_from_hs();
-
-            if om.is_null() {
-                error!("could not allocate space for an acl packet to send to controller");
-                return Err(OsError::NoMem);
-            }
+        if !is_current(self.generation) {
+            return Err(ControllerError::NotStarted);
+        }
+        let Some(mut om) = (unsafe { Mbuf::from_raw(raw::ble_transport_alloc_acl_from_hs()) })
+        else {
+            error!("could not allocate space for an acl packet to send to controller");
+            stats::record_alloc_failure(Pool::TransportAclFromHs);
+            return Err(ControllerError::NoMem);
+        };
 
-            if let Err(e) = packet.write_hci::<OsMbuf>(om.into()) {
-                error!(
-                    "could not serialize acl packet: acl {} error {}",
-                    Debug2Format(&packet),
-                    Debug2Format(&e)
-                );
-                raw::os_mbuf_free_chain(om);
-                return Err(e);
-            };
+        if let Err(e) = packet.write_hci(&mut om) {
+            error!(
+                "could not serialize acl packet: acl {} error {}",
+                Debug2Format(&packet),
+                Debug2Format(&e)
+            );
+            return Err(ControllerError::NoMem);
+        };
 
+        unsafe {
+            let om = om.into_raw();
             let ret = raw::ble_transport_to_ll_acl_impl(om);
             if ret != 0 {
                 error!(
@@ -302,6 +965,7 @@ impl bt_hci::controller::Controller for NimbleController {
                     ret
                 );
                 raw::os_mbuf_free_chain(om);
+                return Err(ControllerError::TransportRejected(ret));
             }
         }
         Ok(())
@@ -319,18 +983,26 @@ impl bt_hci::controller::Controller for NimbleController {
         packet: &bt_hci::data::IsoPacket<'_>,
     ) -> Result<(), Self::Error> {
         trace!("sending iso to controller");
-        unsafe {
-            let om = raw::ble_transport_alloc_iso_from_hs();
-            if let Err(e) = packet.write_hci::<OsMbuf>(om.into()) {
-                error!(
-                    "could not serialize iso packet: iso {} error {}",
-                    Debug2Format(&packet),
-                    Debug2Format(&e)
-                );
-                raw::os_mbuf_free_chain(om);
-                return Err(e);
-            };
+        if !is_current(self.generation) {
+            return Err(ControllerError::NotStarted);
+        }
+        let Some(mut om) = (unsafe { Mbuf::from_raw(raw::ble_transport_alloc_iso_from_hs()) })
+        else {
+            error!("could not allocate space for an iso packet to send to controller");
+            return Err(ControllerError::NoMem);
+        };
+
+        if let Err(e) = packet.write_hci(&mut om) {
+            error!(
+                "could not serialize iso packet: iso {} error {}",
+                Debug2Format(&packet),
+                Debug2Format(&e)
+            );
+            return Err(ControllerError::NoMem);
+        };
 
+        unsafe {
+            let om = om.into_raw();
             let ret = raw::ble_transport_to_ll_iso_impl(om);
             if ret != 0 {
                 error!(
@@ -339,6 +1011,7 @@ impl bt_hci::controller::Controller for NimbleController {
                     ret
                 );
                 raw::os_mbuf_free_chain(om);
+                return Err(ControllerError::TransportRejected(ret));
             }
         }
         Ok(())
@@ -348,16 +1021,24 @@ impl bt_hci::controller::Controller for NimbleController {
         &self,
         buf: &'a mut [u8],
     ) -> Result<bt_hci::ControllerToHostPacket<'a>, Self::Error> {
-        let len = buf.len();
+        let len = buf.len().min(HCI_PKT_BUF_SIZE);
         loop {
             // This should be safe because references to buf aren't being carried across loop iterations
             let buf = unsafe { core::slice::from_raw_parts_mut(buf.as_mut_ptr(), len) };
-            let (kind, data) = READ_CHANNEL.receive().await;
-            buf.copy_from_slice(&data[..len]);
-            match ControllerToHostPacket::from_hci_bytes_with_kind(kind, buf) {
+
+            // events are polled first, so that e.g. a disconnection isn't delayed behind a backlog
+            // of ACL data
+            let event = match select(READ_CHANNEL.receive(), ACL_CHANNEL.receive()).await {
+                Either::First(event) => event,
+                Either::Second(acl) => return Self::read_acl(acl, buf),
+            };
+            buf.copy_from_slice(&event.data[..len]);
+            match ControllerToHostPacket::from_hci_bytes_with_kind(PacketKind::Event, buf) {
                 Ok((ControllerToHostPacket::Event(Event::CommandComplete(_)), _))
                 | Ok((ControllerToHostPacket::Event(Event::CommandStatus(_)), _)) => {
-                    CMD_SIGNAL.send(data).await;
+                    if !retire_cancelled_cmd(event.cmd_buf) {
+                        CMD_SIGNAL.send(event).await;
+                    }
                     continue;
                 }
                 Ok(value) => {
@@ -366,23 +1047,54 @@ impl bt_hci::controller::Controller for NimbleController {
                 }
                 Err(e) => {
                     error!("error reading packet from controller: {}", Debug2Format(&e));
-                    return Err(OsError::Invalid);
+                    return Err(ControllerError::MalformedEvent);
                 }
             }
         }
     }
 }
 
-impl<C: SyncCmd + Debug> ControllerCmdSync<C> for NimbleController
-where
-    C::Return: Debug,
-{
-    async fn exec(
+impl NimbleController {
+    /// Copies ACL data received by the controller into `buf`, and releases the mbuf back to the
+    /// transport's ACL pool.
+    fn read_acl(acl: Mbuf, buf: &mut [u8]) -> Result<ControllerToHostPacket<'_>, ControllerError> {
+        let pkt_len = acl.len();
+        if pkt_len > buf.len() || acl.copy_to(0, &mut buf[..pkt_len]).is_err() {
+            error!(
+                "acl packet from controller does not fit in read buffer: packet {} buffer {}",
+                pkt_len,
+                buf.len()
+            );
+            return Err(ControllerError::BufferTooSmall);
+        }
+        // release the mbuf back to the ACL pool as soon as possible
+        drop(acl);
+
+        ControllerToHostPacket::from_hci_bytes_with_kind(PacketKind::AclData, &buf[..pkt_len])
+            .map(|value| {
+                trace!("reading packet from controller: {}", Debug2Format(&value.0));
+                value.0
+            })
+            .map_err(|e| {
+                error!("error reading acl from controller: {}", Debug2Format(&e));
+                ControllerError::MalformedEvent
+            })
+    }
+}
+
+impl NimbleController {
+    /// Same as [`ControllerCmdSync::exec`], but with a different timeout than the one from
+    /// [`crate::Config::command_timeout`].
+    pub async fn exec_with_timeout<C: SyncCmd + Debug>(
         &self,
         cmd: &C,
-    ) -> Result<<C as SyncCmd>::Return, bt_hci::cmd::Error<Self::Error>> {
+        timeout: embassy_time::Duration,
+    ) -> Result<C::Return, Error<ControllerError>>
+    where
+        C::Return: Debug,
+    {
         let mut buf = [0; HCI_PKT_BUF_SIZE];
-        let response = self.execute_command(&mut buf, cmd).await?;
+        let response = self.execute_command(&mut buf, cmd, timeout).await?;
 
         match response {
             Event::CommandComplete(c) => {
@@ -410,7 +1122,10 @@ where
                         Debug2Format(&cmd),
                         Debug2Format(&c)
                     );
-                    Err(Error::Io(OsError::InvalidParameter))
+                    Err(Error::Io(ControllerError::OpcodeMismatch {
+                        expected: C::OPCODE.to_raw(),
+                        received: c.cmd_opcode.to_raw(),
+                    }))
                 }
             }
             r => {
@@ -419,16 +1134,20 @@ where
                     Debug2Format(&cmd),
                     Debug2Format(&r)
                 );
-                Err(Error::Io(OsError::InvalidParameter))
+                Err(Error::Io(ControllerError::UnexpectedResponse))
             }
         }
     }
-}
 
-impl<C: AsyncCmd + Debug> ControllerCmdAsync<C> for NimbleController {
-    async fn exec(&self, cmd: &C) -> Result<(), bt_hci::cmd::Error<Self::Error>> {
+    /// Same as [`ControllerCmdAsync::exec`], but with a different timeout than the one from
+    /// [`crate::Config::command_timeout`].
+    pub async fn exec_async_with_timeout<C: AsyncCmd + Debug>(
+        &self,
+        cmd: &C,
+        timeout: embassy_time::Duration,
+    ) -> Result<(), Error<ControllerError>> {
         let mut buf = [0; HCI_PKT_BUF_SIZE];
-        let response = self.execute_command(&mut buf, cmd).await?;
+        let response = self.execute_command(&mut buf, cmd, timeout).await?;
 
         match response {
             Event::CommandStatus(c) => {
@@ -445,7 +1164,10 @@ impl<C: AsyncCmd + Debug> ControllerCmdAsync<C> for NimbleController {
                         Debug2Format(&cmd),
                         Debug2Format(&c)
                     );
-                    Err(Error::Io(OsError::InvalidParameter))
+                    Err(Error::Io(ControllerError::OpcodeMismatch {
+                        expected: C::OPCODE.to_raw(),
+                        received: c.cmd_opcode.to_raw(),
+                    }))
                 }
             }
             r => {
@@ -454,8 +1176,304 @@ impl<C: AsyncCmd + Debug> ControllerCmdAsync<C> for NimbleController {
                     Debug2Format(&cmd),
                     Debug2Format(&r),
                 );
-                Err(Error::Io(OsError::InvalidParameter))
+                Err(Error::Io(ControllerError::UnexpectedResponse))
+            }
+        }
+    }
+}
+
+impl<C: SyncCmd + Debug> ControllerCmdSync<C> for NimbleController
+where
+    C::Return: Debug,
+{
+    async fn exec(
+        &self,
+        cmd: &C,
+    ) -> Result<<C as SyncCmd>::Return, bt_hci::cmd::Error<Self::Error>> {
+        self.exec_with_timeout(cmd, self.cmd_timeout).await
+    }
+}
+
+impl<C: AsyncCmd + Debug> ControllerCmdAsync<C> for NimbleController {
+    async fn exec(&self, cmd: &C) -> Result<(), bt_hci::cmd::Error<Self::Error>> {
+        self.exec_async_with_timeout(cmd, self.cmd_timeout).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "encryption")]
+    use super::encrypt_block;
+
+    /// Tests against the fake link layer. With the host enabled, the events go to NimBLE's host
+    /// instead.
+    #[cfg(not(feature = "host"))]
+    mod commands {
+        use bt_hci::cmd::controller_baseband::Reset;
+        use bt_hci::cmd::Error;
+        use bt_hci::controller::{Controller, ControllerCmdSync};
+        use embassy_futures::join::{join, join3};
+        use embassy_futures::select::{select, select3, Either, Either3};
+        use embassy_futures::{block_on, yield_now};
+        use embassy_time::Duration;
+
+        use super::super::{ble_transport_to_hs_evt_impl, ControllerError, HCI_PKT_BUF_SIZE};
+        use crate::test_support::{fake_ll, lock_stack};
+        use crate::{Config, Nimble, NimbleHandle};
+
+        fn init() -> NimbleHandle {
+            Nimble::init(Config::default().command_timeout(Duration::from_millis(100))).unwrap()
+        }
+
+        /// Waits for the next command sent to the fake link layer.
+        async fn next_cmd() -> *mut cty::c_void {
+            loop {
+                if let Some(buf) = fake_ll::take_cmd() {
+                    return buf;
+                }
+                yield_now().await;
+            }
+        }
+
+        /// Answers a command with a successful command complete event, written to the command's
+        /// own buffer like the link layer does.
+        fn respond(buf: *mut cty::c_void) {
+            respond_with_status(buf, 0);
+        }
+
+        fn respond_with_status(buf: *mut cty::c_void, status: u8) {
+            let bytes = buf as *mut u8;
+            unsafe {
+                let opcode = [*bytes, *bytes.add(1)];
+                // status is the only return parameter of the commands used here
+                let event = [0x0e, 4, 1, opcode[0], opcode[1], status];
+                core::ptr::copy_nonoverlapping(event.as_ptr(), bytes, event.len());
+            }
+            assert_eq!(ble_transport_to_hs_evt_impl(buf), 0);
+        }
+
+        async fn answer_cmds() -> ! {
+            loop {
+                respond(next_cmd().await);
+            }
+        }
+
+        /// Reads from the controller, which passes the command responses on to `execute_command`.
+        async fn read_events(nimble: &NimbleHandle) -> ! {
+            let mut buf = [0; HCI_PKT_BUF_SIZE];
+            loop {
+                let _ = nimble.controller.read(&mut buf).await;
             }
         }
+
+        /// Runs `fut` while the fake link layer answers every command.
+        fn with_ll<T>(nimble: &NimbleHandle, fut: impl core::future::Future<Output = T>) -> T {
+            match block_on(select3(fut, read_events(nimble), answer_cmds())) {
+                Either3::First(value) => value,
+            }
+        }
+
+        #[test]
+        fn first_cmd_after_reset_gets_its_own_response() {
+            let _stack = lock_stack();
+            let nimble = init();
+
+            // a command that's cancelled before the link layer gets to it
+            let stale = match block_on(select(
+                nimble.controller.set_default_phy(&[], &[]),
+                next_cmd(),
+            )) {
+                Either::Second(buf) => buf,
+                Either::First(_) => unreachable!("the command was answered"),
+            };
+
+            // its response shows up late, with an error status that the next command mustn't see
+            let (reset, next) = match block_on(select3(
+                async {
+                    let reset = ControllerCmdSync::exec(&nimble.controller, &Reset::new()).await;
+                    (reset, nimble.controller.set_default_phy(&[], &[]).await)
+                },
+                read_events(&nimble),
+                async {
+                    respond_with_status(stale, 0x0c);
+                    answer_cmds().await
+                },
+            )) {
+                Either3::First(value) => value,
+            };
+            assert!(reset.is_ok());
+            assert!(next.is_ok());
+
+            block_on(Nimble::shutdown()).unwrap();
+        }
+
+        #[test]
+        fn reset_cancels_queued_cmds() {
+            let _stack = lock_stack();
+            let nimble = init();
+
+            let (before, reset, queued) = with_ll(
+                &nimble,
+                join3(
+                    nimble.controller.set_default_phy(&[], &[]),
+                    ControllerCmdSync::exec(&nimble.controller, &Reset::new()),
+                    // waits for the lock behind the reset
+                    nimble.controller.set_default_phy(&[], &[]),
+                ),
+            );
+            assert!(before.is_ok());
+            assert!(reset.is_ok());
+            assert!(matches!(queued, Err(Error::Io(ControllerError::Cancelled))));
+
+            // commands sent after the reset go through
+            assert!(with_ll(&nimble, nimble.controller.set_default_phy(&[], &[])).is_ok());
+
+            block_on(Nimble::shutdown()).unwrap();
+        }
+
+        #[test]
+        fn unanswered_cmd_is_abandoned() {
+            let _stack = lock_stack();
+            let nimble = init();
+
+            let (timed_out, stale) = block_on(join(
+                nimble.controller.set_default_phy(&[], &[]),
+                next_cmd(),
+            ));
+            assert!(matches!(
+                timed_out,
+                Err(Error::Io(ControllerError::Timeout))
+            ));
+
+            // the link layer still holds the only command buffer
+            assert!(matches!(
+                block_on(select(
+                    nimble.controller.set_default_phy(&[], &[]),
+                    read_events(&nimble)
+                )),
+                Either::First(Err(Error::Io(ControllerError::NoMem)))
+            ));
+
+            // the late response frees it, without being mistaken for the next command's
+            let next = match block_on(select3(
+                nimble.controller.set_default_phy(&[], &[]),
+                read_events(&nimble),
+                async {
+                    respond_with_status(stale, 0x0c);
+                    answer_cmds().await
+                },
+            )) {
+                Either3::First(value) => value,
+            };
+            assert!(next.is_ok());
+
+            block_on(Nimble::shutdown()).unwrap();
+        }
+
+        #[test]
+        fn shutdown_aborts_the_cmd_in_progress() {
+            let _stack = lock_stack();
+            for _ in 0..50 {
+                let nimble = init();
+                let (result, shutdown) =
+                    block_on(join(nimble.controller.set_default_phy(&[], &[]), async {
+                        // leave the command unanswered
+                        next_cmd().await;
+                        Nimble::shutdown().await
+                    }));
+                assert!(matches!(
+                    result,
+                    Err(Error::Io(ControllerError::NotStarted))
+                ));
+                assert!(shutdown.is_ok());
+
+                assert!(matches!(
+                    block_on(nimble.controller.set_default_phy(&[], &[])),
+                    Err(Error::Io(ControllerError::NotStarted))
+                ));
+            }
+
+            // the aborted command doesn't hold up the next generation
+            let nimble = init();
+            assert!(with_ll(&nimble, nimble.controller.set_default_phy(&[], &[])).is_ok());
+            block_on(Nimble::shutdown()).unwrap();
+        }
+    }
+
+    /// The ranges are checked before sending anything, and reported as our own error rather than
+    /// an HCI status.
+    #[cfg(feature = "data-length-extension")]
+    #[test]
+    fn data_length_out_of_range() {
+        use bt_hci::cmd::Error;
+
+        use super::{ControllerError, NimbleController};
+
+        assert!(NimbleController::check_data_length(0x1b, 0x148).is_ok());
+        assert!(NimbleController::check_data_length(0xfb, 0x4290).is_ok());
+        for (tx_octets, tx_time) in [(0x1a, 0x148), (0xfc, 0x148), (0x1b, 0x147), (0x1b, 0x4291)] {
+            assert!(matches!(
+                NimbleController::check_data_length(tx_octets, tx_time),
+                Err(Error::Io(ControllerError::InvalidParameter))
+            ));
+        }
+    }
+
+    #[cfg(feature = "encryption")]
+    fn hex(s: &str) -> [u8; 16] {
+        let mut out = [0; 16];
+        for (i, byte) in out.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap();
+        }
+        out
+    }
+
+    /// FIPS-197, appendices B and C.1.
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn encrypt_block_fips_197() {
+        assert_eq!(
+            encrypt_block(
+                &hex("2b7e151628aed2a6abf7158809cf4f3c"),
+                &hex("3243f6a8885a308d313198a2e0370734"),
+            ),
+            Ok(hex("3925841d02dc09fbdc118597196a0b32"))
+        );
+        assert_eq!(
+            encrypt_block(
+                &hex("000102030405060708090a0b0c0d0e0f"),
+                &hex("00112233445566778899aabbccddeeff"),
+            ),
+            Ok(hex("69c4e0d86a7b0430d8cdb78070b4c55a"))
+        );
+    }
+
+    /// Core Specification, Vol 6, Part C, 1 (sample data for the `LE Encrypt` command), written
+    /// most significant octet first.
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn encrypt_block_core_spec_e() {
+        assert_eq!(
+            encrypt_block(
+                &hex("4c68384139f574d836bcf34e9dfb01bf"),
+                &hex("0213243546576879acbdcedfe0f10213"),
+            ),
+            Ok(hex("99ad1b5226a37e3e058e3b8e27c2c666"))
+        );
+    }
+
+    /// The random address hash function `ah` used to resolve RPAs, with the sample data from the
+    /// Core Specification, Vol 3, Part H, D.7.
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn encrypt_block_resolves_rpa() {
+        let irk = hex("ec0234a357c8ad05341010a60a397d9b");
+        let prand = [0x70, 0x81, 0x94];
+
+        let mut r = [0; 16];
+        r[13..].copy_from_slice(&prand);
+        let e = encrypt_block(&irk, &r).unwrap();
+        assert_eq!(e, hex("159d5fb72ebe2311a48c1bdcc40dfbaa"));
+        assert_eq!(e[13..], [0x0d, 0xfb, 0xaa]);
     }
 }
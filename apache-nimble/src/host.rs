@@ -1,7 +1,79 @@
+use core::cell::RefCell;
+use core::future::poll_fn;
 use core::mem::MaybeUninit;
 use core::ptr::addr_of;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::Poll;
 
-use crate::raw;
+use defmt::{error, trace};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::waitqueue::MultiWakerRegistration;
+
+use crate::{is_current, raw, OsError, TaskControl};
+
+pub mod ad;
+#[cfg(feature = "role-broadcaster")]
+pub mod adv;
+#[cfg(feature = "role-central")]
+pub mod central;
+#[cfg(any(feature = "role-central", feature = "role-peripheral"))]
+pub mod connection;
+pub mod gatt;
+#[cfg(any(feature = "role-central", feature = "role-peripheral"))]
+pub mod gatt_client;
+#[cfg(all(
+    feature = "l2cap-coc",
+    any(feature = "role-central", feature = "role-peripheral")
+))]
+pub mod l2cap;
+#[cfg(feature = "role-observer")]
+pub mod scan;
+#[cfg(all(
+    feature = "security",
+    any(feature = "role-central", feature = "role-peripheral")
+))]
+pub mod security;
+#[cfg(feature = "bond-store")]
+pub mod store;
+
+pub use ad::{AdStructure, AdStructures, AdvData, AdvFlags};
+#[cfg(feature = "role-broadcaster")]
+pub use adv::{AdvEnd, AdvParams, Advertiser};
+#[cfg(feature = "role-central")]
+pub use central::{Central, ConnectParams};
+#[cfg(any(feature = "role-central", feature = "role-peripheral"))]
+pub use connection::{ConnEvent, ConnInfo, ConnParams, Connection, Role};
+#[cfg(all(
+    feature = "gatt-notify-multiple",
+    any(feature = "role-central", feature = "role-peripheral")
+))]
+pub use gatt::NotifyMultiple;
+pub use gatt::{Characteristic, GattBytes, GattService, GattValue, Subscription};
+#[cfg(any(feature = "role-central", feature = "role-peripheral"))]
+pub use gatt_client::{
+    CharProperties, GattClient, Notification, RemoteCharacteristic, RemoteDescriptor,
+    RemoteService, Uuid,
+};
+#[cfg(all(
+    feature = "l2cap-coc",
+    any(feature = "role-central", feature = "role-peripheral")
+))]
+pub use l2cap::{L2capChannel, L2capChannelInfo, L2capListener};
+#[cfg(feature = "role-observer")]
+pub use scan::{ScanFilterPolicy, ScanParams, ScanReport, Scanner};
+#[cfg(all(
+    feature = "security-sc",
+    any(feature = "role-central", feature = "role-peripheral")
+))]
+pub use security::ScOobData;
+#[cfg(all(
+    feature = "security",
+    any(feature = "role-central", feature = "role-peripheral")
+))]
+pub use security::{IoCapabilities, JustWorks, PairingHandler, SecurityConfig};
+#[cfg(feature = "bond-store")]
+pub use store::{FlashBondStore, RamFlash};
 
 #[cfg(not(feature = "controller"))]
 #[no_mangle]
@@ -23,14 +95,546 @@ extern "C" fn ble_transport_to_ll_cmd_impl() {
 
 pub(crate) static mut DEFLT_EVQ: MaybeUninit<raw::ble_npl_eventq> = MaybeUninit::uninit();
 
-pub async unsafe fn nimble_port_run() -> ! {
+async unsafe fn nimble_port_run() -> ! {
     loop {
         let ev = raw::ble_npl_eventq_get(addr_of!(DEFLT_EVQ) as *mut _, u32::MAX).await;
         raw::ble_npl_event_run(ev);
     }
 }
 
+/// Whether the host has synced with the controller, i.e. finished its startup sequence.
+static SYNCED: AtomicBool = AtomicBool::new(false);
+static SYNC_WAKERS: Mutex<CriticalSectionRawMutex, RefCell<MultiWakerRegistration<4>>> =
+    Mutex::new(RefCell::new(MultiWakerRegistration::new()));
+
+unsafe extern "C" fn on_sync() {
+    trace!("host synced");
+    SYNCED.store(true, Ordering::Release);
+    SYNC_WAKERS.lock(|w| w.borrow_mut().wake());
+}
+
+unsafe extern "C" fn on_reset(reason: cty::c_int) {
+    error!("host reset: {}", HostError::from(reason));
+    SYNCED.store(false, Ordering::Release);
+}
+
+/// Initializes the host and the GAP and GATT services. The host starts (and syncs with the
+/// controller) once the host task runs.
+pub(crate) unsafe fn init() {
+    raw::ble_hs_cfg.sync_cb = Some(on_sync);
+    raw::ble_hs_cfg.reset_cb = Some(on_reset);
+
+    raw::ble_hs_init();
+    raw::ble_svc_gap_init();
+    raw::ble_svc_gatt_init();
+}
+
+/// Drops any queued host events. The host task needs to be stopped first.
+pub(crate) unsafe fn deinit() {
+    SYNCED.store(false, Ordering::Release);
+    #[cfg(feature = "role-broadcaster")]
+    adv::reset();
+    #[cfg(feature = "role-central")]
+    central::reset();
+    #[cfg(any(feature = "role-central", feature = "role-peripheral"))]
+    connection::reset();
+    #[cfg(any(feature = "role-central", feature = "role-peripheral"))]
+    gatt_client::reset();
+    gatt::reset();
+    #[cfg(all(
+        feature = "l2cap-coc",
+        any(feature = "role-central", feature = "role-peripheral")
+    ))]
+    l2cap::reset();
+    #[cfg(feature = "role-observer")]
+    scan::reset();
+    #[cfg(all(
+        feature = "security",
+        any(feature = "role-central", feature = "role-peripheral")
+    ))]
+    security::reset();
+    // let anyone waiting for the sync see that the host is gone
+    SYNC_WAKERS.lock(|w| w.borrow_mut().wake());
+
+    raw::ble_npl_eventq_deinit(addr_of!(DEFLT_EVQ) as *mut _);
+}
+
+pub(crate) static HOST_TASK: TaskControl = TaskControl::new();
+
+/// Runs the host's default event queue.
+pub struct NimbleHostTask {
+    generation: u32,
+}
+
+impl NimbleHostTask {
+    pub(crate) fn new(generation: u32) -> Self {
+        Self { generation }
+    }
+
+    /// Runs the host until [`crate::Nimble::shutdown`] is called.
+    pub async fn run(&self) {
+        HOST_TASK
+            .run(self.generation, unsafe { nimble_port_run() })
+            .await
+    }
+}
+
 #[no_mangle]
 extern "C" fn nimble_port_get_dflt_eventq() -> *mut raw::ble_npl_eventq {
     unsafe { addr_of!(DEFLT_EVQ) as *mut _ }
 }
+
+/// Entry point to the host's GAP and GATT procedures. The host task needs to be running for any
+/// of them to make progress.
+pub struct NimbleHost {
+    generation: u32,
+}
+
+impl NimbleHost {
+    pub(crate) fn new(generation: u32) -> Self {
+        Self { generation }
+    }
+
+    /// Whether the host has finished starting up, and can be used.
+    pub fn is_synced(&self) -> bool {
+        is_current(self.generation) && SYNCED.load(Ordering::Acquire)
+    }
+
+    /// Waits until the host has synced with the controller. The procedures started through this
+    /// type do this on their own.
+    pub async fn wait_for_sync(&self) -> Result<(), HostError> {
+        wait_for_sync(self.generation).await
+    }
+}
+
+/// Waits until the host has synced with the controller, or fails with [`HostError::Disabled`] if
+/// NimBLE was shut down since `generation`.
+pub(crate) async fn wait_for_sync(generation: u32) -> Result<(), HostError> {
+    poll_fn(|cx| {
+        if !is_current(generation) {
+            return Poll::Ready(Err(HostError::Disabled));
+        }
+        if SYNCED.load(Ordering::Acquire) {
+            return Poll::Ready(Ok(()));
+        }
+        SYNC_WAKERS.lock(|w| w.borrow_mut().register(cx.waker()));
+        // the sync might have happened while registering
+        if SYNCED.load(Ordering::Acquire) {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+/// Fails with [`HostError::Disabled`] if NimBLE was shut down since `generation`.
+pub(crate) fn check_current(generation: u32) -> Result<(), HostError> {
+    if is_current(generation) {
+        Ok(())
+    } else {
+        Err(HostError::Disabled)
+    }
+}
+
+/// Callback passed to the GAP procedures started by this crate. NimBLE also reports the events of
+/// the connections created by those procedures through it.
+#[cfg(any(feature = "role-broadcaster", feature = "role-observer"))]
+pub(crate) unsafe extern "C" fn gap_event(
+    event: *mut raw::ble_gap_event,
+    _arg: *mut cty::c_void,
+) -> cty::c_int {
+    let event = &mut *event;
+    match event.type_ as u32 {
+        #[cfg(any(feature = "role-central", feature = "role-peripheral"))]
+        raw::BLE_GAP_EVENT_CONNECT => {
+            let connect = &event.__bindgen_anon_1.connect;
+            let conn = match connect.status {
+                0 => connection::on_connect(connect.conn_handle),
+                _ => None,
+            };
+            if let Some(conn) = conn {
+                gatt::clear_subscriptions(conn.slot());
+                #[cfg(feature = "security")]
+                security::on_connect(conn.slot());
+            }
+            #[cfg(feature = "role-central")]
+            central::on_connect(connect.status, conn);
+        }
+        #[cfg(any(feature = "role-central", feature = "role-peripheral"))]
+        raw::BLE_GAP_EVENT_DISCONNECT => {
+            let disconnect = &event.__bindgen_anon_1.disconnect;
+            #[cfg(feature = "security")]
+            if let Some(slot) = connection::find_slot(disconnect.conn.conn_handle) {
+                security::on_done(slot, disconnect.reason);
+            }
+            connection::on_disconnect(disconnect.conn.conn_handle, disconnect.reason);
+        }
+        #[cfg(any(feature = "role-central", feature = "role-peripheral"))]
+        raw::BLE_GAP_EVENT_CONN_UPDATE => {
+            let update = &event.__bindgen_anon_1.conn_update;
+            let conn_event = connection::ConnEvent::ParamsUpdated(check(update.status));
+            connection::on_event(update.conn_handle, conn_event);
+        }
+        #[cfg(any(feature = "role-central", feature = "role-peripheral"))]
+        raw::BLE_GAP_EVENT_ENC_CHANGE => {
+            let change = &event.__bindgen_anon_1.enc_change;
+            #[cfg(feature = "security")]
+            if let Some(slot) = connection::find_slot(change.conn_handle) {
+                security::on_done(slot, change.status);
+            }
+            let conn_event = connection::ConnEvent::EncryptionChanged(check(change.status));
+            connection::on_event(change.conn_handle, conn_event);
+        }
+        #[cfg(any(feature = "role-central", feature = "role-peripheral"))]
+        raw::BLE_GAP_EVENT_MTU => {
+            let mtu = &event.__bindgen_anon_1.mtu;
+            connection::on_event(
+                mtu.conn_handle,
+                connection::ConnEvent::MtuChanged(mtu.value),
+            );
+        }
+        #[cfg(any(feature = "role-central", feature = "role-peripheral"))]
+        raw::BLE_GAP_EVENT_NOTIFY_RX => {
+            let rx = &mut event.__bindgen_anon_1.notify_rx;
+            // take the mbuf, so that NimBLE doesn't free it after the callback
+            if let Some(data) = crate::Mbuf::from_raw(rx.om) {
+                rx.om = core::ptr::null_mut();
+                let notification = gatt_client::Notification {
+                    handle: rx.attr_handle,
+                    indication: rx.indication() != 0,
+                    data,
+                };
+                connection::on_notification(rx.conn_handle, notification);
+            }
+        }
+        #[cfg(any(feature = "role-central", feature = "role-peripheral"))]
+        raw::BLE_GAP_EVENT_SUBSCRIBE => {
+            let subscribe = &event.__bindgen_anon_1.subscribe;
+            if let Some(slot) = connection::find_slot(subscribe.conn_handle) {
+                gatt::on_subscribe(
+                    slot,
+                    subscribe.attr_handle,
+                    subscribe.cur_notify() != 0,
+                    subscribe.cur_indicate() != 0,
+                );
+            }
+        }
+        #[cfg(any(feature = "role-central", feature = "role-peripheral"))]
+        raw::BLE_GAP_EVENT_NOTIFY_TX => {
+            let tx = &event.__bindgen_anon_1.notify_tx;
+            if tx.indication() != 0 {
+                if let Some(slot) = connection::find_slot(tx.conn_handle) {
+                    gatt::on_indicate_tx(slot, tx.attr_handle, tx.status);
+                }
+            }
+        }
+        #[cfg(all(
+            feature = "security",
+            any(feature = "role-central", feature = "role-peripheral")
+        ))]
+        raw::BLE_GAP_EVENT_PASSKEY_ACTION => {
+            let passkey = &event.__bindgen_anon_1.passkey;
+            if let Some(slot) = connection::find_slot(passkey.conn_handle) {
+                security::on_passkey_action(slot, passkey.params.action, passkey.params.numcmp);
+            }
+        }
+        #[cfg(all(
+            feature = "security",
+            any(feature = "role-central", feature = "role-peripheral")
+        ))]
+        raw::BLE_GAP_EVENT_REPEAT_PAIRING => {
+            return security::on_repeat_pairing(event.__bindgen_anon_1.repeat_pairing.conn_handle);
+        }
+        #[cfg(feature = "role-broadcaster")]
+        raw::BLE_GAP_EVENT_ADV_COMPLETE => {
+            let complete = &event.__bindgen_anon_1.adv_complete;
+            adv::on_complete(complete.instance, complete.reason, complete.conn_handle);
+        }
+        #[cfg(feature = "role-observer")]
+        raw::BLE_GAP_EVENT_EXT_DISC => scan::on_report(&event.__bindgen_anon_1.ext_disc),
+        #[cfg(feature = "role-observer")]
+        raw::BLE_GAP_EVENT_DISC_COMPLETE => {
+            scan::on_complete(event.__bindgen_anon_1.disc_complete.reason);
+        }
+        _ => {}
+    }
+    0
+}
+
+/// Type of a device address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum AddressKind {
+    Public,
+    Random,
+    /// Public identity address of a peer whose resolvable private address was resolved.
+    PublicIdentity,
+    /// Random (static) identity address of a peer whose resolvable private address was resolved.
+    RandomIdentity,
+}
+
+/// A Bluetooth device address. The bytes are in the order they're sent over the air (least
+/// significant byte first).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Address {
+    pub kind: AddressKind,
+    pub bytes: [u8; 6],
+}
+
+impl Address {
+    pub const fn new(kind: AddressKind, bytes: [u8; 6]) -> Self {
+        Self { kind, bytes }
+    }
+
+    #[cfg(any(feature = "role-peripheral", feature = "role-observer"))]
+    pub(crate) fn from_raw(addr: &raw::ble_addr_t) -> Self {
+        let kind = match addr.type_ as u32 {
+            raw::BLE_ADDR_PUBLIC => AddressKind::Public,
+            raw::BLE_ADDR_PUBLIC_ID => AddressKind::PublicIdentity,
+            raw::BLE_ADDR_RANDOM_ID => AddressKind::RandomIdentity,
+            _ => AddressKind::Random,
+        };
+        Self {
+            kind,
+            bytes: addr.val,
+        }
+    }
+
+    #[cfg(any(feature = "role-central", feature = "bond-store"))]
+    pub(crate) fn to_raw(&self) -> raw::ble_addr_t {
+        let type_ = match self.kind {
+            AddressKind::Public => raw::BLE_ADDR_PUBLIC,
+            AddressKind::Random => raw::BLE_ADDR_RANDOM,
+            AddressKind::PublicIdentity => raw::BLE_ADDR_PUBLIC_ID,
+            AddressKind::RandomIdentity => raw::BLE_ADDR_RANDOM_ID,
+        };
+        raw::ble_addr_t {
+            type_: type_ as u8,
+            val: self.bytes,
+        }
+    }
+}
+
+/// Address used by the local device for advertising, scanning or initiating connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum OwnAddressKind {
+    Public,
+    Random,
+    /// A resolvable private address, or the public address if the controller has no IRK for the
+    /// peer.
+    RpaOrPublic,
+    /// A resolvable private address, or the random address if the controller has no IRK for the
+    /// peer.
+    RpaOrRandom,
+}
+
+/// The value of `own_addr_type` to use for a procedure. Without an explicit choice, NimBLE picks
+/// the best address available (public if there is one, random otherwise).
+#[cfg(any(feature = "role-broadcaster", feature = "role-observer"))]
+pub(crate) fn own_addr_type(kind: Option<OwnAddressKind>) -> Result<u8, HostError> {
+    let addr_type = match kind {
+        Some(OwnAddressKind::Public) => raw::BLE_OWN_ADDR_PUBLIC,
+        Some(OwnAddressKind::Random) => raw::BLE_OWN_ADDR_RANDOM,
+        Some(OwnAddressKind::RpaOrPublic) => raw::BLE_OWN_ADDR_RPA_PUBLIC_DEFAULT,
+        Some(OwnAddressKind::RpaOrRandom) => raw::BLE_OWN_ADDR_RPA_RANDOM_DEFAULT,
+        None => {
+            let mut addr_type = 0;
+            check(unsafe { raw::ble_hs_id_infer_auto(0, &mut addr_type) })?;
+            return Ok(addr_type);
+        }
+    };
+    Ok(addr_type as u8)
+}
+
+/// Scan interval or window, in units of 0.625 ms.
+#[cfg(feature = "role-observer")]
+pub(crate) fn scan_interval(interval: embassy_time::Duration) -> u16 {
+    (interval.as_micros() / 625).min(u16::MAX as u64) as u16
+}
+
+/// Converts a NimBLE host return code into a `Result`.
+pub(crate) fn check(rc: cty::c_int) -> Result<(), HostError> {
+    if rc == 0 {
+        Ok(())
+    } else {
+        Err(HostError::from(rc))
+    }
+}
+
+/// Errors returned by the host (NimBLE's `BLE_HS_E*` codes), and errors reported by the
+/// controller or peers, which NimBLE reports with the same codes (offset by a base per protocol).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum HostError {
+    /// Temporary failure, try again.
+    Again,
+    /// The procedure is already in progress, or already done.
+    Already,
+    Invalid,
+    /// The data doesn't fit, e.g. advertising data longer than the PDU allows.
+    MessageTooLong,
+    NotFound,
+    NoMem,
+    NotConnected,
+    NotSupported,
+    /// The application callback returned an error.
+    App,
+    /// A peer sent malformed data.
+    BadData,
+    Os,
+    /// The controller reported an error that doesn't have an HCI error code.
+    Controller,
+    Timeout,
+    /// The procedure completed (e.g. the maximum number of advertising events was reached).
+    Done,
+    Busy,
+    Rejected,
+    Unknown,
+    /// The operation isn't allowed in the current role.
+    Role,
+    /// The controller didn't respond to an HCI command in time.
+    HciTimeout,
+    /// An event couldn't be allocated.
+    NoMemEvent,
+    /// The device has no address of the requested type.
+    NoAddress,
+    /// The host hasn't synced with the controller yet.
+    NotSynced,
+    InsufficientAuthentication,
+    InsufficientAuthorization,
+    InsufficientEncryption,
+    InsufficientKeySize,
+    /// The bond store is full.
+    StoreCapacity,
+    /// The bond store failed.
+    StoreFailed,
+    /// The procedure was preempted by another one.
+    Preempted,
+    /// NimBLE was shut down, or the feature is disabled.
+    Disabled,
+    /// The procedure stalled.
+    Stalled,
+    /// The peer hasn't enabled notifications (or indications) of the characteristic. Not a NimBLE
+    /// code: returned by [`Characteristic::notify`] and [`Characteristic::indicate`].
+    NotSubscribed,
+    /// ATT error code, received from a peer.
+    Att(u8),
+    /// HCI error code, reported by the controller (e.g. a disconnection reason).
+    Hci(u8),
+    /// L2CAP error code.
+    L2cap(u8),
+    /// Security manager error code, caused by the local device.
+    SmUs(u8),
+    /// Security manager error code, received from a peer.
+    SmPeer(u8),
+    /// Hardware error code.
+    Hw(u8),
+    Other(i32),
+}
+
+impl From<cty::c_int> for HostError {
+    fn from(value: cty::c_int) -> Self {
+        let code = value as u32;
+        match code {
+            raw::BLE_HS_EAGAIN => HostError::Again,
+            raw::BLE_HS_EALREADY => HostError::Already,
+            raw::BLE_HS_EINVAL => HostError::Invalid,
+            raw::BLE_HS_EMSGSIZE => HostError::MessageTooLong,
+            raw::BLE_HS_ENOENT => HostError::NotFound,
+            raw::BLE_HS_ENOMEM => HostError::NoMem,
+            raw::BLE_HS_ENOTCONN => HostError::NotConnected,
+            raw::BLE_HS_ENOTSUP => HostError::NotSupported,
+            raw::BLE_HS_EAPP => HostError::App,
+            raw::BLE_HS_EBADDATA => HostError::BadData,
+            raw::BLE_HS_EOS => HostError::Os,
+            raw::BLE_HS_ECONTROLLER => HostError::Controller,
+            raw::BLE_HS_ETIMEOUT => HostError::Timeout,
+            raw::BLE_HS_EDONE => HostError::Done,
+            raw::BLE_HS_EBUSY => HostError::Busy,
+            raw::BLE_HS_EREJECT => HostError::Rejected,
+            raw::BLE_HS_EUNKNOWN => HostError::Unknown,
+            raw::BLE_HS_EROLE => HostError::Role,
+            raw::BLE_HS_ETIMEOUT_HCI => HostError::HciTimeout,
+            raw::BLE_HS_ENOMEM_EVT => HostError::NoMemEvent,
+            raw::BLE_HS_ENOADDR => HostError::NoAddress,
+            raw::BLE_HS_ENOTSYNCED => HostError::NotSynced,
+            raw::BLE_HS_EAUTHEN => HostError::InsufficientAuthentication,
+            raw::BLE_HS_EAUTHOR => HostError::InsufficientAuthorization,
+            raw::BLE_HS_EENCRYPT => HostError::InsufficientEncryption,
+            raw::BLE_HS_EENCRYPT_KEY_SZ => HostError::InsufficientKeySize,
+            raw::BLE_HS_ESTORE_CAP => HostError::StoreCapacity,
+            raw::BLE_HS_ESTORE_FAIL => HostError::StoreFailed,
+            raw::BLE_HS_EPREEMPTED => HostError::Preempted,
+            raw::BLE_HS_EDISABLED => HostError::Disabled,
+            raw::BLE_HS_ESTALLED => HostError::Stalled,
+            _ => {
+                let err = (code & 0xff) as u8;
+                match code & !0xff {
+                    raw::BLE_HS_ERR_ATT_BASE => HostError::Att(err),
+                    raw::BLE_HS_ERR_HCI_BASE => HostError::Hci(err),
+                    raw::BLE_HS_ERR_L2C_BASE => HostError::L2cap(err),
+                    raw::BLE_HS_ERR_SM_US_BASE => HostError::SmUs(err),
+                    raw::BLE_HS_ERR_SM_PEER_BASE => HostError::SmPeer(err),
+                    raw::BLE_HS_ERR_HW_BASE => HostError::Hw(err),
+                    _ => HostError::Other(value),
+                }
+            }
+        }
+    }
+}
+
+impl From<OsError> for HostError {
+    fn from(value: OsError) -> Self {
+        match value {
+            OsError::NoMem => HostError::NoMem,
+            OsError::Invalid | OsError::InvalidParameter => HostError::Invalid,
+            OsError::Timeout => HostError::Timeout,
+            OsError::NotStarted => HostError::Disabled,
+            OsError::NoEnt => HostError::NotFound,
+            OsError::Busy => HostError::Busy,
+            _ => HostError::Os,
+        }
+    }
+}
+
+impl core::fmt::Display for HostError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            HostError::Att(e) => write!(f, "ATT error {e:#04x}"),
+            HostError::Hci(e) => write!(f, "HCI error {e:#04x}"),
+            HostError::L2cap(e) => write!(f, "L2CAP error {e:#04x}"),
+            HostError::SmUs(e) => write!(f, "security manager error {e:#04x}"),
+            HostError::SmPeer(e) => write!(f, "security manager error {e:#04x} from peer"),
+            HostError::Hw(e) => write!(f, "hardware error {e:#04x}"),
+            HostError::Other(e) => write!(f, "host error {e}"),
+            e => write!(f, "{e:?}"),
+        }
+    }
+}
+
+impl embedded_io::Error for HostError {
+    fn kind(&self) -> embedded_io::ErrorKind {
+        use embedded_io::ErrorKind;
+
+        match self {
+            HostError::NoMem | HostError::NoMemEvent | HostError::StoreCapacity => {
+                ErrorKind::OutOfMemory
+            }
+            HostError::Invalid | HostError::MessageTooLong => ErrorKind::InvalidInput,
+            HostError::NotFound | HostError::NoAddress => ErrorKind::NotFound,
+            HostError::NotConnected | HostError::NotSynced | HostError::Disabled => {
+                ErrorKind::NotConnected
+            }
+            HostError::NotSupported | HostError::Role => ErrorKind::Unsupported,
+            HostError::BadData => ErrorKind::InvalidData,
+            HostError::Timeout | HostError::HciTimeout => ErrorKind::TimedOut,
+            HostError::Already => ErrorKind::AlreadyExists,
+            HostError::Rejected => ErrorKind::ConnectionRefused,
+            HostError::Preempted => ErrorKind::Interrupted,
+            HostError::InsufficientAuthentication
+            | HostError::InsufficientAuthorization
+            | HostError::InsufficientEncryption
+            | HostError::InsufficientKeySize => ErrorKind::PermissionDenied,
+            _ => ErrorKind::Other,
+        }
+    }
+}
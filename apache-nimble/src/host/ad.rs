@@ -0,0 +1,481 @@
+use core::ops::{BitOr, Deref};
+
+use super::HostError;
+
+/// Maximum length of legacy advertising and scan response data.
+pub const LEGACY_ADV_DATA_LEN: usize = 31;
+
+/// AD type values, from the Bluetooth Assigned Numbers.
+pub mod ad_type {
+    pub const FLAGS: u8 = 0x01;
+    pub const INCOMPLETE_UUIDS16: u8 = 0x02;
+    pub const COMPLETE_UUIDS16: u8 = 0x03;
+    pub const INCOMPLETE_UUIDS32: u8 = 0x04;
+    pub const COMPLETE_UUIDS32: u8 = 0x05;
+    pub const INCOMPLETE_UUIDS128: u8 = 0x06;
+    pub const COMPLETE_UUIDS128: u8 = 0x07;
+    pub const SHORTENED_NAME: u8 = 0x08;
+    pub const COMPLETE_NAME: u8 = 0x09;
+    pub const TX_POWER_LEVEL: u8 = 0x0a;
+    pub const SERVICE_DATA_UUID16: u8 = 0x16;
+    pub const APPEARANCE: u8 = 0x19;
+    pub const MANUFACTURER_DATA: u8 = 0xff;
+}
+
+/// Contents of the Flags AD structure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct AdvFlags(pub u8);
+
+impl AdvFlags {
+    pub const LE_LIMITED_DISCOVERABLE: Self = Self(0x01);
+    pub const LE_GENERAL_DISCOVERABLE: Self = Self(0x02);
+    pub const BR_EDR_NOT_SUPPORTED: Self = Self(0x04);
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for AdvFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Builds advertising or scan response data out of AD structures. `N` is the maximum length:
+/// [`LEGACY_ADV_DATA_LEN`] by default, which is all that fits in legacy advertising PDUs.
+///
+/// ```ignore
+/// let mut data: AdvData = AdvData::new();
+/// data.flags(AdvFlags::LE_GENERAL_DISCOVERABLE | AdvFlags::BR_EDR_NOT_SUPPORTED)?
+///     .complete_name("my-device")?
+///     .uuids16(&[0x180f], true)?;
+/// ```
+///
+/// Every method fails with [`HostError::MessageTooLong`] (leaving the data unchanged) if the AD
+/// structure doesn't fit.
+#[derive(Clone)]
+pub struct AdvData<const N: usize = LEGACY_ADV_DATA_LEN> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> AdvData<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+        }
+    }
+
+    /// Appends an AD structure made of `parts`, which are concatenated.
+    fn push_parts(&mut self, ad_type: u8, parts: &[&[u8]]) -> Result<&mut Self, HostError> {
+        let data_len: usize = parts.iter().map(|p| p.len()).sum();
+        // the length octet counts the type as well
+        if data_len + 1 > u8::MAX as usize || self.len + data_len + 2 > N {
+            return Err(HostError::MessageTooLong);
+        }
+        self.buf[self.len] = (data_len + 1) as u8;
+        self.buf[self.len + 1] = ad_type;
+        let mut pos = self.len + 2;
+        for part in parts {
+            self.buf[pos..pos + part.len()].copy_from_slice(part);
+            pos += part.len();
+        }
+        self.len = pos;
+        Ok(self)
+    }
+
+    /// Appends an AD structure of any type (see [`ad_type`]).
+    pub fn push(&mut self, ad_type: u8, data: &[u8]) -> Result<&mut Self, HostError> {
+        self.push_parts(ad_type, &[data])
+    }
+
+    pub fn flags(&mut self, flags: AdvFlags) -> Result<&mut Self, HostError> {
+        self.push(ad_type::FLAGS, &[flags.0])
+    }
+
+    /// Lists 16-bit service UUIDs. `complete` tells scanners whether these are all the services
+    /// the device has.
+    pub fn uuids16(&mut self, uuids: &[u16], complete: bool) -> Result<&mut Self, HostError> {
+        let ad_type = if complete {
+            ad_type::COMPLETE_UUIDS16
+        } else {
+            ad_type::INCOMPLETE_UUIDS16
+        };
+        self.push_list(ad_type, uuids.iter().map(|u| u.to_le_bytes()))
+    }
+
+    /// Lists 128-bit service UUIDs, written as numbers (e.g.
+    /// `0x6e400001_b5a3_f393_e0a9_e50e24dcca9e`).
+    pub fn uuids128(&mut self, uuids: &[u128], complete: bool) -> Result<&mut Self, HostError> {
+        let ad_type = if complete {
+            ad_type::COMPLETE_UUIDS128
+        } else {
+            ad_type::INCOMPLETE_UUIDS128
+        };
+        self.push_list(ad_type, uuids.iter().map(|u| u.to_le_bytes()))
+    }
+
+    fn push_list<const M: usize>(
+        &mut self,
+        ad_type: u8,
+        items: impl ExactSizeIterator<Item = [u8; M]>,
+    ) -> Result<&mut Self, HostError> {
+        let data_len = items.len() * M;
+        if data_len + 1 > u8::MAX as usize || self.len + data_len + 2 > N {
+            return Err(HostError::MessageTooLong);
+        }
+        self.buf[self.len] = (data_len + 1) as u8;
+        self.buf[self.len + 1] = ad_type;
+        for (i, item) in items.enumerate() {
+            let pos = self.len + 2 + i * M;
+            self.buf[pos..pos + M].copy_from_slice(&item);
+        }
+        self.len += data_len + 2;
+        Ok(self)
+    }
+
+    pub fn complete_name(&mut self, name: &str) -> Result<&mut Self, HostError> {
+        self.push(ad_type::COMPLETE_NAME, name.as_bytes())
+    }
+
+    /// Adds the start of the device name, for when the complete name doesn't fit.
+    pub fn shortened_name(&mut self, name: &str) -> Result<&mut Self, HostError> {
+        self.push(ad_type::SHORTENED_NAME, name.as_bytes())
+    }
+
+    /// Adds the transmit power, in dBm.
+    pub fn tx_power_level(&mut self, dbm: i8) -> Result<&mut Self, HostError> {
+        self.push(ad_type::TX_POWER_LEVEL, &[dbm as u8])
+    }
+
+    /// Adds the external appearance of the device (see the Bluetooth Assigned Numbers).
+    pub fn appearance(&mut self, appearance: u16) -> Result<&mut Self, HostError> {
+        self.push(ad_type::APPEARANCE, &appearance.to_le_bytes())
+    }
+
+    pub fn service_data16(&mut self, uuid: u16, data: &[u8]) -> Result<&mut Self, HostError> {
+        self.push_parts(ad_type::SERVICE_DATA_UUID16, &[&uuid.to_le_bytes(), data])
+    }
+
+    /// Adds manufacturer specific data, prefixed with the company identifier.
+    pub fn manufacturer_data(
+        &mut self,
+        company_id: u16,
+        data: &[u8],
+    ) -> Result<&mut Self, HostError> {
+        self.push_parts(
+            ad_type::MANUFACTURER_DATA,
+            &[&company_id.to_le_bytes(), data],
+        )
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+impl<const N: usize> Default for AdvData<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Deref for AdvData<N> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        self.as_bytes()
+    }
+}
+
+impl<const N: usize> core::fmt::Debug for AdvData<N> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_tuple("AdvData").field(&self.as_bytes()).finish()
+    }
+}
+
+impl<const N: usize> defmt::Format for AdvData<N> {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "AdvData({=[u8]})", self.as_bytes())
+    }
+}
+
+/// An AD structure, parsed from advertising or scan response data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum AdStructure<'a> {
+    Flags(AdvFlags),
+    /// 16-bit service UUIDs. `complete` tells whether these are all the services the device has.
+    Uuids16 {
+        uuids: Uuids16<'a>,
+        complete: bool,
+    },
+    Uuids32 {
+        uuids: Uuids32<'a>,
+        complete: bool,
+    },
+    Uuids128 {
+        uuids: Uuids128<'a>,
+        complete: bool,
+    },
+    /// The start of the device name. Like [`AdStructure::CompleteName`], it's usually, but not
+    /// necessarily, UTF-8.
+    ShortenedName(&'a [u8]),
+    CompleteName(&'a [u8]),
+    /// Transmit power, in dBm.
+    TxPowerLevel(i8),
+    ServiceData16 {
+        uuid: u16,
+        data: &'a [u8],
+    },
+    Appearance(u16),
+    ManufacturerData {
+        company_id: u16,
+        data: &'a [u8],
+    },
+    /// Any other AD structure, or a malformed one (e.g. an appearance that isn't 2 bytes long).
+    Other {
+        ad_type: u8,
+        data: &'a [u8],
+    },
+}
+
+impl<'a> AdStructure<'a> {
+    fn parse(ad_type: u8, data: &'a [u8]) -> Self {
+        match (ad_type, data) {
+            (ad_type::FLAGS, [flags, ..]) => AdStructure::Flags(AdvFlags(*flags)),
+            (ad_type::INCOMPLETE_UUIDS16 | ad_type::COMPLETE_UUIDS16, _) => AdStructure::Uuids16 {
+                uuids: Uuids16(data),
+                complete: ad_type == ad_type::COMPLETE_UUIDS16,
+            },
+            (ad_type::INCOMPLETE_UUIDS32 | ad_type::COMPLETE_UUIDS32, _) => AdStructure::Uuids32 {
+                uuids: Uuids32(data),
+                complete: ad_type == ad_type::COMPLETE_UUIDS32,
+            },
+            (ad_type::INCOMPLETE_UUIDS128 | ad_type::COMPLETE_UUIDS128, _) => {
+                AdStructure::Uuids128 {
+                    uuids: Uuids128(data),
+                    complete: ad_type == ad_type::COMPLETE_UUIDS128,
+                }
+            }
+            (ad_type::SHORTENED_NAME, _) => AdStructure::ShortenedName(data),
+            (ad_type::COMPLETE_NAME, _) => AdStructure::CompleteName(data),
+            (ad_type::TX_POWER_LEVEL, [dbm]) => AdStructure::TxPowerLevel(*dbm as i8),
+            (ad_type::SERVICE_DATA_UUID16, [a, b, data @ ..]) => AdStructure::ServiceData16 {
+                uuid: u16::from_le_bytes([*a, *b]),
+                data,
+            },
+            (ad_type::APPEARANCE, [a, b]) => AdStructure::Appearance(u16::from_le_bytes([*a, *b])),
+            (ad_type::MANUFACTURER_DATA, [a, b, data @ ..]) => AdStructure::ManufacturerData {
+                company_id: u16::from_le_bytes([*a, *b]),
+                data,
+            },
+            _ => AdStructure::Other { ad_type, data },
+        }
+    }
+}
+
+/// Iterates over the AD structures in advertising or scan response data. Iteration stops at the
+/// first malformed structure (one whose length goes past the end of the data).
+#[derive(Debug, Clone)]
+pub struct AdStructures<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> AdStructures<'a> {
+    pub const fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+}
+
+impl<'a> Iterator for AdStructures<'a> {
+    type Item = AdStructure<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (&len, rest) = self.data.split_first()?;
+        let len = len as usize;
+        // a zero length marks the end of the significant part of the data
+        if len == 0 || len > rest.len() {
+            self.data = &[];
+            return None;
+        }
+        let (structure, rest) = rest.split_at(len);
+        self.data = rest;
+        Some(AdStructure::parse(structure[0], &structure[1..]))
+    }
+}
+
+/// A list of 16-bit UUIDs, from an AD structure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Uuids16<'a>(&'a [u8]);
+
+impl Iterator for Uuids16<'_> {
+    type Item = u16;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (uuid, rest) = self.0.split_first_chunk()?;
+        self.0 = rest;
+        Some(u16::from_le_bytes(*uuid))
+    }
+}
+
+/// A list of 32-bit UUIDs, from an AD structure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Uuids32<'a>(&'a [u8]);
+
+impl Iterator for Uuids32<'_> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (uuid, rest) = self.0.split_first_chunk()?;
+        self.0 = rest;
+        Some(u32::from_le_bytes(*uuid))
+    }
+}
+
+/// A list of 128-bit UUIDs, from an AD structure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Uuids128<'a>(&'a [u8]);
+
+impl Iterator for Uuids128<'_> {
+    type Item = u128;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (uuid, rest) = self.0.split_first_chunk()?;
+        self.0 = rest;
+        Some(u128::from_le_bytes(*uuid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(data: &[u8]) -> Vec<AdStructure<'_>> {
+        AdStructures::new(data).collect()
+    }
+
+    #[test]
+    fn zero_length_ends_the_data() {
+        let data = [2, ad_type::FLAGS, 0x06, 0, 2, ad_type::TX_POWER_LEVEL, 0xfc];
+        assert_eq!(parse(&data), [AdStructure::Flags(AdvFlags(0x06))]);
+        assert!(parse(&[0; 31]).is_empty());
+    }
+
+    #[test]
+    fn length_past_the_end_stops_parsing() {
+        let data = [
+            2,
+            ad_type::TX_POWER_LEVEL,
+            0xfc,
+            4,
+            ad_type::COMPLETE_NAME,
+            b'a',
+            b'b',
+        ];
+        assert_eq!(parse(&data), [AdStructure::TxPowerLevel(-4)]);
+        // a length byte with nothing after it
+        assert!(parse(&[3]).is_empty());
+    }
+
+    #[test]
+    fn unknown_and_malformed_types_are_other() {
+        let data = [3, 0x2a, 1, 2, 2, ad_type::APPEARANCE, 0x40];
+        assert_eq!(
+            parse(&data),
+            [
+                AdStructure::Other {
+                    ad_type: 0x2a,
+                    data: &[1, 2],
+                },
+                AdStructure::Other {
+                    ad_type: ad_type::APPEARANCE,
+                    data: &[0x40],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn uuid_lists_ignore_a_trailing_partial_uuid() {
+        let uuids: Vec<u16> = Uuids16(&[0x0f, 0x18, 0x0a, 0x18, 0xff]).collect();
+        assert_eq!(uuids, [0x180f, 0x180a]);
+
+        let uuids: Vec<u32> = Uuids32(&[1, 2, 3, 4, 5, 6, 7]).collect();
+        assert_eq!(uuids, [0x0403_0201]);
+
+        let mut bytes = [0u8; 17];
+        bytes[0] = 0x9e;
+        bytes[15] = 0x6e;
+        let uuids: Vec<u128> = Uuids128(&bytes).collect();
+        assert_eq!(uuids, [0x6e00_0000_0000_0000_0000_0000_0000_009e]);
+        assert_eq!(Uuids128(&bytes[..15]).next(), None);
+    }
+
+    #[test]
+    fn built_data_parses_back() {
+        let mut data: AdvData = AdvData::new();
+        data.flags(AdvFlags::LE_GENERAL_DISCOVERABLE)
+            .unwrap()
+            .uuids16(&[0x180f], true)
+            .unwrap()
+            .manufacturer_data(0x0059, &[1])
+            .unwrap();
+        let parsed = parse(&data);
+        assert_eq!(parsed.len(), 3);
+        assert_eq!(
+            parsed[0],
+            AdStructure::Flags(AdvFlags::LE_GENERAL_DISCOVERABLE)
+        );
+        match parsed[1] {
+            AdStructure::Uuids16 { uuids, complete } => {
+                assert!(complete);
+                assert_eq!(uuids.collect::<Vec<_>>(), [0x180f]);
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+        assert_eq!(
+            parsed[2],
+            AdStructure::ManufacturerData {
+                company_id: 0x0059,
+                data: &[1],
+            }
+        );
+    }
+
+    #[test]
+    fn data_fills_up_to_exactly_31_bytes() {
+        let mut data: AdvData = AdvData::new();
+        // 2 header bytes + 29 bytes of name = 31
+        data.complete_name(&"a".repeat(29)).unwrap();
+        assert_eq!(data.len(), LEGACY_ADV_DATA_LEN);
+        // nothing else fits, not even an empty structure
+        assert_eq!(data.push(0x2a, &[]).unwrap_err(), HostError::MessageTooLong);
+        assert_eq!(data.len(), LEGACY_ADV_DATA_LEN);
+    }
+
+    #[test]
+    fn data_rejects_32_bytes_unchanged() {
+        let mut data: AdvData = AdvData::new();
+        data.flags(AdvFlags::LE_GENERAL_DISCOVERABLE).unwrap();
+        // 3 + 2 header bytes + 27 bytes of name = 32
+        assert_eq!(
+            data.complete_name(&"a".repeat(27)).unwrap_err(),
+            HostError::MessageTooLong
+        );
+        assert_eq!(
+            data.uuids128(&[1, 2], true).unwrap_err(),
+            HostError::MessageTooLong
+        );
+        assert_eq!(data.as_bytes(), [2, ad_type::FLAGS, 0x02]);
+        data.complete_name(&"a".repeat(26)).unwrap();
+        assert_eq!(data.len(), LEGACY_ADV_DATA_LEN);
+    }
+}
@@ -0,0 +1,314 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use defmt::trace;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::Duration;
+
+#[cfg(feature = "role-peripheral")]
+use super::connection::{self, ConnRef, Connection};
+use super::{
+    check, check_current, gap_event, own_addr_type, HostError, NimbleHost, OwnAddressKind,
+};
+use crate::{is_current, raw, Mbuf, Phy};
+
+/// Number of advertising sets (`BLE_MULTI_ADV_INSTANCES` + 1).
+pub const MAX_ADV_SETS: usize = raw::MYNEWT_VAL_BLE_MULTI_ADV_INSTANCES as usize + 1;
+
+/// Lets the controller pick the transmit power.
+const TX_POWER_NO_PREFERENCE: i8 = 127;
+
+/// Parameters of an advertising set.
+///
+/// The defaults advertise with legacy PDUs, so that every scanner can see the advertisements,
+/// using the fast advertising interval recommended for user-initiated advertising (30 to 60 ms).
+#[derive(Debug, Clone)]
+pub struct AdvParams {
+    /// Advertising set to use, below [`MAX_ADV_SETS`].
+    pub instance: u8,
+    #[cfg(feature = "role-peripheral")]
+    pub connectable: bool,
+    pub scannable: bool,
+    /// Use legacy advertising PDUs (limited to 31 bytes of data) instead of extended ones.
+    pub legacy_pdu: bool,
+    pub interval_min: Duration,
+    pub interval_max: Duration,
+    /// Bitmask of the primary advertising channels to use (37, 38 and 39). 0 uses all of them.
+    pub channel_map: u8,
+    /// `None` picks the best available address.
+    pub own_address: Option<OwnAddressKind>,
+    pub primary_phy: Phy,
+    /// PHY of the auxiliary packets of extended advertising.
+    pub secondary_phy: Phy,
+    /// Maximum transmit power, in dBm. `None` lets the controller decide.
+    pub tx_power: Option<i8>,
+    /// Advertising SID, which lets scanners tell the advertising sets of a device apart.
+    pub sid: u8,
+    /// Stop advertising after this long. `None` advertises until stopped.
+    pub duration: Option<Duration>,
+    /// Stop advertising after this many advertising events. 0 means no limit.
+    pub max_events: u8,
+}
+
+impl Default for AdvParams {
+    fn default() -> Self {
+        Self {
+            instance: 0,
+            #[cfg(feature = "role-peripheral")]
+            connectable: true,
+            scannable: true,
+            legacy_pdu: true,
+            interval_min: Duration::from_millis(30),
+            interval_max: Duration::from_millis(60),
+            channel_map: 0,
+            own_address: None,
+            primary_phy: Phy::Le1M,
+            secondary_phy: Phy::Le1M,
+            tx_power: None,
+            sid: 0,
+            duration: None,
+            max_events: 0,
+        }
+    }
+}
+
+/// How advertising ended.
+#[derive(Debug, defmt::Format)]
+pub enum AdvEnd {
+    /// A central connected.
+    #[cfg(feature = "role-peripheral")]
+    Connected(Connection),
+    /// The duration from [`AdvParams::duration`] elapsed.
+    TimedOut,
+    /// The number of events from [`AdvParams::max_events`] was reached.
+    MaxEvents,
+    /// The host stopped advertising, e.g. to start a connection.
+    Preempted,
+}
+
+enum AdvEvent {
+    #[cfg(feature = "role-peripheral")]
+    Connected(ConnRef),
+    /// Advertising stopped for the given reason (a NimBLE host error code).
+    Stopped(i32),
+}
+
+static EVENTS: [Signal<CriticalSectionRawMutex, AdvEvent>; MAX_ADV_SETS] =
+    [const { Signal::new() }; MAX_ADV_SETS];
+
+/// Bitmask of the advertising sets that have an [`Advertiser`].
+static IN_USE: AtomicU32 = AtomicU32::new(0);
+
+/// Handles `BLE_GAP_EVENT_ADV_COMPLETE`.
+#[cfg_attr(not(feature = "role-peripheral"), allow(unused_variables))]
+pub(crate) fn on_complete(instance: u8, reason: i32, conn_handle: u16) {
+    trace!(
+        "advertising complete: instance {} reason {}",
+        instance,
+        reason
+    );
+    let Some(events) = EVENTS.get(instance as usize) else {
+        return;
+    };
+
+    #[cfg(feature = "role-peripheral")]
+    if reason == 0 {
+        let event = match connection::on_connect(conn_handle) {
+            Some(conn) => AdvEvent::Connected(conn),
+            None => AdvEvent::Stopped(raw::BLE_HS_ENOMEM as i32),
+        };
+        events.signal(event);
+        return;
+    }
+
+    events.signal(AdvEvent::Stopped(reason));
+}
+
+/// Ends every advertiser, after the host was shut down.
+pub(crate) fn reset() {
+    let in_use = IN_USE.swap(0, Ordering::AcqRel);
+    for (instance, events) in EVENTS.iter().enumerate() {
+        if in_use & (1 << instance) != 0 {
+            events.signal(AdvEvent::Stopped(raw::BLE_HS_EDISABLED as i32));
+        }
+    }
+}
+
+/// Advertising interval, in units of 0.625 ms.
+fn adv_interval(interval: Duration) -> u32 {
+    (interval.as_micros() / 625) as u32
+}
+
+fn configure(instance: u8, params: &AdvParams) -> Result<i8, HostError> {
+    let mut p: raw::ble_gap_ext_adv_params = unsafe { core::mem::zeroed() };
+    #[cfg(feature = "role-peripheral")]
+    p.set_connectable(params.connectable as u32);
+    p.set_scannable(params.scannable as u32);
+    p.set_legacy_pdu(params.legacy_pdu as u32);
+    p.itvl_min = adv_interval(params.interval_min);
+    p.itvl_max = adv_interval(params.interval_max);
+    p.channel_map = params.channel_map;
+    p.own_addr_type = own_addr_type(params.own_address)?;
+    p.primary_phy = params.primary_phy.to_raw();
+    p.secondary_phy = params.secondary_phy.to_raw();
+    p.tx_power = params.tx_power.unwrap_or(TX_POWER_NO_PREFERENCE);
+    p.sid = params.sid;
+
+    let mut selected_tx_power = 0;
+    check(unsafe {
+        raw::ble_gap_ext_adv_configure(
+            instance,
+            &p,
+            &mut selected_tx_power,
+            Some(gap_event),
+            core::ptr::null_mut(),
+        )
+    })?;
+    Ok(selected_tx_power)
+}
+
+impl NimbleHost {
+    /// Configures an advertising set, and starts advertising `data`. `scan_response` is sent to
+    /// active scanners if the set is scannable. Either can be built with [`super::AdvData`].
+    ///
+    /// Only one [`Advertiser`] can exist per advertising set at a time.
+    pub async fn advertise(
+        &self,
+        params: &AdvParams,
+        data: &[u8],
+        scan_response: &[u8],
+    ) -> Result<Advertiser, HostError> {
+        self.wait_for_sync().await?;
+
+        let instance = params.instance;
+        if instance as usize >= MAX_ADV_SETS {
+            return Err(HostError::Invalid);
+        }
+        let bit = 1 << instance;
+        if IN_USE.fetch_or(bit, Ordering::AcqRel) & bit != 0 {
+            return Err(HostError::Busy);
+        }
+        EVENTS[instance as usize].reset();
+
+        // from here on, dropping the advertiser cleans up the advertising set
+        let mut advertiser = Advertiser {
+            instance,
+            generation: self.generation,
+            tx_power: 0,
+        };
+        advertiser.tx_power = configure(instance, params)?;
+        if !data.is_empty() {
+            advertiser.set_data(data)?;
+        }
+        if !scan_response.is_empty() {
+            advertiser.set_scan_response(scan_response)?;
+        }
+        advertiser.start(params.duration, params.max_events)?;
+        Ok(advertiser)
+    }
+}
+
+/// An advertising set. Advertising stops, and the set is removed, when this is dropped.
+pub struct Advertiser {
+    instance: u8,
+    generation: u32,
+    tx_power: i8,
+}
+
+impl Advertiser {
+    pub fn instance(&self) -> u8 {
+        self.instance
+    }
+
+    /// Transmit power selected by the controller, in dBm.
+    pub fn tx_power(&self) -> i8 {
+        self.tx_power
+    }
+
+    /// Replaces the advertising data. This can be done while advertising.
+    pub fn set_data(&mut self, data: &[u8]) -> Result<(), HostError> {
+        check_current(self.generation)?;
+        let om = Mbuf::from_slice(data)?;
+        // NimBLE takes ownership of the mbuf, even if this fails
+        check(unsafe { raw::ble_gap_ext_adv_set_data(self.instance, om.into_raw()) })
+    }
+
+    /// Replaces the scan response data. This can be done while advertising.
+    pub fn set_scan_response(&mut self, data: &[u8]) -> Result<(), HostError> {
+        check_current(self.generation)?;
+        let om = Mbuf::from_slice(data)?;
+        check(unsafe { raw::ble_gap_ext_adv_rsp_set_data(self.instance, om.into_raw()) })
+    }
+
+    /// Starts advertising again, after it ended or was stopped.
+    pub fn start(&mut self, duration: Option<Duration>, max_events: u8) -> Result<(), HostError> {
+        check_current(self.generation)?;
+        // in units of 10 ms, 0 means no limit
+        let duration = duration.map_or(0, |d| (d.as_millis() / 10).clamp(1, u16::MAX as u64));
+        EVENTS[self.instance as usize].reset();
+        check(unsafe {
+            raw::ble_gap_ext_adv_start(self.instance, duration as i32, max_events as i32)
+        })
+    }
+
+    /// Stops advertising. The set keeps its parameters and data, and can be started again.
+    pub fn stop(&mut self) -> Result<(), HostError> {
+        check_current(self.generation)?;
+        check(unsafe { raw::ble_gap_ext_adv_stop(self.instance) })
+    }
+
+    pub fn is_active(&self) -> bool {
+        is_current(self.generation) && unsafe { raw::ble_gap_ext_adv_active(self.instance) }
+    }
+
+    /// Waits until advertising ends on its own (it doesn't end when stopped with
+    /// [`Advertiser::stop`]).
+    pub async fn finished(&mut self) -> Result<AdvEnd, HostError> {
+        check_current(self.generation)?;
+        match EVENTS[self.instance as usize].wait().await {
+            #[cfg(feature = "role-peripheral")]
+            AdvEvent::Connected(conn) => {
+                Ok(AdvEnd::Connected(Connection::new(conn, self.generation)))
+            }
+            AdvEvent::Stopped(reason) => match reason as u32 {
+                raw::BLE_HS_ETIMEOUT => Ok(AdvEnd::TimedOut),
+                raw::BLE_HS_EDONE => Ok(AdvEnd::MaxEvents),
+                raw::BLE_HS_EPREEMPTED => Ok(AdvEnd::Preempted),
+                _ => Err(HostError::from(reason)),
+            },
+        }
+    }
+
+    /// Waits for a central to connect. Fails with [`HostError::Timeout`], [`HostError::Done`] or
+    /// [`HostError::Preempted`] if advertising ends without a connection.
+    #[cfg(feature = "role-peripheral")]
+    pub async fn accept(&mut self) -> Result<Connection, HostError> {
+        match self.finished().await? {
+            AdvEnd::Connected(conn) => Ok(conn),
+            AdvEnd::TimedOut => Err(HostError::Timeout),
+            AdvEnd::MaxEvents => Err(HostError::Done),
+            AdvEnd::Preempted => Err(HostError::Preempted),
+        }
+    }
+}
+
+impl Drop for Advertiser {
+    fn drop(&mut self) {
+        if !is_current(self.generation) {
+            return;
+        }
+        unsafe {
+            // these fail harmlessly if advertising already ended, or the set isn't configured
+            raw::ble_gap_ext_adv_stop(self.instance);
+            raw::ble_gap_ext_adv_remove(self.instance);
+        }
+
+        // nobody is going to pick up a connection that was just made
+        #[cfg(feature = "role-peripheral")]
+        if let Some(AdvEvent::Connected(conn)) = EVENTS[self.instance as usize].try_take() {
+            let _ = Connection::new(conn, self.generation).disconnect();
+        }
+
+        IN_USE.fetch_and(!(1 << self.instance), Ordering::AcqRel);
+    }
+}
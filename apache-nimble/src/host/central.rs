@@ -0,0 +1,199 @@
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::trace;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::Duration;
+
+use super::connection::{ConnParams, ConnRef, Connection, REMOTE_USER_TERMINATED};
+use super::{
+    check, gap_event, own_addr_type, scan_interval, wait_for_sync, Address, HostError, NimbleHost,
+    OwnAddressKind,
+};
+use crate::{is_current, raw};
+
+/// `BLE_GAP_LE_PHY_1M_MASK`
+const PHY_1M_MASK: u8 = 0x01;
+/// `BLE_GAP_LE_PHY_CODED_MASK`
+#[cfg(feature = "phy-coded")]
+const PHY_CODED_MASK: u8 = 0x04;
+
+/// Parameters of a connection attempt.
+#[derive(Debug, Clone)]
+pub struct ConnectParams {
+    /// `None` picks the best available address.
+    pub own_address: Option<OwnAddressKind>,
+    /// Give up after this long. `None` keeps trying until the connection attempt is dropped.
+    pub timeout: Option<Duration>,
+    pub scan_interval: Duration,
+    /// How long to listen in each scan interval. Must not be longer than `scan_interval`.
+    pub scan_window: Duration,
+    /// Parameters of the connection, once established.
+    pub conn: ConnParams,
+    /// Also try connecting on the LE Coded PHY.
+    #[cfg(feature = "phy-coded")]
+    pub coded: bool,
+}
+
+impl Default for ConnectParams {
+    fn default() -> Self {
+        Self {
+            own_address: None,
+            timeout: Some(Duration::from_secs(30)),
+            scan_interval: Duration::from_millis(60),
+            scan_window: Duration::from_millis(30),
+            conn: ConnParams::default(),
+            #[cfg(feature = "phy-coded")]
+            coded: false,
+        }
+    }
+}
+
+/// Result of the pending connection attempt: the connection, or a NimBLE host error code.
+static RESULT: Signal<CriticalSectionRawMutex, Result<ConnRef, i32>> = Signal::new();
+/// Whether a connection attempt is in progress. NimBLE only makes one at a time.
+static PENDING: AtomicBool = AtomicBool::new(false);
+
+fn is_central(handle: u16) -> bool {
+    let mut desc = MaybeUninit::<raw::ble_gap_conn_desc>::uninit();
+    let rc = unsafe { raw::ble_gap_conn_find(handle, desc.as_mut_ptr()) };
+    rc == 0 && unsafe { desc.assume_init() }.role as u32 == raw::BLE_GAP_ROLE_MASTER
+}
+
+/// Handles `BLE_GAP_EVENT_CONNECT`. `conn` is the connection that was registered if `status` is
+/// 0. Connections made while advertising are reported here as well, and ignored.
+pub(crate) fn on_connect(status: i32, conn: Option<ConnRef>) {
+    match conn {
+        Some(conn) if is_central(conn.handle()) => {
+            if PENDING.load(Ordering::Acquire) {
+                RESULT.signal(Ok(conn));
+            } else {
+                // the attempt was cancelled too late, and nobody wants the connection
+                trace!(
+                    "terminating connection {} made after cancelling",
+                    conn.handle()
+                );
+                unsafe { raw::ble_gap_terminate(conn.handle(), REMOTE_USER_TERMINATED) };
+            }
+        }
+        Some(_) => {}
+        None if status == 0 => {
+            if PENDING.load(Ordering::Acquire) {
+                RESULT.signal(Err(raw::BLE_HS_ENOMEM as i32));
+            }
+        }
+        None => {
+            if PENDING.load(Ordering::Acquire) {
+                RESULT.signal(Err(status));
+            }
+        }
+    }
+}
+
+/// Fails the pending connection attempt, after the host was shut down.
+pub(crate) fn reset() {
+    if PENDING.swap(false, Ordering::AcqRel) {
+        RESULT.signal(Err(raw::BLE_HS_EDISABLED as i32));
+    }
+}
+
+impl NimbleHost {
+    pub fn central(&self) -> Central {
+        Central {
+            generation: self.generation,
+        }
+    }
+}
+
+/// Initiates connections to peripherals.
+pub struct Central {
+    generation: u32,
+}
+
+impl Central {
+    /// Connects to the peripheral with the given address. The attempt is cancelled if the future
+    /// is dropped.
+    ///
+    /// Only one connection attempt can be in progress at a time.
+    pub async fn connect(
+        &self,
+        peer: &Address,
+        params: &ConnectParams,
+    ) -> Result<Connection, HostError> {
+        wait_for_sync(self.generation).await?;
+
+        if PENDING.swap(true, Ordering::AcqRel) {
+            return Err(HostError::Busy);
+        }
+        // from here on, dropping the guard cancels the attempt
+        let _guard = ConnectGuard {
+            generation: self.generation,
+        };
+        RESULT.reset();
+
+        let upd = params.conn.to_raw();
+        let conn_params = raw::ble_gap_conn_params {
+            scan_itvl: scan_interval(params.scan_interval),
+            scan_window: scan_interval(params.scan_window),
+            itvl_min: upd.itvl_min,
+            itvl_max: upd.itvl_max,
+            latency: upd.latency,
+            supervision_timeout: upd.supervision_timeout,
+            min_ce_len: upd.min_ce_len,
+            max_ce_len: upd.max_ce_len,
+        };
+        #[cfg(feature = "phy-coded")]
+        let (phy_mask, coded_ptr) = if params.coded {
+            (PHY_1M_MASK | PHY_CODED_MASK, &conn_params as *const _)
+        } else {
+            (PHY_1M_MASK, core::ptr::null())
+        };
+        #[cfg(not(feature = "phy-coded"))]
+        let (phy_mask, coded_ptr) = (PHY_1M_MASK, core::ptr::null());
+        // in milliseconds, `BLE_HS_FOREVER` means no limit
+        let timeout = params
+            .timeout
+            .map_or(i32::MAX, |t| t.as_millis().min(i32::MAX as u64 - 1) as i32);
+        let own_addr_type = own_addr_type(params.own_address)?;
+
+        check(unsafe {
+            raw::ble_gap_ext_connect(
+                own_addr_type,
+                &peer.to_raw(),
+                timeout,
+                phy_mask,
+                &conn_params,
+                core::ptr::null(),
+                coded_ptr,
+                Some(gap_event),
+                core::ptr::null_mut(),
+            )
+        })?;
+
+        match RESULT.wait().await {
+            Ok(conn) => Ok(Connection::new(conn, self.generation)),
+            Err(rc) => Err(HostError::from(rc)),
+        }
+    }
+}
+
+/// Cancels the connection attempt of [`Central::connect`] when dropped.
+struct ConnectGuard {
+    generation: u32,
+}
+
+impl Drop for ConnectGuard {
+    fn drop(&mut self) {
+        if !is_current(self.generation) {
+            return;
+        }
+        // fails harmlessly if the attempt already completed
+        unsafe { raw::ble_gap_conn_cancel() };
+        // nobody is going to pick up a connection that was just made
+        if let Some(Ok(conn)) = RESULT.try_take() {
+            let _ = Connection::new(conn, self.generation).disconnect();
+        }
+        PENDING.store(false, Ordering::Release);
+    }
+}
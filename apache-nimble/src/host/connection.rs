@@ -0,0 +1,443 @@
+use core::future::poll_fn;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicI32, AtomicU16, AtomicU32, Ordering};
+use core::task::Poll;
+
+use defmt::{trace, warn};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::waitqueue::AtomicWaker;
+use embassy_time::Duration;
+
+use super::gatt_client::Notification;
+use super::{check, check_current, Address, HostError};
+use crate::{is_current, raw};
+
+/// Maximum number of simultaneous connections (`BLE_MAX_CONNECTIONS`).
+pub const MAX_CONNECTIONS: usize = raw::MYNEWT_VAL_BLE_MAX_CONNECTIONS as usize;
+
+/// `BLE_HS_CONN_HANDLE_NONE`
+const NO_HANDLE: u16 = 0xffff;
+
+/// `BLE_ERR_REM_USER_CONN_TERM`
+pub(crate) const REMOTE_USER_TERMINATED: u8 = 0x13;
+
+/// Number of events that can be queued per connection while waiting for
+/// [`Connection::next_event`]. Events that arrive while the queue is full are dropped.
+pub const CONN_EVENT_QUEUE_LEN: usize = 4;
+
+/// Number of notifications and indications that can be queued per connection while waiting for
+/// [`super::GattClient::notification`]. Notifications that arrive while the queue is full are
+/// dropped.
+pub const NOTIFICATION_QUEUE_LEN: usize = 4;
+
+/// State of a connection, updated from the GAP event callback.
+struct ConnSlot {
+    handle: AtomicU16,
+    /// Incremented whenever the slot is released, so that a [`Connection`] can tell that its
+    /// connection is gone even if the handle has been reused since.
+    epoch: AtomicU32,
+    /// Reason of the last disconnection (a NimBLE host error code).
+    reason: AtomicI32,
+    disconnected: AtomicWaker,
+    /// Events, tagged with the epoch of the connection they belong to.
+    events: Channel<CriticalSectionRawMutex, (u32, ConnEvent), CONN_EVENT_QUEUE_LEN>,
+    /// Notifications from the peer, tagged like `events`. `None` marks the disconnection.
+    notifications:
+        Channel<CriticalSectionRawMutex, (u32, Option<Notification>), NOTIFICATION_QUEUE_LEN>,
+}
+
+impl ConnSlot {
+    const fn new() -> Self {
+        Self {
+            handle: AtomicU16::new(NO_HANDLE),
+            epoch: AtomicU32::new(0),
+            reason: AtomicI32::new(0),
+            disconnected: AtomicWaker::new(),
+            events: Channel::new(),
+            notifications: Channel::new(),
+        }
+    }
+}
+
+static SLOTS: [ConnSlot; MAX_CONNECTIONS] = [const { ConnSlot::new() }; MAX_CONNECTIONS];
+
+pub(crate) fn find_slot(handle: u16) -> Option<usize> {
+    SLOTS
+        .iter()
+        .position(|s| s.handle.load(Ordering::Acquire) == handle)
+}
+
+/// Identifies a tracked connection, until it's released.
+#[derive(Clone, Copy)]
+pub(crate) struct ConnRef {
+    handle: u16,
+    slot: usize,
+    epoch: u32,
+}
+
+impl ConnRef {
+    pub(crate) fn handle(&self) -> u16 {
+        self.handle
+    }
+
+    pub(crate) fn slot(&self) -> usize {
+        self.slot
+    }
+}
+
+/// Starts tracking a new connection, if it isn't tracked already. Returns `None` if every slot is
+/// in use (which can't happen, since NimBLE doesn't accept more than `BLE_MAX_CONNECTIONS`).
+pub(crate) fn on_connect(handle: u16) -> Option<ConnRef> {
+    let slot = match find_slot(handle) {
+        Some(slot) => slot,
+        None => {
+            let slot = SLOTS.iter().position(|s| {
+                s.handle
+                    .compare_exchange(NO_HANDLE, handle, Ordering::AcqRel, Ordering::Acquire)
+                    .is_ok()
+            });
+            let Some(slot) = slot else {
+                warn!("no slot for connection {}", handle);
+                return None;
+            };
+            trace!("connected: handle {}", handle);
+            // drop what the previous connection that used the slot left behind, so that it doesn't
+            // take up room in the queue
+            while SLOTS[slot].events.try_receive().is_ok() {}
+            while SLOTS[slot].notifications.try_receive().is_ok() {}
+            slot
+        }
+    };
+    Some(ConnRef {
+        handle,
+        slot,
+        epoch: SLOTS[slot].epoch.load(Ordering::Acquire),
+    })
+}
+
+/// Releases the slot of a connection, and wakes up anyone waiting for the disconnection.
+pub(crate) fn on_disconnect(handle: u16, reason: i32) {
+    trace!(
+        "disconnected: handle {} reason {}",
+        handle,
+        HostError::from(reason)
+    );
+    if let Some(slot) = find_slot(handle) {
+        release(&SLOTS[slot], reason);
+    }
+}
+
+/// Queues an event for the connection with the given handle.
+pub(crate) fn on_event(handle: u16, event: ConnEvent) {
+    let Some(slot) = find_slot(handle) else {
+        return;
+    };
+    let slot = &SLOTS[slot];
+    let epoch = slot.epoch.load(Ordering::Acquire);
+    if slot.events.try_send((epoch, event)).is_err() {
+        trace!(
+            "event queue of connection {} full, dropping {}",
+            handle,
+            event
+        );
+    }
+}
+
+/// Queues a notification or indication received on the connection with the given handle.
+pub(crate) fn on_notification(handle: u16, notification: Notification) {
+    let Some(slot) = find_slot(handle) else {
+        return;
+    };
+    let slot = &SLOTS[slot];
+    let epoch = slot.epoch.load(Ordering::Acquire);
+    let attr_handle = notification.handle;
+    if slot
+        .notifications
+        .try_send((epoch, Some(notification)))
+        .is_err()
+    {
+        trace!(
+            "notification queue of connection {} full, dropping notification of {}",
+            handle,
+            attr_handle
+        );
+    }
+}
+
+fn release(slot: &ConnSlot, reason: i32) {
+    slot.reason.store(reason, Ordering::Release);
+    let epoch = slot.epoch.fetch_add(1, Ordering::AcqRel);
+    slot.handle.store(NO_HANDLE, Ordering::Release);
+    slot.disconnected.wake();
+    // wakes up `Connection::next_event`. If the queue is full, it will notice the disconnection
+    // once it has gone through the queued events (the queue is only drained by the next
+    // connection that uses the slot).
+    let _ = slot
+        .events
+        .try_send((epoch, ConnEvent::Disconnected(HostError::from(reason))));
+    let _ = slot.notifications.try_send((epoch, None));
+}
+
+/// Marks every connection as gone, after the host was shut down.
+pub(crate) fn reset() {
+    for slot in SLOTS.iter() {
+        if slot.handle.load(Ordering::Acquire) != NO_HANDLE {
+            release(slot, raw::BLE_HS_EDISABLED as i32);
+        }
+    }
+}
+
+/// Role of the local device in a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Role {
+    Central,
+    Peripheral,
+}
+
+/// Event of a connection, from [`Connection::next_event`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ConnEvent {
+    /// The connection was terminated, for the given reason (see [`Connection::disconnected`]).
+    /// This is the last event.
+    Disconnected(HostError),
+    /// A connection parameter update completed.
+    ParamsUpdated(Result<(), HostError>),
+    /// Encryption was enabled (or refreshed), or failed to be.
+    EncryptionChanged(Result<(), HostError>),
+    /// The ATT MTU was exchanged.
+    MtuChanged(u16),
+}
+
+/// Connection parameters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnParams {
+    pub interval_min: Duration,
+    pub interval_max: Duration,
+    /// Number of connection events the peripheral can skip.
+    pub latency: u16,
+    pub supervision_timeout: Duration,
+}
+
+impl Default for ConnParams {
+    fn default() -> Self {
+        Self {
+            interval_min: Duration::from_millis(30),
+            interval_max: Duration::from_millis(50),
+            latency: 0,
+            supervision_timeout: Duration::from_secs(4),
+        }
+    }
+}
+
+impl ConnParams {
+    pub(crate) fn to_raw(&self) -> raw::ble_gap_upd_params {
+        // the intervals are in units of 1.25 ms, and the timeout in units of 10 ms
+        let interval = |d: Duration| (d.as_micros() / 1250).min(u16::MAX as u64) as u16;
+        raw::ble_gap_upd_params {
+            itvl_min: interval(self.interval_min),
+            itvl_max: interval(self.interval_max),
+            latency: self.latency,
+            supervision_timeout: (self.supervision_timeout.as_millis() / 10).min(u16::MAX as u64)
+                as u16,
+            min_ce_len: 0,
+            max_ce_len: 0,
+        }
+    }
+}
+
+/// Parameters and security state of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct ConnInfo {
+    pub role: Role,
+    /// Identity address of the peer (the address it connected with, if it's not resolved).
+    pub peer_address: Address,
+    /// Address the peer connected from.
+    pub peer_ota_address: Address,
+    /// Connection interval, in units of 1.25 ms.
+    pub interval: u16,
+    /// Number of connection events the peripheral can skip.
+    pub latency: u16,
+    /// Supervision timeout, in units of 10 ms.
+    pub supervision_timeout: u16,
+    pub encrypted: bool,
+    pub authenticated: bool,
+    pub bonded: bool,
+    pub key_size: u8,
+}
+
+/// A connection to a peer. Dropping this doesn't disconnect; use [`Connection::disconnect`].
+pub struct Connection {
+    handle: u16,
+    slot: usize,
+    epoch: u32,
+    generation: u32,
+}
+
+impl Connection {
+    /// Wraps a connection that was registered by the GAP event callback. It might already be
+    /// gone, in which case the methods fail with [`HostError::NotConnected`].
+    pub(crate) fn new(conn: ConnRef, generation: u32) -> Self {
+        Self {
+            handle: conn.handle,
+            slot: conn.slot,
+            epoch: conn.epoch,
+            generation,
+        }
+    }
+
+    pub fn handle(&self) -> u16 {
+        self.handle
+    }
+
+    pub(crate) fn slot(&self) -> usize {
+        self.slot
+    }
+
+    pub(crate) fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    pub fn is_connected(&self) -> bool {
+        is_current(self.generation) && SLOTS[self.slot].epoch.load(Ordering::Acquire) == self.epoch
+    }
+
+    pub(crate) fn check_connected(&self) -> Result<(), HostError> {
+        check_current(self.generation)?;
+        if self.is_connected() {
+            Ok(())
+        } else {
+            Err(HostError::NotConnected)
+        }
+    }
+
+    /// Reads the connection's current parameters and security state.
+    pub fn info(&self) -> Result<ConnInfo, HostError> {
+        self.check_connected()?;
+        let mut desc = MaybeUninit::<raw::ble_gap_conn_desc>::uninit();
+        check(unsafe { raw::ble_gap_conn_find(self.handle, desc.as_mut_ptr()) })?;
+        let desc = unsafe { desc.assume_init() };
+
+        Ok(ConnInfo {
+            role: if desc.role as u32 == raw::BLE_GAP_ROLE_MASTER {
+                Role::Central
+            } else {
+                Role::Peripheral
+            },
+            peer_address: Address::from_raw(&desc.peer_id_addr),
+            peer_ota_address: Address::from_raw(&desc.peer_ota_addr),
+            interval: desc.conn_itvl,
+            latency: desc.conn_latency,
+            supervision_timeout: desc.supervision_timeout,
+            encrypted: desc.sec_state.encrypted() != 0,
+            authenticated: desc.sec_state.authenticated() != 0,
+            bonded: desc.sec_state.bonded() != 0,
+            key_size: desc.sec_state.key_size() as u8,
+        })
+    }
+
+    /// Reads the signal strength of the connection, in dBm.
+    pub fn rssi(&self) -> Result<i8, HostError> {
+        self.check_connected()?;
+        let mut rssi = 0;
+        check(unsafe { raw::ble_gap_conn_rssi(self.handle, &mut rssi) })?;
+        Ok(rssi)
+    }
+
+    /// Starts a connection parameter update. Its result is reported as
+    /// [`ConnEvent::ParamsUpdated`].
+    pub fn update_params(&self, params: &ConnParams) -> Result<(), HostError> {
+        self.check_connected()?;
+        check(unsafe { raw::ble_gap_update_params(self.handle, &params.to_raw()) })
+    }
+
+    /// Waits for the next event of the connection. After the connection is terminated, this keeps
+    /// returning [`ConnEvent::Disconnected`].
+    ///
+    /// Only one task can wait for events at a time.
+    pub async fn next_event(&self) -> ConnEvent {
+        let slot = &SLOTS[self.slot];
+        let disconnected = || {
+            let reason = slot.reason.load(Ordering::Acquire);
+            ConnEvent::Disconnected(HostError::from(reason))
+        };
+        poll_fn(|cx| loop {
+            let epoch = slot.epoch.load(Ordering::Acquire);
+            let connected = epoch == self.epoch;
+            // Events queued before the disconnection are still delivered, until another connection
+            // takes the slot (and drops them).
+            if !connected
+                && (epoch != self.epoch.wrapping_add(1)
+                    || slot.handle.load(Ordering::Acquire) != NO_HANDLE)
+            {
+                return Poll::Ready(disconnected());
+            }
+            match slot.events.poll_receive(cx) {
+                Poll::Ready((epoch, event)) if epoch == self.epoch => return Poll::Ready(event),
+                // left over from a previous connection that used the same slot
+                Poll::Ready(_) => continue,
+                Poll::Pending if connected => return Poll::Pending,
+                Poll::Pending => return Poll::Ready(disconnected()),
+            }
+        })
+        .await
+    }
+
+    /// Waits for the next notification or indication. Fails with [`HostError::NotConnected`] once
+    /// the connection is gone.
+    pub(crate) async fn next_notification(&self) -> Result<Notification, HostError> {
+        check_current(self.generation)?;
+        let slot = &SLOTS[self.slot];
+        poll_fn(|cx| loop {
+            if slot.epoch.load(Ordering::Acquire) != self.epoch {
+                return Poll::Ready(Err(HostError::NotConnected));
+            }
+            match slot.notifications.poll_receive(cx) {
+                Poll::Ready((epoch, Some(notification))) if epoch == self.epoch => {
+                    return Poll::Ready(Ok(notification))
+                }
+                // the disconnection marker, or left over from a previous connection
+                Poll::Ready(_) => continue,
+                Poll::Pending => return Poll::Pending,
+            }
+        })
+        .await
+    }
+
+    /// Starts terminating the connection. [`Connection::disconnected`] completes once it's done.
+    pub fn disconnect(&self) -> Result<(), HostError> {
+        self.check_connected()?;
+        check(unsafe { raw::ble_gap_terminate(self.handle, REMOTE_USER_TERMINATED) })
+    }
+
+    /// Waits until the connection is terminated, and returns the reason (usually
+    /// [`HostError::Hci`] with the HCI error code sent by the peer or the controller).
+    ///
+    /// Only one task can wait for the disconnection at a time.
+    pub async fn disconnected(&self) -> HostError {
+        let slot = &SLOTS[self.slot];
+        poll_fn(|cx| {
+            slot.disconnected.register(cx.waker());
+            if slot.epoch.load(Ordering::Acquire) != self.epoch {
+                Poll::Ready(HostError::from(slot.reason.load(Ordering::Acquire)))
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
+
+impl core::fmt::Debug for Connection {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Connection")
+            .field("handle", &self.handle)
+            .finish()
+    }
+}
+
+impl defmt::Format for Connection {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "Connection {{ handle: {} }}", self.handle)
+    }
+}
@@ -0,0 +1,842 @@
+use core::cell::RefCell;
+use core::mem::ManuallyDrop;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU16, AtomicU32, Ordering};
+
+use defmt::trace;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+
+#[cfg(any(feature = "role-central", feature = "role-peripheral"))]
+use super::connection::{Connection, MAX_CONNECTIONS};
+use super::{check, check_current, HostError, NimbleHost};
+use crate::{raw, Mbuf, OsError};
+
+pub use apache_nimble_macros::GattService;
+
+/// A GATT service, declared with `#[derive(GattService)]`:
+///
+/// ```ignore
+/// #[derive(GattService)]
+/// #[service(uuid = 0x180f)]
+/// struct BatteryService {
+///     #[characteristic(uuid = 0x2a19, read, notify)]
+///     #[descriptor(uuid = 0x2901, value = "Battery level")]
+///     level: Characteristic<u8>,
+/// }
+///
+/// static BATTERY: BatteryService = BatteryService {
+///     level: Characteristic::new(100),
+/// };
+///
+/// nimble.host.register_service(&BATTERY)?;
+/// ```
+///
+/// `uuid` is a 16-bit or 32-bit UUID, or a 128-bit UUID written as a number (e.g.
+/// `0x6e400001_b5a3_f393_e0a9_e50e24dcca9e`) or a string (e.g.
+/// `"6e400001-b5a3-f393-e0a9-e50e24dcca9e"`).
+///
+/// Characteristics accept the `read`, `write`, `write_without_response`, `notify` and `indicate`
+/// properties, the `read_encrypted`, `read_authenticated`, `read_authorized`, `write_encrypted`,
+/// `write_authenticated` and `write_authorized` permissions, and `min_key_size = N`. Descriptors
+/// have a constant `value` (a string or byte string), and accept the `read_encrypted` and
+/// `read_authenticated` permissions and their own `min_key_size = N`. Fields without a
+/// `#[characteristic]` attribute are ignored.
+///
+/// # Safety
+///
+/// [`GattService::definitions`] must return a service table that stays valid forever, and
+/// [`GattService::registration`] the same [`__private::Registration`] on every call. The derive
+/// macro takes care of this; there should be no reason to implement this trait by hand.
+pub unsafe trait GattService: Sync + 'static {
+    /// NimBLE's service table, terminated by an empty entry. The table is stored in statics and
+    /// filled in on the first call, so there can only be one registered instance of each service
+    /// type: for the others, this returns `None`.
+    fn definitions(&'static self) -> Option<*const raw::ble_gatt_svc_def>;
+
+    /// Keeps track of the service table and of the registrations.
+    #[doc(hidden)]
+    fn registration(&'static self) -> &'static __private::Registration;
+}
+
+impl NimbleHost {
+    /// Adds a service to the GATT server. Services have to be registered after
+    /// [`crate::Nimble::init`] and before the host task starts running (the GATT server is started
+    /// when the host starts), and registered again after a [`crate::Nimble::shutdown`].
+    ///
+    /// Fails with [`HostError::Already`] if the service was registered already, or if another
+    /// instance of the same service type was.
+    pub fn register_service<S: GattService>(&self, service: &'static S) -> Result<(), HostError> {
+        check_current(self.generation)?;
+        let registration = service.registration();
+        if registration.is_registered(self.generation) {
+            return Err(HostError::Already);
+        }
+        let defs = service.definitions().ok_or(HostError::Already)?;
+        unsafe {
+            check(raw::ble_gatts_count_cfg(defs))?;
+            check(raw::ble_gatts_add_svcs(defs))?;
+        }
+        registration.set_registered(self.generation);
+        Ok(())
+    }
+}
+
+/// A value that can be stored in a [`Characteristic`].
+///
+/// Integers are encoded in little endian, as is usual in GATT.
+pub trait GattValue: Sized {
+    /// Appends the value to a read response.
+    fn write_to(&self, om: &mut Mbuf) -> Result<(), OsError>;
+
+    /// Parses a value written by a peer. Returns `None` if it's invalid (e.g. it has the wrong
+    /// length), in which case the write is rejected.
+    fn read_from(om: &Mbuf) -> Option<Self>;
+}
+
+macro_rules! impl_gatt_value_int {
+    ($($t:ty),*) => {
+        $(
+            impl GattValue for $t {
+                fn write_to(&self, om: &mut Mbuf) -> Result<(), OsError> {
+                    om.append(&self.to_le_bytes())
+                }
+
+                fn read_from(om: &Mbuf) -> Option<Self> {
+                    let mut bytes = [0; core::mem::size_of::<$t>()];
+                    if om.len() != bytes.len() {
+                        return None;
+                    }
+                    om.copy_to(0, &mut bytes).ok()?;
+                    Some(<$t>::from_le_bytes(bytes))
+                }
+            }
+        )*
+    };
+}
+
+impl_gatt_value_int!(u8, u16, u32, u64, i8, i16, i32, i64, f32);
+
+impl GattValue for bool {
+    fn write_to(&self, om: &mut Mbuf) -> Result<(), OsError> {
+        om.append(&[*self as u8])
+    }
+
+    fn read_from(om: &Mbuf) -> Option<Self> {
+        u8::read_from(om).map(|b| b != 0)
+    }
+}
+
+impl<const N: usize> GattValue for [u8; N] {
+    fn write_to(&self, om: &mut Mbuf) -> Result<(), OsError> {
+        om.append(self)
+    }
+
+    fn read_from(om: &Mbuf) -> Option<Self> {
+        let mut bytes = [0; N];
+        if om.len() != N {
+            return None;
+        }
+        om.copy_to(0, &mut bytes).ok()?;
+        Some(bytes)
+    }
+}
+
+/// A variable length value, of up to `N` bytes.
+#[derive(Clone, PartialEq, Eq)]
+pub struct GattBytes<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> GattBytes<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+        }
+    }
+
+    /// Fails with [`HostError::MessageTooLong`] if `data` is longer than `N`.
+    pub fn from_slice(data: &[u8]) -> Result<Self, HostError> {
+        let mut bytes = Self::new();
+        bytes
+            .buf
+            .get_mut(..data.len())
+            .ok_or(HostError::MessageTooLong)?
+            .copy_from_slice(data);
+        bytes.len = data.len();
+        Ok(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl<const N: usize> Default for GattBytes<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> core::ops::Deref for GattBytes<N> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        self.as_bytes()
+    }
+}
+
+impl<const N: usize> core::fmt::Debug for GattBytes<N> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_tuple("GattBytes").field(&self.as_bytes()).finish()
+    }
+}
+
+impl<const N: usize> defmt::Format for GattBytes<N> {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "GattBytes({=[u8]})", self.as_bytes())
+    }
+}
+
+impl<const N: usize> GattValue for GattBytes<N> {
+    fn write_to(&self, om: &mut Mbuf) -> Result<(), OsError> {
+        om.append(self.as_bytes())
+    }
+
+    fn read_from(om: &Mbuf) -> Option<Self> {
+        let mut bytes = Self::new();
+        let len = om.len();
+        om.copy_to(0, bytes.buf.get_mut(..len)?).ok()?;
+        bytes.len = len;
+        Some(bytes)
+    }
+}
+
+// the subscriptions are tracked as bitmasks of connection slots
+const _: () = assert!(raw::MYNEWT_VAL_BLE_MAX_CONNECTIONS <= 32);
+
+/// State of a registered characteristic that doesn't depend on its value type. Characteristics
+/// that can be notified or indicated are linked into `REGISTERED`, so that CCCD writes can be
+/// looked up by handle.
+struct Registration {
+    /// Value handle, filled in by NimBLE when the GATT server starts.
+    handle: AtomicU16,
+    /// Connection slots that enabled notifications.
+    notify: AtomicU32,
+    /// Connection slots that enabled indications.
+    indicate: AtomicU32,
+    next: AtomicPtr<Registration>,
+    linked: AtomicBool,
+}
+
+impl Registration {
+    const fn new() -> Self {
+        Self {
+            handle: AtomicU16::new(0),
+            notify: AtomicU32::new(0),
+            indicate: AtomicU32::new(0),
+            next: AtomicPtr::new(null_mut()),
+            linked: AtomicBool::new(false),
+        }
+    }
+
+    /// Adds the characteristic to `REGISTERED`, unless it's already there (after being registered
+    /// before a [`crate::Nimble::shutdown`]).
+    fn link(&'static self) {
+        if self.linked.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let this = self as *const _ as *mut Registration;
+        let mut head = REGISTERED.load(Ordering::Acquire);
+        loop {
+            self.next.store(head, Ordering::Release);
+            match REGISTERED.compare_exchange(head, this, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return,
+                Err(current) => head = current,
+            }
+        }
+    }
+}
+
+/// Characteristics that can be notified or indicated.
+static REGISTERED: AtomicPtr<Registration> = AtomicPtr::new(null_mut());
+
+fn registrations() -> impl Iterator<Item = &'static Registration> {
+    let mut next = REGISTERED.load(Ordering::Acquire);
+    core::iter::from_fn(move || {
+        // Safety: only `'static` registrations are linked, and they're never unlinked
+        let reg = unsafe { next.as_ref() }?;
+        next = reg.next.load(Ordering::Acquire);
+        Some(reg)
+    })
+}
+
+/// Handles `BLE_GAP_EVENT_SUBSCRIBE`, for the connection in slot `slot`.
+#[cfg(any(feature = "role-central", feature = "role-peripheral"))]
+pub(crate) fn on_subscribe(slot: usize, attr_handle: u16, notify: bool, indicate: bool) {
+    trace!(
+        "subscription of handle {}: notify {} indicate {}",
+        attr_handle,
+        notify,
+        indicate
+    );
+    let bit = 1 << slot;
+    for reg in registrations().filter(|r| r.handle.load(Ordering::Acquire) == attr_handle) {
+        let update = |flags: &AtomicU32, enabled: bool| {
+            if enabled {
+                flags.fetch_or(bit, Ordering::AcqRel);
+            } else {
+                flags.fetch_and(!bit, Ordering::AcqRel);
+            }
+        };
+        update(&reg.notify, notify);
+        update(&reg.indicate, indicate);
+    }
+}
+
+/// Forgets the subscriptions of a connection slot, when a new connection starts using it.
+#[cfg(any(feature = "role-central", feature = "role-peripheral"))]
+pub(crate) fn clear_subscriptions(slot: usize) {
+    for reg in registrations() {
+        reg.notify.fetch_and(!(1 << slot), Ordering::AcqRel);
+        reg.indicate.fetch_and(!(1 << slot), Ordering::AcqRel);
+    }
+}
+
+/// Forgets every subscription, after the host was shut down.
+pub(crate) fn reset() {
+    for reg in registrations() {
+        reg.notify.store(0, Ordering::Release);
+        reg.indicate.store(0, Ordering::Release);
+    }
+    #[cfg(any(feature = "role-central", feature = "role-peripheral"))]
+    for indication in INDICATIONS.iter() {
+        indication.result.signal((0, raw::BLE_HS_EDISABLED as i32));
+    }
+}
+
+/// Indication in progress on a connection slot. NimBLE only sends one at a time per connection.
+#[cfg(any(feature = "role-central", feature = "role-peripheral"))]
+struct Indication {
+    lock: embassy_sync::mutex::Mutex<CriticalSectionRawMutex, ()>,
+    /// Attribute handle and status from `BLE_GAP_EVENT_NOTIFY_TX`.
+    result: Signal<CriticalSectionRawMutex, (u16, i32)>,
+}
+
+#[cfg(any(feature = "role-central", feature = "role-peripheral"))]
+static INDICATIONS: [Indication; MAX_CONNECTIONS] = [const {
+    Indication {
+        lock: embassy_sync::mutex::Mutex::new(()),
+        result: Signal::new(),
+    }
+}; MAX_CONNECTIONS];
+
+/// Handles `BLE_GAP_EVENT_NOTIFY_TX` for an indication, for the connection in slot `slot`.
+/// Notifications don't need to be tracked.
+#[cfg(any(feature = "role-central", feature = "role-peripheral"))]
+pub(crate) fn on_indicate_tx(slot: usize, attr_handle: u16, status: i32) {
+    // 0 means the indication was sent, and is followed by another event once it's confirmed (or
+    // timed out)
+    if status != 0 {
+        INDICATIONS[slot].result.signal((attr_handle, status));
+    }
+}
+
+/// Whether a peer enabled notifications or indications of a characteristic.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Subscription {
+    pub notify: bool,
+    pub indicate: bool,
+}
+
+/// The value of a characteristic declared in a [`GattService`]. Reads and writes from peers are
+/// handled by NimBLE's access callback, which reads or replaces the stored value.
+pub struct Characteristic<T> {
+    value: Mutex<CriticalSectionRawMutex, RefCell<T>>,
+    registration: Registration,
+    /// Handle of the last connection that wrote the value.
+    written: Signal<CriticalSectionRawMutex, u16>,
+}
+
+impl<T: GattValue + Clone> Characteristic<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            value: Mutex::new(RefCell::new(value)),
+            registration: Registration::new(),
+            written: Signal::new(),
+        }
+    }
+
+    /// Handle of the characteristic value, or 0 if the GATT server hasn't started yet.
+    pub fn handle(&self) -> u16 {
+        self.registration.handle.load(Ordering::Acquire)
+    }
+
+    pub fn get(&self) -> T {
+        self.value.lock(|v| v.borrow().clone())
+    }
+
+    /// Replaces the value returned to peers.
+    pub fn set(&self, value: T) {
+        self.value.lock(|v| *v.borrow_mut() = value);
+    }
+
+    /// Waits until a peer writes the value, and returns the handle of its connection. Only one
+    /// task can wait at a time.
+    pub async fn written(&self) -> u16 {
+        self.written.wait().await
+    }
+
+    /// Allocates an mbuf with the current value, with room for the ATT header.
+    fn value_mbuf(&self) -> Result<Mbuf, HostError> {
+        let mut om = unsafe { Mbuf::from_raw(raw::ble_hs_mbuf_att_pkt()) }.ok_or_else(|| {
+            crate::stats::record_alloc_failure(crate::stats::Pool::Msys);
+            HostError::NoMem
+        })?;
+        self.get().write_to(&mut om)?;
+        Ok(om)
+    }
+}
+
+#[cfg(any(feature = "role-central", feature = "role-peripheral"))]
+impl<T: GattValue + Clone> Characteristic<T> {
+    /// Whether the peer of `conn` enabled notifications or indications, by writing the CCCD of the
+    /// characteristic.
+    pub fn subscription(&self, conn: &Connection) -> Subscription {
+        if !conn.is_connected() {
+            return Subscription::default();
+        }
+        let bit = 1 << conn.slot();
+        Subscription {
+            notify: self.registration.notify.load(Ordering::Acquire) & bit != 0,
+            indicate: self.registration.indicate.load(Ordering::Acquire) & bit != 0,
+        }
+    }
+
+    /// Sends the current value to the peer of `conn` in a notification. Fails with
+    /// [`HostError::NotSubscribed`] if the peer didn't enable notifications, and with
+    /// [`HostError::NoMem`] if the mbuf pool is exhausted.
+    pub fn notify(&self, conn: &Connection) -> Result<(), HostError> {
+        conn.check_connected()?;
+        if !self.subscription(conn).notify {
+            return Err(HostError::NotSubscribed);
+        }
+        let om = self.value_mbuf()?;
+        // NimBLE takes ownership of the mbuf, even if this fails
+        check(unsafe { raw::ble_gatts_notify_custom(conn.handle(), self.handle(), om.into_raw()) })
+    }
+
+    /// Sends the current value to the peer of `conn` in an indication, and waits until the peer
+    /// confirms it (NimBLE gives up after 30 seconds, failing with [`HostError::Timeout`]). Fails
+    /// with [`HostError::NotSubscribed`] if the peer didn't enable indications, and with
+    /// [`HostError::NoMem`] if the mbuf pool is exhausted.
+    ///
+    /// Indications on the same connection are sent one at a time.
+    pub async fn indicate(&self, conn: &Connection) -> Result<(), HostError> {
+        conn.check_connected()?;
+        let indication = &INDICATIONS[conn.slot()];
+        let _lock = indication.lock.lock().await;
+
+        if !self.subscription(conn).indicate {
+            return Err(HostError::NotSubscribed);
+        }
+        let om = self.value_mbuf()?;
+        let handle = self.handle();
+        indication.result.reset();
+        // NimBLE takes ownership of the mbuf, even if this fails
+        check(unsafe { raw::ble_gatts_indicate_custom(conn.handle(), handle, om.into_raw()) })?;
+
+        loop {
+            let (attr_handle, status) = indication.result.wait().await;
+            check_current(conn.generation())?;
+            // a late confirmation of an indication whose future was dropped
+            if attr_handle != handle {
+                continue;
+            }
+            return match status as u32 {
+                raw::BLE_HS_EDONE => Ok(()),
+                _ => Err(HostError::from(status)),
+            };
+        }
+    }
+}
+
+/// Maximum number of characteristics in a [`NotifyMultiple`].
+#[cfg(all(
+    feature = "gatt-notify-multiple",
+    any(feature = "role-central", feature = "role-peripheral")
+))]
+pub const MAX_NOTIFY_MULTIPLE: usize = 8;
+
+/// Notifies several characteristics at once, with an ATT Multiple Handle Value Notification if the
+/// peer supports it (NimBLE falls back to separate notifications otherwise).
+///
+/// ```ignore
+/// let mut batch = NotifyMultiple::new(&conn);
+/// batch.add(&SENSOR.temperature)?;
+/// batch.add(&SENSOR.humidity)?;
+/// batch.send()?;
+/// ```
+#[cfg(all(
+    feature = "gatt-notify-multiple",
+    any(feature = "role-central", feature = "role-peripheral")
+))]
+pub struct NotifyMultiple<'a> {
+    conn: &'a Connection,
+    tuples: [raw::ble_gatt_notif; MAX_NOTIFY_MULTIPLE],
+    len: usize,
+}
+
+#[cfg(all(
+    feature = "gatt-notify-multiple",
+    any(feature = "role-central", feature = "role-peripheral")
+))]
+impl<'a> NotifyMultiple<'a> {
+    pub fn new(conn: &'a Connection) -> Self {
+        Self {
+            conn,
+            tuples: unsafe { core::mem::zeroed() },
+            len: 0,
+        }
+    }
+
+    /// Adds the current value of a characteristic. Fails with [`HostError::NotSubscribed`] if the
+    /// peer didn't enable notifications, with [`HostError::NoMem`] if the mbuf pool is exhausted,
+    /// and with [`HostError::MessageTooLong`] if there are already [`MAX_NOTIFY_MULTIPLE`]
+    /// characteristics.
+    pub fn add<T: GattValue + Clone>(&mut self, chr: &Characteristic<T>) -> Result<(), HostError> {
+        if self.len == MAX_NOTIFY_MULTIPLE {
+            return Err(HostError::MessageTooLong);
+        }
+        if !chr.subscription(self.conn).notify {
+            return Err(HostError::NotSubscribed);
+        }
+        let om = chr.value_mbuf()?;
+        self.tuples[self.len] = raw::ble_gatt_notif {
+            handle: chr.handle(),
+            value: om.into_raw(),
+        };
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Sends the notifications.
+    pub fn send(mut self) -> Result<(), HostError> {
+        self.conn.check_connected()?;
+        let len = core::mem::take(&mut self.len);
+        // NimBLE takes ownership of the mbufs, even if this fails
+        check(unsafe {
+            raw::ble_gatts_notify_multiple_custom(self.conn.handle(), len, self.tuples.as_mut_ptr())
+        })
+    }
+}
+
+#[cfg(all(
+    feature = "gatt-notify-multiple",
+    any(feature = "role-central", feature = "role-peripheral")
+))]
+impl Drop for NotifyMultiple<'_> {
+    fn drop(&mut self) {
+        // values that weren't sent
+        for tuple in &self.tuples[..self.len] {
+            drop(unsafe { Mbuf::from_raw(tuple.value) });
+        }
+    }
+}
+
+/// ATT error code, returned from the access callbacks.
+fn att_error(code: u32) -> cty::c_int {
+    code as cty::c_int
+}
+
+/// Runs `f` with the mbuf of an access context, which stays owned by NimBLE.
+unsafe fn with_mbuf<R>(
+    ctxt: &raw::ble_gatt_access_ctxt,
+    f: impl FnOnce(&mut Mbuf) -> R,
+) -> Option<R> {
+    let mut om = ManuallyDrop::new(Mbuf::from_raw(ctxt.om)?);
+    Some(f(&mut om))
+}
+
+unsafe extern "C" fn access_characteristic<T: GattValue + Clone>(
+    conn_handle: u16,
+    attr_handle: u16,
+    ctxt: *mut raw::ble_gatt_access_ctxt,
+    arg: *mut cty::c_void,
+) -> cty::c_int {
+    let chr = &*(arg as *const Characteristic<T>);
+    let ctxt = &*ctxt;
+    match ctxt.op as u32 {
+        raw::BLE_GATT_ACCESS_OP_READ_CHR => {
+            let value = chr.get();
+            match with_mbuf(ctxt, |om| value.write_to(om)) {
+                Some(Ok(())) => 0,
+                _ => att_error(raw::BLE_ATT_ERR_INSUFFICIENT_RES),
+            }
+        }
+        raw::BLE_GATT_ACCESS_OP_WRITE_CHR => match with_mbuf(ctxt, |om| T::read_from(om)) {
+            Some(Some(value)) => {
+                trace!("connection {} wrote handle {}", conn_handle, attr_handle);
+                chr.set(value);
+                chr.written.signal(conn_handle);
+                0
+            }
+            _ => att_error(raw::BLE_ATT_ERR_INVALID_ATTR_VALUE_LEN),
+        },
+        _ => att_error(raw::BLE_ATT_ERR_UNLIKELY),
+    }
+}
+
+unsafe extern "C" fn access_descriptor(
+    _conn_handle: u16,
+    _attr_handle: u16,
+    ctxt: *mut raw::ble_gatt_access_ctxt,
+    arg: *mut cty::c_void,
+) -> cty::c_int {
+    let value = *(arg as *const &'static [u8]);
+    let ctxt = &*ctxt;
+    match ctxt.op as u32 {
+        raw::BLE_GATT_ACCESS_OP_READ_DSC => match with_mbuf(ctxt, |om| om.append(value)) {
+            Some(Ok(())) => 0,
+            _ => att_error(raw::BLE_ATT_ERR_INSUFFICIENT_RES),
+        },
+        _ => att_error(raw::BLE_ATT_ERR_UNLIKELY),
+    }
+}
+
+/// Support code for `#[derive(GattService)]`.
+#[doc(hidden)]
+pub mod __private {
+    use core::cell::UnsafeCell;
+    use core::sync::atomic::{AtomicU32, AtomicU8, AtomicUsize, Ordering};
+
+    use super::{access_characteristic, access_descriptor, Characteristic, GattValue};
+    use crate::raw;
+
+    const EMPTY: u8 = 0;
+    const FILLING: u8 = 1;
+    const FILLED: u8 = 2;
+
+    /// The state of a service type's tables, and the generation it was last registered in.
+    pub struct Registration {
+        state: AtomicU8,
+        /// The address of the instance the tables were filled in for.
+        owner: AtomicUsize,
+        /// The generation plus one, or 0 if the service was never registered.
+        registered: AtomicU32,
+    }
+
+    impl Registration {
+        pub const fn new() -> Self {
+            Self {
+                state: AtomicU8::new(EMPTY),
+                owner: AtomicUsize::new(0),
+                registered: AtomicU32::new(0),
+            }
+        }
+
+        /// Fills in the tables for `owner` the first time it's called. Returns whether the tables
+        /// belong to `owner`, i.e. `false` if they were filled in for another instance (or are
+        /// being filled in from another context).
+        pub fn fill_once<T>(&self, owner: &'static T, fill: impl FnOnce()) -> bool {
+            let owner = owner as *const T as usize;
+            match self
+                .state
+                .compare_exchange(EMPTY, FILLING, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => {
+                    self.owner.store(owner, Ordering::Relaxed);
+                    fill();
+                    self.state.store(FILLED, Ordering::Release);
+                    true
+                }
+                Err(FILLED) => self.owner.load(Ordering::Relaxed) == owner,
+                Err(_) => false,
+            }
+        }
+
+        pub(super) fn is_registered(&self, generation: u32) -> bool {
+            self.registered.load(Ordering::Acquire) == generation.wrapping_add(1)
+        }
+
+        pub(super) fn set_registered(&self, generation: u32) {
+            self.registered
+                .store(generation.wrapping_add(1), Ordering::Release);
+        }
+    }
+
+    impl Default for Registration {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    /// A table that NimBLE points to. It's filled in once, when the service is first registered.
+    #[repr(transparent)]
+    pub struct Static<T>(UnsafeCell<T>);
+
+    // Safety: the tables are only written once, by `Registration::fill_once`, before NimBLE reads
+    // them
+    unsafe impl<T> Sync for Static<T> {}
+
+    impl<T> Static<T> {
+        pub const fn zeroed() -> Self {
+            Self(UnsafeCell::new(unsafe { core::mem::zeroed() }))
+        }
+
+        pub const fn get(&self) -> *mut T {
+            self.0.get()
+        }
+    }
+
+    pub const fn uuid16(value: u16) -> raw::ble_uuid16_t {
+        raw::ble_uuid16_t {
+            u: raw::ble_uuid_t {
+                type_: raw::BLE_UUID_TYPE_16 as u8,
+            },
+            value,
+        }
+    }
+
+    pub const fn uuid32(value: u32) -> raw::ble_uuid32_t {
+        raw::ble_uuid32_t {
+            u: raw::ble_uuid_t {
+                type_: raw::BLE_UUID_TYPE_32 as u8,
+            },
+            value,
+        }
+    }
+
+    pub const fn uuid128(value: u128) -> raw::ble_uuid128_t {
+        raw::ble_uuid128_t {
+            u: raw::ble_uuid_t {
+                type_: raw::BLE_UUID_TYPE_128 as u8,
+            },
+            value: value.to_le_bytes(),
+        }
+    }
+
+    pub fn service(
+        uuid: *const raw::ble_uuid_t,
+        characteristics: *const raw::ble_gatt_chr_def,
+    ) -> raw::ble_gatt_svc_def {
+        let mut def: raw::ble_gatt_svc_def = unsafe { core::mem::zeroed() };
+        def.type_ = raw::BLE_GATT_SVC_TYPE_PRIMARY as u8;
+        def.uuid = uuid;
+        def.characteristics = characteristics;
+        def
+    }
+
+    pub fn characteristic<T: GattValue + Clone>(
+        uuid: *const raw::ble_uuid_t,
+        chr: &'static Characteristic<T>,
+        flags: u16,
+        min_key_size: u8,
+        descriptors: *mut raw::ble_gatt_dsc_def,
+    ) -> raw::ble_gatt_chr_def {
+        let mut def: raw::ble_gatt_chr_def = unsafe { core::mem::zeroed() };
+        def.uuid = uuid;
+        def.access_cb = Some(access_characteristic::<T>);
+        def.arg = chr as *const _ as *mut cty::c_void;
+        def.descriptors = descriptors;
+        def.flags = flags;
+        def.min_key_size = min_key_size;
+        def.val_handle = chr.registration.handle.as_ptr();
+        if flags & (raw::BLE_GATT_CHR_F_NOTIFY | raw::BLE_GATT_CHR_F_INDICATE) as u16 != 0 {
+            chr.registration.link();
+        }
+        def
+    }
+
+    pub fn descriptor(
+        uuid: *const raw::ble_uuid_t,
+        att_flags: u8,
+        min_key_size: u8,
+        value: &'static &'static [u8],
+    ) -> raw::ble_gatt_dsc_def {
+        let mut def: raw::ble_gatt_dsc_def = unsafe { core::mem::zeroed() };
+        def.uuid = uuid;
+        def.att_flags = att_flags;
+        def.min_key_size = min_key_size;
+        def.access_cb = Some(access_descriptor);
+        def.arg = value as *const _ as *mut cty::c_void;
+        def
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MbufPool;
+
+    type Pool = MbufPool<64, 8>;
+
+    fn mbuf(pool: &'static Pool, data: &[u8]) -> Mbuf {
+        pool.alloc_from_slice(data).unwrap()
+    }
+
+    fn written<T: GattValue>(pool: &'static Pool, value: &T) -> Vec<u8> {
+        let mut om = pool.get_pkthdr(0).unwrap();
+        value.write_to(&mut om).unwrap();
+        om.segments().flatten().copied().collect()
+    }
+
+    #[test]
+    fn ints_round_trip_little_endian() {
+        static POOL: Pool = Pool::new(c"gatt-ints");
+        assert_eq!(written(&POOL, &0x1234u16), [0x34, 0x12]);
+        assert_eq!(written(&POOL, &-2i32), [0xfe, 0xff, 0xff, 0xff]);
+        assert_eq!(written(&POOL, &true), [1]);
+
+        assert_eq!(u16::read_from(&mbuf(&POOL, &[0x34, 0x12])), Some(0x1234));
+        assert_eq!(i64::read_from(&mbuf(&POOL, &[0xff; 8])), Some(-1));
+        assert_eq!(
+            f32::read_from(&mbuf(&POOL, &1.5f32.to_le_bytes())),
+            Some(1.5)
+        );
+        assert_eq!(bool::read_from(&mbuf(&POOL, &[2])), Some(true));
+    }
+
+    #[test]
+    fn ints_reject_the_wrong_length() {
+        static POOL: Pool = Pool::new(c"gatt-lengths");
+        assert_eq!(u8::read_from(&mbuf(&POOL, &[])), None);
+        assert_eq!(u8::read_from(&mbuf(&POOL, &[1, 2])), None);
+        assert_eq!(u16::read_from(&mbuf(&POOL, &[1])), None);
+        assert_eq!(u16::read_from(&mbuf(&POOL, &[1, 2, 3])), None);
+        assert_eq!(u32::read_from(&mbuf(&POOL, &[1, 2, 3])), None);
+        assert_eq!(bool::read_from(&mbuf(&POOL, &[1, 0])), None);
+        assert_eq!(<[u8; 4]>::read_from(&mbuf(&POOL, &[1, 2, 3])), None);
+        assert_eq!(<[u8; 4]>::read_from(&mbuf(&POOL, &[1, 2, 3, 4, 5])), None);
+        assert_eq!(
+            <[u8; 4]>::read_from(&mbuf(&POOL, &[1, 2, 3, 4])),
+            Some([1, 2, 3, 4])
+        );
+    }
+
+    #[test]
+    fn bytes_accept_up_to_n() {
+        static POOL: Pool = Pool::new(c"gatt-bytes");
+        let bytes = GattBytes::<4>::read_from(&mbuf(&POOL, &[])).unwrap();
+        assert!(bytes.is_empty());
+        let bytes = GattBytes::<4>::read_from(&mbuf(&POOL, &[1, 2, 3, 4])).unwrap();
+        assert_eq!(bytes.as_bytes(), [1, 2, 3, 4]);
+        assert_eq!(written(&POOL, &bytes), [1, 2, 3, 4]);
+        assert_eq!(
+            GattBytes::<4>::read_from(&mbuf(&POOL, &[1, 2, 3, 4, 5])),
+            None
+        );
+
+        assert_eq!(
+            GattBytes::<4>::from_slice(&[1, 2]).unwrap().as_bytes(),
+            [1, 2]
+        );
+        assert_eq!(
+            GattBytes::<4>::from_slice(&[0; 5]).unwrap_err(),
+            HostError::MessageTooLong
+        );
+    }
+}
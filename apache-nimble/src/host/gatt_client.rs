@@ -0,0 +1,836 @@
+use core::cell::RefCell;
+use core::future::poll_fn;
+use core::task::Poll;
+
+use embassy_futures::select::select;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::waitqueue::{AtomicWaker, MultiWakerRegistration};
+use embassy_time::{Duration, Timer};
+
+use super::{check, Connection, HostError};
+use crate::{raw, Mbuf};
+
+/// Maximum number of GATT client procedures in progress at a time, across all connections
+/// (`BLE_GATT_MAX_PROCS`). Further procedures wait for one of them to complete.
+///
+/// NimBLE's pool of procedures is also used by indications sent by the GATT server and by MTU
+/// exchanges, so fewer client procedures can be running when those are in progress.
+pub const MAX_PROCS: usize = raw::MYNEWT_VAL_BLE_GATT_MAX_PROCS as usize;
+
+/// How long a procedure that found NimBLE's procedure pool full waits before trying again, if no
+/// other client procedure finishes in the meantime.
+const PROC_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// ATT opcode and handle, which a write request adds to the value.
+const WRITE_HEADER_LEN: usize = 3;
+
+/// A 16-bit, 32-bit or 128-bit UUID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Uuid {
+    Uuid16(u16),
+    Uuid32(u32),
+    Uuid128(u128),
+}
+
+impl Uuid {
+    /// Reads a `ble_uuid16_t`, `ble_uuid32_t` or `ble_uuid128_t`, depending on its type.
+    unsafe fn from_raw(uuid: *const raw::ble_uuid_t) -> Self {
+        match (*uuid).type_ as u32 {
+            raw::BLE_UUID_TYPE_16 => Uuid::Uuid16((*(uuid as *const raw::ble_uuid16_t)).value),
+            raw::BLE_UUID_TYPE_32 => Uuid::Uuid32((*(uuid as *const raw::ble_uuid32_t)).value),
+            _ => Uuid::Uuid128(u128::from_le_bytes(
+                (*(uuid as *const raw::ble_uuid128_t)).value,
+            )),
+        }
+    }
+}
+
+impl Default for Uuid {
+    fn default() -> Self {
+        Uuid::Uuid16(0)
+    }
+}
+
+/// A service found by [`GattClient::discover_services`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct RemoteService {
+    pub uuid: Uuid,
+    pub start_handle: u16,
+    pub end_handle: u16,
+}
+
+/// Properties of a characteristic, from its declaration.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct CharProperties(pub u8);
+
+impl CharProperties {
+    pub const BROADCAST: Self = Self(0x01);
+    pub const READ: Self = Self(0x02);
+    pub const WRITE_WITHOUT_RESPONSE: Self = Self(0x04);
+    pub const WRITE: Self = Self(0x08);
+    pub const NOTIFY: Self = Self(0x10);
+    pub const INDICATE: Self = Self(0x20);
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+/// A characteristic found by [`GattClient::discover_characteristics`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct RemoteCharacteristic {
+    pub uuid: Uuid,
+    /// Handle of the characteristic declaration.
+    pub def_handle: u16,
+    pub value_handle: u16,
+    pub properties: CharProperties,
+}
+
+/// A descriptor found by [`GattClient::discover_descriptors`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct RemoteDescriptor {
+    pub uuid: Uuid,
+    pub handle: u16,
+}
+
+/// A notification or indication received from a peer's GATT server.
+#[derive(Debug, defmt::Format)]
+pub struct Notification {
+    /// Handle of the characteristic value.
+    pub handle: u16,
+    /// Whether this was an indication (which NimBLE has already confirmed).
+    pub indication: bool,
+    pub data: Mbuf,
+}
+
+/// Where the results of a procedure go, while its caller is waiting for them.
+#[derive(Clone, Copy)]
+enum Sink {
+    /// The caller is gone, or the procedure has no results.
+    None,
+    Services(*mut RemoteService, usize),
+    Characteristics(*mut RemoteCharacteristic, usize),
+    Descriptors(*mut RemoteDescriptor, usize),
+    Bytes(*mut u8, usize),
+}
+
+// Safety: the buffers are only accessed with `PROCS` locked, and detached before their owner goes
+// away
+unsafe impl Send for Sink {}
+
+struct ProcState {
+    in_use: bool,
+    /// Whether the caller is still waiting for the procedure.
+    waiting: bool,
+    /// Whether NimBLE has accepted the procedure (and will call its callback until it's done).
+    started: bool,
+    sink: Sink,
+    /// Number of results (or bytes) stored in the sink.
+    count: usize,
+    /// Whether there were more results than fit in the sink.
+    overflow: bool,
+    /// Final status (a NimBLE host error code), once the procedure is done.
+    status: Option<i32>,
+}
+
+impl ProcState {
+    const fn new() -> Self {
+        Self {
+            in_use: false,
+            waiting: false,
+            started: false,
+            sink: Sink::None,
+            count: 0,
+            overflow: false,
+            status: None,
+        }
+    }
+}
+
+struct Procs {
+    states: [ProcState; MAX_PROCS],
+    /// Tasks waiting for a free procedure slot.
+    free_wakers: MultiWakerRegistration<4>,
+}
+
+static PROCS: Mutex<CriticalSectionRawMutex, RefCell<Procs>> = Mutex::new(RefCell::new(Procs {
+    states: [const { ProcState::new() }; MAX_PROCS],
+    free_wakers: MultiWakerRegistration::new(),
+}));
+static DONE_WAKERS: [AtomicWaker; MAX_PROCS] = [const { AtomicWaker::new() }; MAX_PROCS];
+
+fn release(procs: &mut Procs, slot: usize) {
+    procs.states[slot] = ProcState::new();
+    procs.free_wakers.wake();
+}
+
+/// Runs `f` on the state of the procedure whose callback argument is `slot`.
+fn with_proc(slot: *mut cty::c_void, f: impl FnOnce(&mut ProcState)) {
+    PROCS.lock(|procs| f(&mut procs.borrow_mut().states[slot as usize]));
+}
+
+/// Completes a procedure, and frees its slot if its caller is gone.
+fn finish(slot: *mut cty::c_void, status: i32) {
+    let slot = slot as usize;
+    PROCS.lock(|procs| {
+        let mut procs = procs.borrow_mut();
+        procs.states[slot].status = Some(status);
+        if !procs.states[slot].waiting {
+            release(&mut procs, slot);
+        }
+    });
+    DONE_WAKERS[slot].wake();
+}
+
+/// Fails every procedure, after the host was shut down.
+pub(crate) fn reset() {
+    PROCS.lock(|procs| {
+        let mut procs = procs.borrow_mut();
+        for slot in 0..MAX_PROCS {
+            let state = &mut procs.states[slot];
+            if state.waiting {
+                // NimBLE won't call the callback anymore
+                state.status = Some(raw::BLE_HS_EDISABLED as i32);
+                state.sink = Sink::None;
+                DONE_WAKERS[slot].wake();
+            } else if state.in_use {
+                release(&mut procs, slot);
+            }
+        }
+    });
+}
+
+/// Appends `item` to a sink buffer, if it fits.
+unsafe fn store<T>(state: &mut ProcState, buf: *mut T, len: usize, item: T) {
+    if state.count < len {
+        buf.add(state.count).write(item);
+        state.count += 1;
+    } else {
+        state.overflow = true;
+    }
+}
+
+unsafe extern "C" fn on_service(
+    _conn_handle: u16,
+    error: *const raw::ble_gatt_error,
+    service: *const raw::ble_gatt_svc,
+    arg: *mut cty::c_void,
+) -> cty::c_int {
+    let status = (*error).status as i32;
+    if status != 0 {
+        finish(arg, status);
+        return 0;
+    }
+    let service = &*service;
+    with_proc(arg, |state| {
+        if let Sink::Services(buf, len) = state.sink {
+            let item = RemoteService {
+                uuid: Uuid::from_raw(&service.uuid as *const _ as *const raw::ble_uuid_t),
+                start_handle: service.start_handle,
+                end_handle: service.end_handle,
+            };
+            store(state, buf, len, item);
+        }
+    });
+    0
+}
+
+unsafe extern "C" fn on_characteristic(
+    _conn_handle: u16,
+    error: *const raw::ble_gatt_error,
+    chr: *const raw::ble_gatt_chr,
+    arg: *mut cty::c_void,
+) -> cty::c_int {
+    let status = (*error).status as i32;
+    if status != 0 {
+        finish(arg, status);
+        return 0;
+    }
+    let chr = &*chr;
+    with_proc(arg, |state| {
+        if let Sink::Characteristics(buf, len) = state.sink {
+            let item = RemoteCharacteristic {
+                uuid: Uuid::from_raw(&chr.uuid as *const _ as *const raw::ble_uuid_t),
+                def_handle: chr.def_handle,
+                value_handle: chr.val_handle,
+                properties: CharProperties(chr.properties),
+            };
+            store(state, buf, len, item);
+        }
+    });
+    0
+}
+
+unsafe extern "C" fn on_descriptor(
+    _conn_handle: u16,
+    error: *const raw::ble_gatt_error,
+    _chr_val_handle: u16,
+    dsc: *const raw::ble_gatt_dsc,
+    arg: *mut cty::c_void,
+) -> cty::c_int {
+    let status = (*error).status as i32;
+    if status != 0 {
+        finish(arg, status);
+        return 0;
+    }
+    let dsc = &*dsc;
+    with_proc(arg, |state| {
+        if let Sink::Descriptors(buf, len) = state.sink {
+            let item = RemoteDescriptor {
+                uuid: Uuid::from_raw(&dsc.uuid as *const _ as *const raw::ble_uuid_t),
+                handle: dsc.handle,
+            };
+            store(state, buf, len, item);
+        }
+    });
+    0
+}
+
+/// Copies (part of) an attribute value into a byte sink, at the attribute's offset.
+unsafe fn store_attr(state: &mut ProcState, attr: &raw::ble_gatt_attr) {
+    let Sink::Bytes(buf, len) = state.sink else {
+        return;
+    };
+    let Some(om) = Mbuf::from_raw(attr.om) else {
+        return;
+    };
+    // NimBLE frees the mbuf after the callback
+    let om = core::mem::ManuallyDrop::new(om);
+    let offset = attr.offset as usize;
+    if offset >= len {
+        // nothing of it fits, and `buf.add(offset)` would point past the buffer
+        state.overflow |= !om.is_empty();
+        return;
+    }
+    let end = offset + om.len();
+    if end > len {
+        state.overflow = true;
+    }
+    let copy_len = end.min(len).saturating_sub(offset);
+    let buf = core::slice::from_raw_parts_mut(buf.add(offset), copy_len);
+    if om.copy_to(0, buf).is_ok() {
+        state.count = state.count.max(offset + copy_len);
+    }
+}
+
+unsafe extern "C" fn on_read(
+    _conn_handle: u16,
+    error: *const raw::ble_gatt_error,
+    attr: *mut raw::ble_gatt_attr,
+    arg: *mut cty::c_void,
+) -> cty::c_int {
+    let status = (*error).status as i32;
+    if status == 0 {
+        with_proc(arg, |state| store_attr(state, &*attr));
+    }
+    // a single read completes with its first callback
+    finish(arg, status);
+    0
+}
+
+unsafe extern "C" fn on_read_long(
+    _conn_handle: u16,
+    error: *const raw::ble_gatt_error,
+    attr: *mut raw::ble_gatt_attr,
+    arg: *mut cty::c_void,
+) -> cty::c_int {
+    let status = (*error).status as i32;
+    if status == 0 {
+        with_proc(arg, |state| store_attr(state, &*attr));
+    } else {
+        finish(arg, status);
+    }
+    0
+}
+
+unsafe extern "C" fn on_write(
+    _conn_handle: u16,
+    error: *const raw::ble_gatt_error,
+    _attr: *mut raw::ble_gatt_attr,
+    arg: *mut cty::c_void,
+) -> cty::c_int {
+    finish(arg, (*error).status as i32);
+    0
+}
+
+/// Frees the slot of a procedure when its caller goes away, or detaches the caller's buffer if
+/// NimBLE is still running the procedure.
+struct ProcGuard {
+    slot: usize,
+}
+
+impl Drop for ProcGuard {
+    fn drop(&mut self) {
+        PROCS.lock(|procs| {
+            let mut procs = procs.borrow_mut();
+            let state = &mut procs.states[self.slot];
+            if state.started && state.status.is_none() {
+                state.waiting = false;
+                state.sink = Sink::None;
+            } else {
+                release(&mut procs, self.slot);
+            }
+        });
+    }
+}
+
+/// Waits for a free procedure slot, and reserves it.
+async fn acquire() -> ProcGuard {
+    poll_fn(|cx| {
+        PROCS.lock(|procs| {
+            let mut procs = procs.borrow_mut();
+            match procs.states.iter().position(|s| !s.in_use) {
+                Some(slot) => {
+                    procs.states[slot].in_use = true;
+                    procs.states[slot].waiting = true;
+                    Poll::Ready(ProcGuard { slot })
+                }
+                None => {
+                    procs.free_wakers.register(cx.waker());
+                    Poll::Pending
+                }
+            }
+        })
+    })
+    .await
+}
+
+/// Waits until another client procedure finishes, or for [`PROC_RETRY_INTERVAL`], since the
+/// indications and MTU exchanges that share NimBLE's procedure pool don't tell us when they're done.
+async fn wait_for_free_proc() {
+    let mut registered = false;
+    let freed = poll_fn(|cx| {
+        if registered {
+            return Poll::Ready(());
+        }
+        PROCS.lock(|procs| procs.borrow_mut().free_wakers.register(cx.waker()));
+        registered = true;
+        Poll::Pending
+    });
+    select(freed, Timer::after(PROC_RETRY_INTERVAL)).await;
+}
+
+/// Runs a procedure that stores its results in `sink`. `start` starts it with the given callback
+/// argument, and is called again if NimBLE's procedure pool is full (`BLE_HS_ENOMEM`), once
+/// another procedure may have finished. Returns the number of results.
+async fn run(
+    sink: Sink,
+    mut start: impl FnMut(*mut cty::c_void) -> cty::c_int,
+) -> Result<usize, HostError> {
+    let guard = loop {
+        let guard = acquire().await;
+        let slot = guard.slot;
+        PROCS.lock(|procs| procs.borrow_mut().states[slot].sink = sink);
+
+        match start(slot as *mut cty::c_void) {
+            rc if rc == raw::BLE_HS_ENOMEM as i32 => {
+                // the slot wasn't started, so this frees it
+                drop(guard);
+                wait_for_free_proc().await;
+            }
+            rc => {
+                check(rc)?;
+                break guard;
+            }
+        }
+    };
+    let slot = guard.slot;
+    PROCS.lock(|procs| procs.borrow_mut().states[slot].started = true);
+
+    let (status, count, overflow) = poll_fn(|cx| {
+        DONE_WAKERS[slot].register(cx.waker());
+        PROCS.lock(|procs| {
+            let procs = procs.borrow();
+            let state = &procs.states[slot];
+            match state.status {
+                Some(status) => Poll::Ready((status, state.count, state.overflow)),
+                None => Poll::Pending,
+            }
+        })
+    })
+    .await;
+    drop(guard);
+
+    match status as u32 {
+        // discovery procedures and long reads end with `BLE_HS_EDONE`
+        0 | raw::BLE_HS_EDONE if overflow => Err(HostError::NoMem),
+        0 | raw::BLE_HS_EDONE => Ok(count),
+        _ => Err(HostError::from(status)),
+    }
+}
+
+/// GATT client procedures on a connection.
+///
+/// NimBLE runs at most [`MAX_PROCS`] procedures at a time. Beyond that, procedures wait for a free
+/// slot before starting. The same pool holds NimBLE's indications and MTU exchanges: a procedure
+/// that finds it full waits for one of them to finish, and tries again. NimBLE can't cancel
+/// procedures: when a future is dropped, the procedure keeps its slot until the peer responds (or
+/// the connection ends), and its results are dropped.
+pub struct GattClient<'a> {
+    conn: &'a Connection,
+}
+
+impl<'a> GattClient<'a> {
+    pub fn new(conn: &'a Connection) -> Self {
+        Self { conn }
+    }
+
+    /// Discovers all primary services, and stores them in `services`. Returns the number of
+    /// services, or [`HostError::NoMem`] if they don't fit.
+    pub async fn discover_services(
+        &self,
+        services: &mut [RemoteService],
+    ) -> Result<usize, HostError> {
+        self.conn.check_connected()?;
+        let handle = self.conn.handle();
+        let sink = Sink::Services(services.as_mut_ptr(), services.len());
+        run(sink, |arg| unsafe {
+            raw::ble_gattc_disc_all_svcs(handle, Some(on_service), arg)
+        })
+        .await
+    }
+
+    /// Discovers the characteristics of a service, and stores them in `characteristics`. Returns
+    /// the number of characteristics, or [`HostError::NoMem`] if they don't fit.
+    pub async fn discover_characteristics(
+        &self,
+        service: &RemoteService,
+        characteristics: &mut [RemoteCharacteristic],
+    ) -> Result<usize, HostError> {
+        self.conn.check_connected()?;
+        let handle = self.conn.handle();
+        let sink = Sink::Characteristics(characteristics.as_mut_ptr(), characteristics.len());
+        run(sink, |arg| unsafe {
+            raw::ble_gattc_disc_all_chrs(
+                handle,
+                service.start_handle,
+                service.end_handle,
+                Some(on_characteristic),
+                arg,
+            )
+        })
+        .await
+    }
+
+    /// Discovers the descriptors of a characteristic, and stores them in `descriptors`.
+    /// `end_handle` is the last handle of the characteristic: the handle before the next
+    /// characteristic's declaration, or the end of its service. Returns the number of descriptors,
+    /// or [`HostError::NoMem`] if they don't fit.
+    pub async fn discover_descriptors(
+        &self,
+        characteristic: &RemoteCharacteristic,
+        end_handle: u16,
+        descriptors: &mut [RemoteDescriptor],
+    ) -> Result<usize, HostError> {
+        self.conn.check_connected()?;
+        let handle = self.conn.handle();
+        let sink = Sink::Descriptors(descriptors.as_mut_ptr(), descriptors.len());
+        run(sink, |arg| unsafe {
+            raw::ble_gattc_disc_all_dscs(
+                handle,
+                characteristic.value_handle,
+                end_handle,
+                Some(on_descriptor),
+                arg,
+            )
+        })
+        .await
+    }
+
+    /// Reads an attribute, with a single request (which returns at most the ATT MTU - 1 bytes).
+    /// Returns the length of the value, or [`HostError::NoMem`] if it doesn't fit in `buf`.
+    pub async fn read(&self, attr_handle: u16, buf: &mut [u8]) -> Result<usize, HostError> {
+        self.conn.check_connected()?;
+        let handle = self.conn.handle();
+        let sink = Sink::Bytes(buf.as_mut_ptr(), buf.len());
+        run(sink, |arg| unsafe {
+            raw::ble_gattc_read(handle, attr_handle, Some(on_read), arg)
+        })
+        .await
+    }
+
+    /// Reads an attribute whose value may be longer than what fits in a single response. Returns
+    /// the length of the value, or [`HostError::NoMem`] if it doesn't fit in `buf`.
+    pub async fn read_long(&self, attr_handle: u16, buf: &mut [u8]) -> Result<usize, HostError> {
+        self.conn.check_connected()?;
+        let handle = self.conn.handle();
+        let sink = Sink::Bytes(buf.as_mut_ptr(), buf.len());
+        run(sink, |arg| unsafe {
+            raw::ble_gattc_read_long(handle, attr_handle, 0, Some(on_read_long), arg)
+        })
+        .await
+    }
+
+    /// Writes an attribute, and waits for the peer to acknowledge it. Values longer than what fits
+    /// in a single request are written with a long write.
+    pub async fn write(&self, attr_handle: u16, data: &[u8]) -> Result<(), HostError> {
+        self.conn.check_connected()?;
+        let handle = self.conn.handle();
+        let mtu = unsafe { raw::ble_att_mtu(handle) } as usize;
+        let len = u16::try_from(data.len()).map_err(|_| HostError::MessageTooLong)?;
+
+        if data.len() + WRITE_HEADER_LEN <= mtu {
+            run(Sink::None, |arg| unsafe {
+                raw::ble_gattc_write_flat(
+                    handle,
+                    attr_handle,
+                    data.as_ptr() as *const cty::c_void,
+                    len,
+                    Some(on_write),
+                    arg,
+                )
+            })
+            .await?;
+        } else {
+            let mut om = Some(Mbuf::from_slice(data)?);
+            run(Sink::None, |arg| unsafe {
+                // NimBLE takes ownership of the mbuf, even if this fails, so a retry needs a new
+                // one
+                let om = match om.take().map_or_else(|| Mbuf::from_slice(data), Ok) {
+                    Ok(om) => om,
+                    Err(_) => return raw::BLE_HS_ENOMEM as i32,
+                };
+                raw::ble_gattc_write_long(
+                    handle,
+                    attr_handle,
+                    0,
+                    om.into_raw(),
+                    Some(on_write),
+                    arg,
+                )
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Writes an attribute with a write command, which the peer doesn't acknowledge. The value
+    /// has to fit in a single packet (the ATT MTU - 3 bytes).
+    pub fn write_without_response(&self, attr_handle: u16, data: &[u8]) -> Result<(), HostError> {
+        self.conn.check_connected()?;
+        let len = u16::try_from(data.len()).map_err(|_| HostError::MessageTooLong)?;
+        check(unsafe {
+            raw::ble_gattc_write_no_rsp_flat(
+                self.conn.handle(),
+                attr_handle,
+                data.as_ptr() as *const cty::c_void,
+                len,
+            )
+        })
+    }
+
+    /// Enables or disables notifications and indications, by writing the Client Characteristic
+    /// Configuration descriptor (found with [`GattClient::discover_descriptors`], UUID 0x2902).
+    pub async fn subscribe(
+        &self,
+        cccd_handle: u16,
+        notifications: bool,
+        indications: bool,
+    ) -> Result<(), HostError> {
+        let value = u16::from(notifications) | u16::from(indications) << 1;
+        self.write(cccd_handle, &value.to_le_bytes()).await
+    }
+
+    /// Waits for the next notification or indication from the peer. Fails with
+    /// [`HostError::NotConnected`] once the connection is gone.
+    ///
+    /// Notifications are queued per connection (see [`super::connection::NOTIFICATION_QUEUE_LEN`]),
+    /// and dropped when the queue is full. Only one task can wait for notifications at a time.
+    pub async fn notification(&self) -> Result<Notification, HostError> {
+        self.conn.next_notification().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::{block_on, poll_once};
+
+    use super::*;
+    use crate::test_support::lock_stack;
+    use crate::MbufPool;
+
+    type Pool = MbufPool<64, 8>;
+
+    const EDONE: u32 = raw::BLE_HS_EDONE;
+
+    fn error(status: u32) -> raw::ble_gatt_error {
+        let mut error: raw::ble_gatt_error = unsafe { core::mem::zeroed() };
+        error.status = status as u16;
+        error
+    }
+
+    fn service(uuid: u16, start_handle: u16) -> raw::ble_gatt_svc {
+        let mut service: raw::ble_gatt_svc = unsafe { core::mem::zeroed() };
+        service.uuid.u16 = raw::ble_uuid16_t {
+            u: raw::ble_uuid_t {
+                type_: raw::BLE_UUID_TYPE_16 as u8,
+            },
+            value: uuid,
+        };
+        service.start_handle = start_handle;
+        service.end_handle = start_handle + 4;
+        service
+    }
+
+    /// Passes `data`, at `offset`, to `callback` as NimBLE does for reads.
+    fn attr(
+        pool: &'static Pool,
+        offset: u16,
+        data: &[u8],
+        callback: impl FnOnce(&mut raw::ble_gatt_attr),
+    ) {
+        let om = pool.alloc_from_slice(data).unwrap();
+        let mut attr: raw::ble_gatt_attr = unsafe { core::mem::zeroed() };
+        attr.offset = offset;
+        attr.om = om.as_ptr();
+        callback(&mut attr);
+    }
+
+    fn bytes_state(buf: &mut [u8]) -> ProcState {
+        let mut state = ProcState::new();
+        state.sink = Sink::Bytes(buf.as_mut_ptr(), buf.len());
+        state
+    }
+
+    fn all_free() -> bool {
+        PROCS.lock(|procs| procs.borrow().states.iter().all(|s| !s.in_use))
+    }
+
+    #[test]
+    fn store_attr_copies_at_the_offset() {
+        static POOL: Pool = Pool::new(c"store-attr");
+        let mut buf = [0; 8];
+        let mut state = bytes_state(&mut buf);
+
+        // inside the sink, and ending at its end
+        attr(&POOL, 2, &[3, 4], |a| unsafe { store_attr(&mut state, a) });
+        assert_eq!(state.count, 4);
+        attr(&POOL, 4, &[5, 6, 7, 8], |a| unsafe {
+            store_attr(&mut state, a)
+        });
+        assert_eq!(state.count, 8);
+        assert!(!state.overflow);
+        // an earlier chunk doesn't shrink the count
+        attr(&POOL, 0, &[1, 2], |a| unsafe { store_attr(&mut state, a) });
+        assert_eq!(state.count, 8);
+        assert!(!state.overflow);
+        assert_eq!(buf, [1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn store_attr_flags_what_doesnt_fit() {
+        static POOL: Pool = Pool::new(c"store-attr-overflow");
+        let mut buf = [0; 8];
+
+        // straddling the end of the sink: the start is still copied
+        let mut state = bytes_state(&mut buf);
+        attr(&POOL, 6, &[7, 8, 9], |a| unsafe {
+            store_attr(&mut state, a)
+        });
+        assert_eq!(state.count, 8);
+        assert!(state.overflow);
+
+        // an empty chunk at the end of the sink fits
+        let mut state = bytes_state(&mut buf);
+        attr(&POOL, 8, &[], |a| unsafe { store_attr(&mut state, a) });
+        assert_eq!(state.count, 0);
+        assert!(!state.overflow);
+        // anything at or past the end doesn't
+        attr(&POOL, 8, &[9], |a| unsafe { store_attr(&mut state, a) });
+        assert!(state.overflow);
+        let mut state = bytes_state(&mut buf);
+        attr(&POOL, 20, &[9], |a| unsafe { store_attr(&mut state, a) });
+        assert_eq!(state.count, 0);
+        assert!(state.overflow);
+        assert_eq!(buf, [0, 0, 0, 0, 0, 0, 7, 8]);
+    }
+
+    #[test]
+    fn run_counts_results() {
+        let _stack = lock_stack();
+        let mut services = [RemoteService::default(); 2];
+        let sink = Sink::Services(services.as_mut_ptr(), services.len());
+        let count = block_on(run(sink, |arg| unsafe {
+            on_service(0, &error(0), &service(0x180f, 1), arg);
+            on_service(0, &error(0), &service(0x180a, 6), arg);
+            on_service(0, &error(EDONE), core::ptr::null(), arg);
+            0
+        }));
+        assert_eq!(count, Ok(2));
+        assert_eq!(services[1].uuid, Uuid::Uuid16(0x180a));
+        assert_eq!(services[1].start_handle, 6);
+        assert!(all_free());
+    }
+
+    #[test]
+    fn run_reports_overflow_as_no_mem() {
+        static POOL: Pool = Pool::new(c"run-overflow");
+        let _stack = lock_stack();
+
+        let mut services = [RemoteService::default(); 1];
+        let sink = Sink::Services(services.as_mut_ptr(), services.len());
+        let result = block_on(run(sink, |arg| unsafe {
+            on_service(0, &error(0), &service(0x180f, 1), arg);
+            on_service(0, &error(0), &service(0x180a, 6), arg);
+            on_service(0, &error(EDONE), core::ptr::null(), arg);
+            0
+        }));
+        assert_eq!(result, Err(HostError::NoMem));
+        assert_eq!(services[0].uuid, Uuid::Uuid16(0x180f));
+
+        let mut buf = [0; 4];
+        let sink = Sink::Bytes(buf.as_mut_ptr(), buf.len());
+        let result = block_on(run(sink, |arg| {
+            attr(&POOL, 0, &[1, 2, 3], |a| unsafe {
+                on_read_long(0, &error(0), a, arg);
+            });
+            attr(&POOL, 3, &[4, 5], |a| unsafe {
+                on_read_long(0, &error(0), a, arg);
+            });
+            unsafe { on_read_long(0, &error(EDONE), core::ptr::null_mut(), arg) };
+            0
+        }));
+        assert_eq!(result, Err(HostError::NoMem));
+        assert_eq!(buf, [1, 2, 3, 4]);
+        assert!(all_free());
+    }
+
+    #[test]
+    fn dropping_a_started_procedure_detaches_its_sink() {
+        static POOL: Pool = Pool::new(c"proc-guard");
+        let _stack = lock_stack();
+
+        let mut buf = [0; 4];
+        let mut slot = None;
+        let sink = Sink::Bytes(buf.as_mut_ptr(), buf.len());
+        // NimBLE accepts the procedure, and the caller gives up before the peer responds
+        let poll = poll_once(run(sink, |arg| {
+            slot = Some(arg);
+            0
+        }));
+        assert!(matches!(poll, Poll::Pending));
+        let arg = slot.unwrap();
+        PROCS.lock(|procs| {
+            let procs = procs.borrow();
+            let state = &procs.states[arg as usize];
+            assert!(state.in_use && state.started && !state.waiting);
+            assert!(matches!(state.sink, Sink::None));
+        });
+
+        // the late response is dropped, and frees the slot
+        attr(&POOL, 0, &[1, 2, 3], |a| unsafe {
+            on_read(0, &error(0), a, arg);
+        });
+        assert_eq!(buf, [0; 4]);
+        assert!(all_free());
+    }
+
+    #[test]
+    fn a_procedure_that_fails_to_start_frees_its_slot() {
+        let _stack = lock_stack();
+        let result = block_on(run(Sink::None, |_| raw::BLE_HS_EINVAL as i32));
+        assert_eq!(result, Err(HostError::from(raw::BLE_HS_EINVAL as i32)));
+        assert!(all_free());
+    }
+}
@@ -0,0 +1,1066 @@
+use core::cell::RefCell;
+use core::future::poll_fn;
+use core::mem::MaybeUninit;
+use core::task::Poll;
+
+use defmt::{trace, warn};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::waitqueue::{AtomicWaker, MultiWakerRegistration};
+
+use super::{check, check_current, Connection, HostError, NimbleHost};
+use crate::{is_current, raw, Mbuf};
+
+/// Maximum number of L2CAP connection-oriented channels, across all connections
+/// (`BLE_L2CAP_COC_MAX_NUM`). This also limits the number of listened PSMs.
+pub const MAX_CHANNELS: usize = raw::MYNEWT_VAL_BLE_L2CAP_COC_MAX_NUM as usize;
+
+/// Number of received SDUs that can be queued per channel while waiting for
+/// [`L2capChannel::receive`]. Once the queue is full, the peer runs out of credits until an SDU
+/// is received.
+pub const RX_QUEUE_LEN: usize = 2;
+
+/// Maximum number of channels opened by a single enhanced connect request.
+#[cfg(feature = "l2cap-enhanced-coc")]
+pub const MAX_ENHANCED_CHANNELS: usize = 5;
+
+/// Callback argument of channels we connect, ORed with the first slot of the request. Channels
+/// accepted by a listener get its PSM instead.
+const ARG_CONNECT: usize = 1 << 16;
+
+struct ChanState {
+    in_use: bool,
+    /// Incremented whenever the slot is released, so that a stale [`L2capChannel`] can tell.
+    epoch: u32,
+    /// Whether the slot belongs to an [`L2capChannel`] or to a connect in progress.
+    owned: bool,
+    /// NimBLE's channel, while it's open (or being connected).
+    chan: *mut raw::ble_l2cap_chan,
+    conn_handle: u16,
+    /// PSM of the listener that accepted the channel, until it's handed out by
+    /// [`L2capListener::accept`].
+    listener: Option<u16>,
+    /// First slot of the connect request that the channel belongs to.
+    request: Option<usize>,
+    connected: bool,
+    /// Why the channel is closed (a NimBLE host error code), once it is.
+    closed: Option<i32>,
+    rx: [Option<Mbuf>; RX_QUEUE_LEN],
+    rx_head: usize,
+    rx_len: usize,
+    /// Whether NimBLE is waiting for a buffer to receive the next SDU into.
+    rx_starved: bool,
+    /// Whether NimBLE ran out of credits while sending the last SDU.
+    tx_stalled: bool,
+    tx_status: i32,
+    /// Result of a reconfiguration, once it's done.
+    #[cfg(feature = "l2cap-enhanced-coc")]
+    reconfigured: Option<i32>,
+}
+
+impl ChanState {
+    const fn new(epoch: u32) -> Self {
+        Self {
+            in_use: false,
+            epoch,
+            owned: false,
+            chan: core::ptr::null_mut(),
+            conn_handle: 0,
+            listener: None,
+            request: None,
+            connected: false,
+            closed: None,
+            rx: [const { None }; RX_QUEUE_LEN],
+            rx_head: 0,
+            rx_len: 0,
+            rx_starved: false,
+            tx_stalled: false,
+            tx_status: 0,
+            #[cfg(feature = "l2cap-enhanced-coc")]
+            reconfigured: None,
+        }
+    }
+
+    fn push_rx(&mut self, sdu: Mbuf) {
+        if self.rx_len == RX_QUEUE_LEN {
+            warn!("L2CAP receive queue full, dropping an SDU");
+            return;
+        }
+        self.rx[(self.rx_head + self.rx_len) % RX_QUEUE_LEN] = Some(sdu);
+        self.rx_len += 1;
+    }
+
+    fn pop_rx(&mut self) -> Option<Mbuf> {
+        if self.rx_len == 0 {
+            return None;
+        }
+        let sdu = self.rx[self.rx_head].take();
+        self.rx_head = (self.rx_head + 1) % RX_QUEUE_LEN;
+        self.rx_len -= 1;
+        sdu
+    }
+}
+
+struct Chans {
+    states: [ChanState; MAX_CHANNELS],
+    /// Tasks waiting in [`L2capListener::accept`].
+    accept_wakers: MultiWakerRegistration<4>,
+}
+
+// Safety: the channel pointers are only passed to NimBLE, which protects them with the host lock
+unsafe impl Send for Chans {}
+
+static CHANS: Mutex<CriticalSectionRawMutex, RefCell<Chans>> = Mutex::new(RefCell::new(Chans {
+    states: [const { ChanState::new(0) }; MAX_CHANNELS],
+    accept_wakers: MultiWakerRegistration::new(),
+}));
+static RX_WAKERS: [AtomicWaker; MAX_CHANNELS] = [const { AtomicWaker::new() }; MAX_CHANNELS];
+/// Woken when a channel connects, closes, or can send again.
+static TX_WAKERS: [AtomicWaker; MAX_CHANNELS] = [const { AtomicWaker::new() }; MAX_CHANNELS];
+/// Woken when a channel's reconfiguration completes, or it closes.
+#[cfg(feature = "l2cap-enhanced-coc")]
+static RECONFIG_WAKERS: [AtomicWaker; MAX_CHANNELS] = [const { AtomicWaker::new() }; MAX_CHANNELS];
+
+/// Frees a slot, dropping any queued SDUs.
+fn release(chans: &mut Chans, slot: usize) {
+    let epoch = chans.states[slot].epoch.wrapping_add(1);
+    chans.states[slot] = ChanState::new(epoch);
+    RX_WAKERS[slot].wake();
+    TX_WAKERS[slot].wake();
+    #[cfg(feature = "l2cap-enhanced-coc")]
+    RECONFIG_WAKERS[slot].wake();
+}
+
+fn find_chan(chans: &Chans, chan: *mut raw::ble_l2cap_chan) -> Option<usize> {
+    chans.states.iter().position(|s| s.in_use && s.chan == chan)
+}
+
+/// Gives NimBLE a buffer to receive the next SDU of `chan` into. Returns `false` if none could be
+/// allocated.
+fn give_rx_buffer(chan: *mut raw::ble_l2cap_chan) -> bool {
+    let Ok(sdu) = Mbuf::new() else {
+        return false;
+    };
+    let sdu = sdu.into_raw();
+    let rc = unsafe { raw::ble_l2cap_recv_ready(chan, sdu) };
+    if rc != 0 {
+        trace!("L2CAP receive buffer refused: {}", HostError::from(rc));
+        // NimBLE only takes the buffer when it succeeds
+        drop(unsafe { Mbuf::from_raw(sdu) });
+    }
+    rc == 0
+}
+
+/// Closes every channel, after the host was shut down.
+pub(crate) fn reset() {
+    CHANS.lock(|chans| {
+        let mut chans = chans.borrow_mut();
+        for slot in 0..MAX_CHANNELS {
+            if chans.states[slot].in_use {
+                release(&mut chans, slot);
+            }
+        }
+        chans.accept_wakers.wake();
+    });
+}
+
+unsafe extern "C" fn l2cap_event(
+    event: *mut raw::ble_l2cap_event,
+    arg: *mut cty::c_void,
+) -> cty::c_int {
+    let event = &mut *event;
+    match event.type_ as u32 {
+        raw::BLE_L2CAP_EVENT_COC_ACCEPT => {
+            let accept = &event.__bindgen_anon_1.accept;
+            return on_accept(arg as usize as u16, accept.conn_handle, accept.chan);
+        }
+        raw::BLE_L2CAP_EVENT_COC_CONNECTED => {
+            let connect = &event.__bindgen_anon_1.connect;
+            on_connected(
+                arg as usize,
+                connect.conn_handle,
+                connect.chan,
+                connect.status,
+            );
+        }
+        raw::BLE_L2CAP_EVENT_COC_DISCONNECTED => {
+            on_disconnected(event.__bindgen_anon_1.disconnect.chan);
+        }
+        raw::BLE_L2CAP_EVENT_COC_DATA_RECEIVED => {
+            let receive = &event.__bindgen_anon_1.receive;
+            // the SDU is ours now
+            if let Some(sdu) = Mbuf::from_raw(receive.sdu_rx) {
+                on_receive(receive.chan, sdu);
+            }
+        }
+        raw::BLE_L2CAP_EVENT_COC_TX_UNSTALLED => {
+            let unstalled = &event.__bindgen_anon_1.tx_unstalled;
+            on_tx_unstalled(unstalled.chan, unstalled.status);
+        }
+        #[cfg(feature = "l2cap-enhanced-coc")]
+        raw::BLE_L2CAP_EVENT_COC_RECONFIG_COMPLETED => {
+            let reconfigured = &event.__bindgen_anon_1.reconfigured;
+            on_reconfigured(reconfigured.chan, reconfigured.status);
+        }
+        _ => {}
+    }
+    0
+}
+
+/// Takes a channel opened by a peer to a listened PSM, if there's a free slot for it.
+fn on_accept(psm: u16, conn_handle: u16, chan: *mut raw::ble_l2cap_chan) -> cty::c_int {
+    let slot = CHANS.lock(|chans| {
+        let mut chans = chans.borrow_mut();
+        let slot = chans.states.iter().position(|s| !s.in_use)?;
+        let state = &mut chans.states[slot];
+        state.in_use = true;
+        state.chan = chan;
+        state.conn_handle = conn_handle;
+        state.listener = Some(psm);
+        Some(slot)
+    });
+    let Some(slot) = slot else {
+        warn!("no slot for L2CAP channel on PSM {}", psm);
+        return raw::BLE_HS_ENOMEM as cty::c_int;
+    };
+    trace!("L2CAP channel accepted on PSM {}", psm);
+    // NimBLE refuses the channel if it has no receive buffer
+    if !give_rx_buffer(chan) {
+        CHANS.lock(|chans| release(&mut chans.borrow_mut(), slot));
+        return raw::BLE_HS_ENOMEM as cty::c_int;
+    }
+    0
+}
+
+fn on_connected(arg: usize, conn_handle: u16, chan: *mut raw::ble_l2cap_chan, status: i32) {
+    trace!(
+        "L2CAP channel connected: handle {} status {}",
+        conn_handle,
+        status
+    );
+    let orphan = CHANS.lock(|chans| {
+        let mut chans = chans.borrow_mut();
+        let slot = find_chan(&chans, chan).or_else(|| {
+            let request = arg.checked_sub(ARG_CONNECT)?;
+            // the next channel of the request that NimBLE hasn't reported yet
+            chans.states.iter().position(|s| {
+                s.in_use && s.request == Some(request) && !s.connected && s.closed.is_none()
+            })
+        });
+        let Some(slot) = slot else {
+            return (status == 0).then_some(chan);
+        };
+        let state = &mut chans.states[slot];
+        if status == 0 {
+            state.chan = chan;
+            state.connected = true;
+        } else {
+            // NimBLE frees the channel
+            state.chan = core::ptr::null_mut();
+            state.closed = Some(status);
+        }
+        TX_WAKERS[slot].wake();
+        if state.listener.is_some() {
+            chans.accept_wakers.wake();
+            return None;
+        }
+        if !chans.states[slot].owned {
+            // the connect was cancelled
+            if status == 0 {
+                return Some(chan);
+            }
+            release(&mut chans, slot);
+        }
+        None
+    });
+    if let Some(chan) = orphan {
+        unsafe { raw::ble_l2cap_disconnect(chan) };
+    }
+}
+
+fn on_disconnected(chan: *mut raw::ble_l2cap_chan) {
+    CHANS.lock(|chans| {
+        let mut chans = chans.borrow_mut();
+        let Some(slot) = find_chan(&chans, chan) else {
+            return;
+        };
+        trace!(
+            "L2CAP channel disconnected: handle {}",
+            chans.states[slot].conn_handle
+        );
+        let state = &mut chans.states[slot];
+        state.chan = core::ptr::null_mut();
+        state.connected = false;
+        state.closed.get_or_insert(raw::BLE_HS_ENOTCONN as i32);
+        if state.owned {
+            RX_WAKERS[slot].wake();
+            TX_WAKERS[slot].wake();
+            #[cfg(feature = "l2cap-enhanced-coc")]
+            RECONFIG_WAKERS[slot].wake();
+        } else {
+            release(&mut chans, slot);
+        }
+    });
+}
+
+fn on_receive(chan: *mut raw::ble_l2cap_chan, sdu: Mbuf) {
+    let has_room = CHANS.lock(|chans| {
+        let mut chans = chans.borrow_mut();
+        let slot = find_chan(&chans, chan)?;
+        let state = &mut chans.states[slot];
+        state.push_rx(sdu);
+        RX_WAKERS[slot].wake();
+        // hold back the next buffer (and with it the peer's credits) until there's room
+        state.rx_starved = state.rx_len == RX_QUEUE_LEN;
+        Some((slot, !state.rx_starved))
+    });
+    if let Some((slot, true)) = has_room {
+        if !give_rx_buffer(chan) {
+            CHANS.lock(|chans| chans.borrow_mut().states[slot].rx_starved = true);
+        }
+    }
+}
+
+fn on_tx_unstalled(chan: *mut raw::ble_l2cap_chan, status: i32) {
+    CHANS.lock(|chans| {
+        let mut chans = chans.borrow_mut();
+        if let Some(slot) = find_chan(&chans, chan) {
+            let state = &mut chans.states[slot];
+            state.tx_stalled = false;
+            state.tx_status = status;
+            TX_WAKERS[slot].wake();
+        }
+    });
+}
+
+#[cfg(feature = "l2cap-enhanced-coc")]
+fn on_reconfigured(chan: *mut raw::ble_l2cap_chan, status: i32) {
+    CHANS.lock(|chans| {
+        let mut chans = chans.borrow_mut();
+        if let Some(slot) = find_chan(&chans, chan) {
+            chans.states[slot].reconfigured = Some(status);
+            RECONFIG_WAKERS[slot].wake();
+        }
+    });
+}
+
+/// Reserves `N` slots for a connect request on `conn_handle`.
+fn reserve<const N: usize>(conn_handle: u16) -> Result<ConnectGuard<N>, HostError> {
+    CHANS.lock(|chans| {
+        let mut chans = chans.borrow_mut();
+        let mut slots = [0; N];
+        let mut free = chans.states.iter().enumerate().filter(|(_, s)| !s.in_use);
+        for slot in slots.iter_mut() {
+            *slot = free.next().ok_or(HostError::NoMem)?.0;
+        }
+        let mut epochs = [0; N];
+        for (&slot, epoch) in slots.iter().zip(epochs.iter_mut()) {
+            let state = &mut chans.states[slot];
+            state.in_use = true;
+            state.owned = true;
+            state.conn_handle = conn_handle;
+            state.request = Some(slots[0]);
+            *epoch = state.epoch;
+        }
+        Ok(ConnectGuard {
+            slots,
+            epochs,
+            started: false,
+        })
+    })
+}
+
+/// Gives up the slots of a connect request that didn't complete: the ones that NimBLE is still
+/// connecting are released once it's done.
+struct ConnectGuard<const N: usize> {
+    slots: [usize; N],
+    epochs: [u32; N],
+    /// Whether NimBLE accepted the request (and will report each channel).
+    started: bool,
+}
+
+impl<const N: usize> ConnectGuard<N> {
+    /// Hands the slots over to the caller.
+    fn finish(self) {
+        core::mem::forget(self);
+    }
+
+    /// Releases the slots, or leaves the ones NimBLE is still connecting to be released once it's
+    /// done. Returns the channels that connected, which need to be disconnected.
+    fn abandon(&self) -> [*mut raw::ble_l2cap_chan; N] {
+        let mut orphans = [core::ptr::null_mut(); N];
+        CHANS.lock(|chans| {
+            let mut chans = chans.borrow_mut();
+            for (i, &slot) in self.slots.iter().enumerate() {
+                let state = &mut chans.states[slot];
+                if state.epoch != self.epochs[i] {
+                    continue;
+                }
+                if state.connected {
+                    orphans[i] = state.chan;
+                }
+                if !self.started || state.connected || state.closed.is_some() {
+                    release(&mut chans, slot);
+                } else {
+                    state.owned = false;
+                }
+            }
+        });
+        orphans
+    }
+}
+
+impl<const N: usize> Drop for ConnectGuard<N> {
+    fn drop(&mut self) {
+        for chan in self.abandon().into_iter().filter(|c| !c.is_null()) {
+            unsafe { raw::ble_l2cap_disconnect(chan) };
+        }
+    }
+}
+
+/// Waits for the channels of a connect request, once NimBLE has started it.
+async fn connected<const N: usize>(
+    mut guard: ConnectGuard<N>,
+    generation: u32,
+) -> [Result<L2capChannel, HostError>; N] {
+    guard.started = true;
+    poll_fn(|cx| {
+        CHANS.lock(|chans| {
+            let chans = chans.borrow();
+            let mut done = true;
+            for (&slot, &epoch) in guard.slots.iter().zip(guard.epochs.iter()) {
+                TX_WAKERS[slot].register(cx.waker());
+                let state = &chans.states[slot];
+                done &= state.epoch != epoch || state.connected || state.closed.is_some();
+            }
+            if done {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+    })
+    .await;
+
+    let results = CHANS.lock(|chans| {
+        let mut chans = chans.borrow_mut();
+        core::array::from_fn(|i| {
+            let slot = guard.slots[i];
+            let state = &mut chans.states[slot];
+            if state.epoch != guard.epochs[i] {
+                // the host was shut down
+                return Err(HostError::Disabled);
+            }
+            if state.connected {
+                state.request = None;
+                return Ok(L2capChannel {
+                    slot,
+                    epoch: state.epoch,
+                    conn_handle: state.conn_handle,
+                    generation,
+                });
+            }
+            let status = state.closed.unwrap_or(raw::BLE_HS_ENOTCONN as i32);
+            release(&mut chans, slot);
+            Err(HostError::from(status))
+        })
+    });
+    guard.finish();
+    results
+}
+
+/// Parameters of an open channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct L2capChannelInfo {
+    pub psm: u16,
+    /// Source channel ID (ours).
+    pub scid: u16,
+    /// Destination channel ID (the peer's).
+    pub dcid: u16,
+    /// Largest SDU we can receive.
+    pub mtu: u16,
+    /// Largest SDU the peer can receive.
+    pub peer_mtu: u16,
+    /// Largest PDU we can receive (SDUs are split into PDUs).
+    pub mps: u16,
+    /// Largest PDU the peer can receive.
+    pub peer_mps: u16,
+}
+
+/// An L2CAP connection-oriented channel, opened with [`L2capChannel::connect`] or accepted by an
+/// [`L2capListener`]. Dropping it disconnects the channel.
+///
+/// Data is exchanged in SDUs of up to the receiver's MTU, which NimBLE splits into PDUs, sending
+/// them as long as the peer gives credits. Received SDUs are queued (see [`RX_QUEUE_LEN`]); while
+/// the queue is full, the peer doesn't get any more credits.
+pub struct L2capChannel {
+    slot: usize,
+    epoch: u32,
+    conn_handle: u16,
+    generation: u32,
+}
+
+impl L2capChannel {
+    /// Opens a channel to `psm` on the peer. `mtu` is the largest SDU we accept.
+    pub async fn connect(conn: &Connection, psm: u16, mtu: u16) -> Result<Self, HostError> {
+        conn.check_connected()?;
+        let handle = conn.handle();
+        let guard = reserve::<1>(handle)?;
+        let slot = guard.slots[0];
+        let sdu = Mbuf::new()?;
+        // NimBLE takes ownership of the receive buffer, even if this fails
+        let rc = unsafe {
+            raw::ble_l2cap_connect(
+                handle,
+                psm,
+                mtu,
+                sdu.into_raw(),
+                Some(l2cap_event),
+                (ARG_CONNECT | slot) as *mut cty::c_void,
+            )
+        };
+        check(rc)?;
+        let [result] = connected(guard, conn.generation()).await;
+        result
+    }
+
+    /// Opens `N` channels to `psm` on the peer at once, with the enhanced credit based flow
+    /// control mode of Bluetooth 5.2. `mtu` is the largest SDU we accept on each of them.
+    ///
+    /// The peer may refuse some of the channels: each one has its own result.
+    #[cfg(feature = "l2cap-enhanced-coc")]
+    pub async fn connect_enhanced<const N: usize>(
+        conn: &Connection,
+        psm: u16,
+        mtu: u16,
+    ) -> Result<[Result<Self, HostError>; N], HostError> {
+        // `BLE_L2CAP_COC_MAX_NUM` can be configured below `MAX_ENHANCED_CHANNELS`
+        const { assert!(N > 0 && N <= MAX_CHANNELS && N <= MAX_ENHANCED_CHANNELS) };
+        conn.check_connected()?;
+        let handle = conn.handle();
+        let guard = reserve::<N>(handle)?;
+        let first = guard.slots[0];
+        let mut sdus = [core::ptr::null_mut(); N];
+        for sdu in sdus.iter_mut() {
+            match Mbuf::new() {
+                Ok(om) => *sdu = om.into_raw(),
+                Err(e) => {
+                    for sdu in sdus.into_iter().filter(|s| !s.is_null()) {
+                        drop(unsafe { Mbuf::from_raw(sdu) });
+                    }
+                    return Err(e.into());
+                }
+            }
+        }
+        // NimBLE takes ownership of the receive buffers, even if this fails
+        let rc = unsafe {
+            raw::ble_l2cap_enhanced_connect(
+                handle,
+                psm,
+                mtu,
+                N as u8,
+                sdus.as_mut_ptr(),
+                Some(l2cap_event),
+                (ARG_CONNECT | first) as *mut cty::c_void,
+            )
+        };
+        check(rc)?;
+        Ok(connected(guard, conn.generation()).await)
+    }
+
+    /// Handle of the connection the channel belongs to.
+    pub fn conn_handle(&self) -> u16 {
+        self.conn_handle
+    }
+
+    /// Runs `f` on the state of the channel, or fails if the host was shut down since it was
+    /// opened.
+    fn with_state<R>(&self, f: impl FnOnce(&mut ChanState) -> R) -> Result<R, HostError> {
+        check_current(self.generation)?;
+        CHANS.lock(|chans| {
+            let mut chans = chans.borrow_mut();
+            let state = &mut chans.states[self.slot];
+            if state.epoch == self.epoch {
+                Ok(f(state))
+            } else {
+                Err(HostError::Disabled)
+            }
+        })
+    }
+
+    /// NimBLE's channel, or [`HostError::NotConnected`] once it's closed.
+    fn chan(&self) -> Result<*mut raw::ble_l2cap_chan, HostError> {
+        let chan = self.with_state(|state| state.chan)?;
+        if chan.is_null() {
+            Err(HostError::NotConnected)
+        } else {
+            Ok(chan)
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.chan().is_ok()
+    }
+
+    /// Reads the channel's IDs, MTUs and MPSs.
+    pub fn info(&self) -> Result<L2capChannelInfo, HostError> {
+        let chan = self.chan()?;
+        let mut info = MaybeUninit::<raw::ble_l2cap_chan_info>::uninit();
+        check(unsafe { raw::ble_l2cap_get_chan_info(chan, info.as_mut_ptr()) })?;
+        let info = unsafe { info.assume_init() };
+        Ok(L2capChannelInfo {
+            psm: info.psm,
+            scid: info.scid,
+            dcid: info.dcid,
+            mtu: info.our_coc_mtu,
+            peer_mtu: info.peer_coc_mtu,
+            mps: info.our_l2cap_mtu,
+            peer_mps: info.peer_l2cap_mtu,
+        })
+    }
+
+    /// Sends an SDU, which can't be longer than the peer's MTU. Completes once NimBLE has queued
+    /// all of it, which may take a while if the peer runs out of credits.
+    ///
+    /// Only one task can send at a time.
+    pub async fn send(&self, sdu: Mbuf) -> Result<(), HostError> {
+        let chan = self.chan()?;
+        if sdu.len() > self.info()?.peer_mtu as usize {
+            return Err(HostError::MessageTooLong);
+        }
+        self.with_state(|state| state.tx_stalled = true)?;
+        let sdu = sdu.into_raw();
+        let rc = unsafe { raw::ble_l2cap_send(chan, sdu) };
+        match rc as u32 {
+            0 => {}
+            raw::BLE_HS_ESTALLED => {
+                // the rest goes out as the peer gives credits
+                let status = poll_fn(|cx| {
+                    TX_WAKERS[self.slot].register(cx.waker());
+                    match self.with_state(|state| {
+                        if state.closed.is_some() {
+                            Some(Err(HostError::NotConnected))
+                        } else if state.tx_stalled {
+                            None
+                        } else {
+                            Some(check(state.tx_status))
+                        }
+                    }) {
+                        Ok(Some(result)) => Poll::Ready(result),
+                        Ok(None) => Poll::Pending,
+                        Err(e) => Poll::Ready(Err(e)),
+                    }
+                })
+                .await;
+                return status;
+            }
+            // NimBLE only refuses the SDU before queuing it: it's busy sending another one, or
+            // the arguments are invalid. After that, it frees the SDU if sending fails.
+            raw::BLE_HS_EBUSY | raw::BLE_HS_EBADDATA | raw::BLE_HS_EINVAL | raw::BLE_HS_ENOTSUP => {
+                drop(unsafe { Mbuf::from_raw(sdu) });
+            }
+            _ => {}
+        }
+        self.with_state(|state| state.tx_stalled = false)?;
+        check(rc)
+    }
+
+    /// Copies `data` into an SDU, and sends it like [`L2capChannel::send`].
+    pub async fn send_slice(&self, data: &[u8]) -> Result<(), HostError> {
+        self.send(Mbuf::from_slice(data)?).await
+    }
+
+    /// Waits for the next SDU from the peer. Fails with [`HostError::NotConnected`] once the
+    /// channel is closed and every received SDU was returned.
+    ///
+    /// Only one task can receive at a time.
+    pub async fn receive(&self) -> Result<Mbuf, HostError> {
+        let (sdu, starved) = poll_fn(|cx| {
+            RX_WAKERS[self.slot].register(cx.waker());
+            let polled = self.with_state(|state| match state.pop_rx() {
+                Some(sdu) => {
+                    let starved = core::mem::take(&mut state.rx_starved);
+                    Some(Ok((sdu, starved.then_some(state.chan))))
+                }
+                None if state.closed.is_some() => Some(Err(HostError::NotConnected)),
+                None => None,
+            });
+            match polled {
+                Ok(Some(result)) => Poll::Ready(result),
+                Ok(None) => Poll::Pending,
+                Err(e) => Poll::Ready(Err(e)),
+            }
+        })
+        .await?;
+        // there's room in the queue again, so let the peer send more
+        if let Some(chan) = starved.filter(|c| !c.is_null()) {
+            if !give_rx_buffer(chan) {
+                self.with_state(|state| state.rx_starved = true)?;
+            }
+        }
+        Ok(sdu)
+    }
+
+    /// Starts disconnecting the channel. [`L2capChannel::receive`] fails once it's done.
+    pub fn disconnect(&self) -> Result<(), HostError> {
+        check(unsafe { raw::ble_l2cap_disconnect(self.chan()?) })
+    }
+
+    /// Changes the MTU of channels opened with the enhanced mode, on the same connection, and
+    /// waits for the peer to accept it. The MTU can only grow.
+    #[cfg(feature = "l2cap-enhanced-coc")]
+    pub async fn reconfigure(channels: &[&L2capChannel], mtu: u16) -> Result<(), HostError> {
+        let mut chans = [core::ptr::null_mut(); MAX_ENHANCED_CHANNELS];
+        if channels.is_empty() || channels.len() > MAX_ENHANCED_CHANNELS {
+            return Err(HostError::Invalid);
+        }
+        for (chan, channel) in chans.iter_mut().zip(channels) {
+            *chan = channel.chan()?;
+            channel.with_state(|state| state.reconfigured = None)?;
+        }
+        check(unsafe { raw::ble_l2cap_reconfig(chans.as_mut_ptr(), channels.len() as u8, mtu) })?;
+
+        for channel in channels {
+            let status = poll_fn(|cx| {
+                RECONFIG_WAKERS[channel.slot].register(cx.waker());
+                match channel.with_state(|state| {
+                    if state.closed.is_some() {
+                        Some(raw::BLE_HS_ENOTCONN as i32)
+                    } else {
+                        state.reconfigured
+                    }
+                }) {
+                    Ok(Some(status)) => Poll::Ready(check(status)),
+                    Ok(None) => Poll::Pending,
+                    Err(e) => Poll::Ready(Err(e)),
+                }
+            })
+            .await;
+            status?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for L2capChannel {
+    fn drop(&mut self) {
+        if !is_current(self.generation) {
+            return;
+        }
+        let chan = CHANS.lock(|chans| {
+            let mut chans = chans.borrow_mut();
+            let state = &mut chans.states[self.slot];
+            if state.epoch != self.epoch {
+                return None;
+            }
+            if state.chan.is_null() {
+                release(&mut chans, self.slot);
+                return None;
+            }
+            // released once NimBLE reports the disconnection
+            state.owned = false;
+            Some(state.chan)
+        });
+        if let Some(chan) = chan {
+            unsafe { raw::ble_l2cap_disconnect(chan) };
+        }
+    }
+}
+
+impl core::fmt::Debug for L2capChannel {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("L2capChannel")
+            .field("conn_handle", &self.conn_handle)
+            .finish()
+    }
+}
+
+impl defmt::Format for L2capChannel {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "L2capChannel {{ conn_handle: {} }}", self.conn_handle)
+    }
+}
+
+/// Accepts the channels that peers open to a PSM, from [`NimbleHost::l2cap_listen`]. NimBLE can't
+/// stop listening, so the PSM is listened to until [`crate::Nimble::shutdown`].
+///
+/// Channels are accepted as long as there are free slots (see [`MAX_CHANNELS`]), and wait for
+/// [`L2capListener::accept`] to be handed out.
+pub struct L2capListener {
+    psm: u16,
+    generation: u32,
+}
+
+impl L2capListener {
+    pub fn psm(&self) -> u16 {
+        self.psm
+    }
+
+    /// Waits for a peer to open a channel.
+    pub async fn accept(&self) -> Result<L2capChannel, HostError> {
+        poll_fn(|cx| {
+            if let Err(e) = check_current(self.generation) {
+                return Poll::Ready(Err(e));
+            }
+            CHANS.lock(|chans| {
+                let mut chans = chans.borrow_mut();
+                for slot in 0..MAX_CHANNELS {
+                    let state = &mut chans.states[slot];
+                    if !state.in_use || state.listener != Some(self.psm) {
+                        continue;
+                    }
+                    if state.connected {
+                        state.listener = None;
+                        state.owned = true;
+                        return Poll::Ready(Ok(L2capChannel {
+                            slot,
+                            epoch: state.epoch,
+                            conn_handle: state.conn_handle,
+                            generation: self.generation,
+                        }));
+                    }
+                    if state.closed.is_some() {
+                        release(&mut chans, slot);
+                    }
+                }
+                chans.accept_wakers.register(cx.waker());
+                Poll::Pending
+            })
+        })
+        .await
+    }
+}
+
+impl NimbleHost {
+    /// Listens for L2CAP channels opened by peers to `psm`. `mtu` is the largest SDU we accept on
+    /// them.
+    pub fn l2cap_listen(&self, psm: u16, mtu: u16) -> Result<L2capListener, HostError> {
+        check_current(self.generation)?;
+        check(unsafe {
+            raw::ble_l2cap_create_server(
+                psm,
+                mtu,
+                Some(l2cap_event),
+                psm as usize as *mut cty::c_void,
+            )
+        })?;
+        Ok(L2capListener {
+            psm,
+            generation: self.generation,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::{block_on, poll_once};
+
+    use super::*;
+    use crate::test_support::lock_stack;
+    use crate::{Config, MbufPool, Nimble};
+
+    type Pool = MbufPool<64, 8>;
+
+    const CONN_HANDLE: u16 = 1;
+    const PSM: u16 = 0x80;
+
+    /// Stands in for NimBLE's channels, which are only compared, never dereferenced.
+    fn fake_chan(n: usize) -> *mut raw::ble_l2cap_chan {
+        (0x1000 + n * 0x100) as *mut raw::ble_l2cap_chan
+    }
+
+    /// Takes a slot for a channel opened by a peer, like `on_accept` without the receive buffer.
+    fn accepted(slot: usize, chan: *mut raw::ble_l2cap_chan) {
+        with_slot(slot, |s| {
+            assert!(!s.in_use);
+            s.in_use = true;
+            s.chan = chan;
+            s.conn_handle = CONN_HANDLE;
+            s.listener = Some(PSM);
+        });
+    }
+
+    fn with_slot<R>(slot: usize, f: impl FnOnce(&mut ChanState) -> R) -> R {
+        CHANS.lock(|chans| f(&mut chans.borrow_mut().states[slot]))
+    }
+
+    fn in_use() -> Vec<usize> {
+        CHANS.lock(|chans| {
+            let chans = chans.borrow();
+            (0..MAX_CHANNELS)
+                .filter(|&slot| chans.states[slot].in_use)
+                .collect()
+        })
+    }
+
+    fn first_byte(sdu: Option<Mbuf>) -> Option<u8> {
+        sdu.map(|sdu| sdu.first_segment()[0])
+    }
+
+    #[test]
+    fn rx_queue_wraps_around() {
+        static POOL: Pool = Pool::new(c"l2cap-rx");
+        let mut state = ChanState::new(0);
+        assert_eq!(first_byte(state.pop_rx()), None);
+
+        state.push_rx(POOL.alloc_from_slice(&[1]).unwrap());
+        state.push_rx(POOL.alloc_from_slice(&[2]).unwrap());
+        assert_eq!(first_byte(state.pop_rx()), Some(1));
+        // goes into the slot that 1 left
+        state.push_rx(POOL.alloc_from_slice(&[3]).unwrap());
+        assert_eq!(state.rx_head, 1);
+        assert_eq!(first_byte(state.pop_rx()), Some(2));
+        assert_eq!(first_byte(state.pop_rx()), Some(3));
+        assert_eq!(first_byte(state.pop_rx()), None);
+        assert_eq!(state.rx_len, 0);
+    }
+
+    #[test]
+    fn rx_queue_drops_when_full() {
+        static POOL: Pool = Pool::new(c"l2cap-rx-full");
+        let mut state = ChanState::new(0);
+        for byte in 1..=RX_QUEUE_LEN as u8 + 1 {
+            state.push_rx(POOL.alloc_from_slice(&[byte]).unwrap());
+        }
+        // the last one was dropped
+        assert_eq!(state.rx_len, RX_QUEUE_LEN);
+        for byte in 1..=RX_QUEUE_LEN as u8 {
+            assert_eq!(first_byte(state.pop_rx()), Some(byte));
+        }
+        assert_eq!(first_byte(state.pop_rx()), None);
+    }
+
+    #[test]
+    fn abandoning_an_unstarted_connect_releases_its_slots() {
+        let _stack = lock_stack();
+        reset();
+        let guard = reserve::<2>(CONN_HANDLE).unwrap();
+        assert_eq!(in_use(), guard.slots);
+        assert_eq!(reserve::<1>(CONN_HANDLE).err(), Some(HostError::NoMem));
+
+        let epochs = guard.epochs;
+        assert_eq!(guard.abandon(), [core::ptr::null_mut(); 2]);
+        guard.finish();
+        assert!(in_use().is_empty());
+        assert_eq!(with_slot(0, |s| s.epoch), epochs[0].wrapping_add(1));
+    }
+
+    #[test]
+    fn abandoning_a_started_connect() {
+        let _stack = lock_stack();
+        reset();
+        let mut guard = reserve::<2>(CONN_HANDLE).unwrap();
+        guard.started = true;
+        let [first, second] = guard.slots;
+        on_connected(ARG_CONNECT | first, CONN_HANDLE, fake_chan(0), 0);
+        assert!(with_slot(first, |s| s.connected));
+
+        // the connected channel is released and needs disconnecting, while the pending one stays
+        // with NimBLE until it reports it
+        assert_eq!(guard.abandon(), [fake_chan(0), core::ptr::null_mut()]);
+        guard.finish();
+        assert_eq!(in_use(), [second]);
+        assert!(with_slot(second, |s| !s.owned));
+        on_connected(ARG_CONNECT | first, CONN_HANDLE, fake_chan(1), 1);
+        assert!(in_use().is_empty());
+    }
+
+    #[test]
+    fn abandoning_a_started_connect_after_it_failed() {
+        let _stack = lock_stack();
+        reset();
+        let mut guard = reserve::<1>(CONN_HANDLE).unwrap();
+        guard.started = true;
+        let [slot] = guard.slots;
+        on_connected(ARG_CONNECT | slot, CONN_HANDLE, fake_chan(0), 1);
+        assert_eq!(with_slot(slot, |s| s.closed), Some(1));
+
+        assert_eq!(guard.abandon(), [core::ptr::null_mut()]);
+        guard.finish();
+        assert!(in_use().is_empty());
+    }
+
+    #[test]
+    fn abandoning_skips_slots_released_since() {
+        let _stack = lock_stack();
+        reset();
+        let guard = reserve::<1>(CONN_HANDLE).unwrap();
+        // the host was shut down, and the slot reused
+        reset();
+        let other = reserve::<1>(CONN_HANDLE).unwrap();
+        assert_eq!(other.slots, guard.slots);
+
+        guard.abandon();
+        guard.finish();
+        assert_eq!(in_use(), other.slots);
+        other.abandon();
+        other.finish();
+    }
+
+    #[test]
+    fn connected_fills_the_next_unreported_slot() {
+        let _stack = lock_stack();
+        reset();
+        let guard = reserve::<2>(CONN_HANDLE).unwrap();
+        let [first, second] = guard.slots;
+
+        // the peer refused the first channel
+        on_connected(ARG_CONNECT | first, CONN_HANDLE, fake_chan(0), 1);
+        on_connected(ARG_CONNECT | first, CONN_HANDLE, fake_chan(1), 0);
+        assert_eq!(
+            with_slot(first, |s| (s.connected, s.closed)),
+            (false, Some(1))
+        );
+        assert!(with_slot(first, |s| s.chan.is_null()));
+        assert_eq!(with_slot(second, |s| s.chan), fake_chan(1));
+        assert!(with_slot(second, |s| s.connected));
+
+        // a channel NimBLE already reported is found by its pointer
+        on_connected(ARG_CONNECT | first, CONN_HANDLE, fake_chan(1), 0);
+        assert_eq!(with_slot(first, |s| s.closed), Some(1));
+
+        let [refused, accepted] = block_on(connected(guard, 0));
+        assert_eq!(refused.err(), Some(HostError::from(1)));
+        let accepted = accepted.unwrap();
+        assert_eq!(accepted.slot, second);
+        assert_eq!(in_use(), [second]);
+        // the channel belongs to a shut down host, so dropping it doesn't disconnect
+        drop(accepted);
+        reset();
+    }
+
+    #[test]
+    fn accept_reaps_closed_channels() {
+        let _stack = lock_stack();
+        let nimble = Nimble::init(Config::default()).unwrap();
+        reset();
+        let listener = L2capListener {
+            psm: PSM,
+            generation: nimble.host.generation,
+        };
+
+        // peers opened channels that failed to connect before they were accepted
+        accepted(0, fake_chan(0));
+        on_connected(PSM as usize, CONN_HANDLE, fake_chan(0), 1);
+        accepted(1, fake_chan(1));
+        on_connected(PSM as usize, CONN_HANDLE, fake_chan(1), 1);
+        assert_eq!(in_use(), [0, 1]);
+
+        assert!(poll_once(listener.accept()).is_pending());
+        assert!(in_use().is_empty());
+
+        // an open channel is handed out
+        accepted(0, fake_chan(2));
+        on_connected(PSM as usize, CONN_HANDLE, fake_chan(2), 0);
+        let Poll::Ready(Ok(channel)) = poll_once(listener.accept()) else {
+            panic!("no channel accepted");
+        };
+        assert_eq!(channel.slot, 0);
+        assert!(with_slot(0, |s| s.owned && s.listener.is_none()));
+        // dropping it would disconnect the fake channel
+        core::mem::forget(channel);
+
+        block_on(Nimble::shutdown()).unwrap();
+        assert!(in_use().is_empty());
+    }
+}
@@ -0,0 +1,318 @@
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::Poll;
+
+use defmt::trace;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embassy_time::Duration;
+
+use super::ad::AdStructures;
+use super::{
+    check, check_current, gap_event, own_addr_type, scan_interval, Address, HostError, NimbleHost,
+    OwnAddressKind,
+};
+use crate::{is_current, raw, Phy};
+
+/// Maximum amount of data in a single advertising report (what fits in one HCI LE Extended
+/// Advertising Report event). Longer extended advertising data is reported in several fragments.
+pub const MAX_REPORT_DATA_LEN: usize = 229;
+
+/// Number of reports that can be queued while waiting for [`Scanner::next`]. Reports that arrive
+/// while the queue is full are dropped.
+pub const SCAN_QUEUE_LEN: usize = 8;
+
+/// Which advertisements the controller reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ScanFilterPolicy {
+    /// Every advertisement, except directed ones that aren't addressed to this device.
+    Unfiltered,
+    /// Only advertisements from devices in the filter accept list.
+    AcceptList,
+    /// Like [`ScanFilterPolicy::Unfiltered`], but also directed advertisements to resolvable
+    /// private addresses that can't be resolved yet.
+    UnfilteredResolvable,
+    /// Like [`ScanFilterPolicy::AcceptList`], but also directed advertisements to resolvable
+    /// private addresses that can't be resolved yet.
+    AcceptListResolvable,
+}
+
+/// Parameters of a scan.
+#[derive(Debug, Clone)]
+pub struct ScanParams {
+    /// Send scan requests, to receive scan responses as well as advertisements.
+    pub active: bool,
+    pub interval: Duration,
+    /// How long to listen in each interval. Must not be longer than `interval`.
+    pub window: Duration,
+    pub filter_policy: ScanFilterPolicy,
+    /// Let the controller report each advertiser only once (per scan).
+    pub filter_duplicates: bool,
+    /// Only report devices in the limited discoverable mode.
+    pub limited: bool,
+    /// `None` picks the best available address.
+    pub own_address: Option<OwnAddressKind>,
+    /// Stop scanning after this long. `None` scans until the [`Scanner`] is dropped.
+    pub duration: Option<Duration>,
+    /// Scan on the LE Coded PHY as well as the LE 1M PHY.
+    #[cfg(feature = "phy-coded")]
+    pub coded: bool,
+}
+
+impl Default for ScanParams {
+    fn default() -> Self {
+        Self {
+            active: false,
+            interval: Duration::from_millis(60),
+            window: Duration::from_millis(30),
+            filter_policy: ScanFilterPolicy::Unfiltered,
+            filter_duplicates: false,
+            limited: false,
+            own_address: None,
+            duration: None,
+            #[cfg(feature = "phy-coded")]
+            coded: false,
+        }
+    }
+}
+
+/// An advertisement or scan response, received while scanning.
+#[derive(Clone)]
+pub struct ScanReport {
+    pub address: Address,
+    /// Signal strength, in dBm.
+    pub rssi: i8,
+    /// Transmit power reported by the advertiser, in dBm.
+    pub tx_power: Option<i8>,
+    pub primary_phy: Phy,
+    /// PHY of the auxiliary packets, for extended advertisements.
+    pub secondary_phy: Option<Phy>,
+    /// Advertising SID, for extended advertisements.
+    pub sid: Option<u8>,
+    pub connectable: bool,
+    pub scannable: bool,
+    pub directed: bool,
+    pub scan_response: bool,
+    /// Whether this was sent with legacy advertising PDUs.
+    pub legacy: bool,
+    /// Whether the data is complete. If it's not, more fragments follow in the next reports (or
+    /// the controller failed to receive them).
+    pub complete: bool,
+    data: [u8; MAX_REPORT_DATA_LEN],
+    len: u8,
+}
+
+impl ScanReport {
+    /// The advertising data (or scan response data), as received.
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+
+    /// Parses the AD structures in the data.
+    pub fn ad_structures(&self) -> AdStructures<'_> {
+        AdStructures::new(self.data())
+    }
+
+    fn from_raw(desc: &raw::ble_gap_ext_disc_desc) -> Self {
+        let props = desc.props as u32;
+        let len = (desc.length_data as usize).min(MAX_REPORT_DATA_LEN);
+        let mut data = [0; MAX_REPORT_DATA_LEN];
+        if len > 0 {
+            data[..len].copy_from_slice(unsafe { core::slice::from_raw_parts(desc.data, len) });
+        }
+
+        Self {
+            address: Address::from_raw(&desc.addr),
+            rssi: desc.rssi,
+            // 127 means "not available"
+            tx_power: (desc.tx_power != 127).then_some(desc.tx_power),
+            primary_phy: Phy::from_raw(desc.prim_phy).unwrap_or(Phy::Le1M),
+            secondary_phy: Phy::from_raw(desc.sec_phy),
+            sid: (desc.sid != 0xff).then_some(desc.sid),
+            connectable: props & raw::BLE_HCI_ADV_CONN_MASK != 0,
+            scannable: props & raw::BLE_HCI_ADV_SCAN_MASK != 0,
+            directed: props & raw::BLE_HCI_ADV_DIRECT_MASK != 0,
+            scan_response: props & raw::BLE_HCI_ADV_SCAN_RSP_MASK != 0,
+            legacy: props & raw::BLE_HCI_ADV_LEGACY_MASK != 0,
+            complete: desc.data_status as u32 == raw::BLE_GAP_EXT_ADV_DATA_STATUS_COMPLETE,
+            data,
+            len: len as u8,
+        }
+    }
+}
+
+impl core::fmt::Debug for ScanReport {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ScanReport")
+            .field("address", &self.address)
+            .field("rssi", &self.rssi)
+            .field("tx_power", &self.tx_power)
+            .field("primary_phy", &self.primary_phy)
+            .field("secondary_phy", &self.secondary_phy)
+            .field("sid", &self.sid)
+            .field("connectable", &self.connectable)
+            .field("scannable", &self.scannable)
+            .field("directed", &self.directed)
+            .field("scan_response", &self.scan_response)
+            .field("legacy", &self.legacy)
+            .field("complete", &self.complete)
+            .field("data", &self.data())
+            .finish()
+    }
+}
+
+impl defmt::Format for ScanReport {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "ScanReport {{ address: {}, rssi: {}, connectable: {}, scan_response: {}, data: {=[u8]} }}",
+            self.address,
+            self.rssi,
+            self.connectable,
+            self.scan_response,
+            self.data()
+        )
+    }
+}
+
+static REPORTS: Channel<CriticalSectionRawMutex, ScanReport, SCAN_QUEUE_LEN> = Channel::new();
+/// Reason the scan ended (a NimBLE host error code, or 0 when its duration elapsed).
+static COMPLETE: Signal<CriticalSectionRawMutex, i32> = Signal::new();
+/// Whether a [`Scanner`] exists. NimBLE only runs one scan at a time.
+static IN_USE: AtomicBool = AtomicBool::new(false);
+
+/// Handles `BLE_GAP_EVENT_EXT_DISC`.
+pub(crate) fn on_report(desc: &raw::ble_gap_ext_disc_desc) {
+    if REPORTS.try_send(ScanReport::from_raw(desc)).is_err() {
+        trace!("scan queue full, dropping report");
+    }
+}
+
+/// Handles `BLE_GAP_EVENT_DISC_COMPLETE`.
+pub(crate) fn on_complete(reason: i32) {
+    trace!("scan complete: {}", reason);
+    COMPLETE.signal(reason);
+}
+
+/// Ends the scanner, after the host was shut down.
+pub(crate) fn reset() {
+    if IN_USE.swap(false, Ordering::AcqRel) {
+        COMPLETE.signal(raw::BLE_HS_EDISABLED as i32);
+    }
+}
+
+impl NimbleHost {
+    /// Starts scanning. Reports are read from the returned [`Scanner`], and scanning stops when
+    /// it's dropped.
+    ///
+    /// Only one [`Scanner`] can exist at a time.
+    pub async fn scan(&self, params: &ScanParams) -> Result<Scanner, HostError> {
+        self.wait_for_sync().await?;
+
+        if IN_USE.swap(true, Ordering::AcqRel) {
+            return Err(HostError::Busy);
+        }
+        // from here on, dropping the scanner releases it
+        let scanner = Scanner {
+            generation: self.generation,
+        };
+        while REPORTS.try_receive().is_ok() {}
+        COMPLETE.reset();
+
+        let mut uncoded: raw::ble_gap_ext_disc_params = unsafe { core::mem::zeroed() };
+        uncoded.itvl = scan_interval(params.interval);
+        uncoded.window = scan_interval(params.window);
+        uncoded.set_passive(u8::from(!params.active));
+        #[cfg(feature = "phy-coded")]
+        let coded = uncoded;
+        #[cfg(feature = "phy-coded")]
+        let coded_ptr = if params.coded {
+            &coded as *const _
+        } else {
+            core::ptr::null()
+        };
+        #[cfg(not(feature = "phy-coded"))]
+        let coded_ptr = core::ptr::null();
+
+        let filter_policy = match params.filter_policy {
+            ScanFilterPolicy::Unfiltered => 0,
+            ScanFilterPolicy::AcceptList => 1,
+            ScanFilterPolicy::UnfilteredResolvable => 2,
+            ScanFilterPolicy::AcceptListResolvable => 3,
+        };
+        // in units of 10 ms, 0 means no limit
+        let duration = params
+            .duration
+            .map_or(0, |d| (d.as_millis() / 10).clamp(1, u16::MAX as u64) as u16);
+
+        let own_addr_type = own_addr_type(params.own_address)?;
+
+        check(unsafe {
+            raw::ble_gap_ext_disc(
+                own_addr_type,
+                duration,
+                0,
+                u8::from(params.filter_duplicates),
+                filter_policy,
+                u8::from(params.limited),
+                &uncoded,
+                coded_ptr,
+                Some(gap_event),
+                core::ptr::null_mut(),
+            )
+        })?;
+        Ok(scanner)
+    }
+}
+
+/// A running scan. Scanning stops when this is dropped.
+pub struct Scanner {
+    generation: u32,
+}
+
+impl Scanner {
+    /// Waits for the next report. Returns `Ok(None)` once the scan duration has elapsed, and
+    /// fails if the scan ended for another reason (e.g. [`HostError::Disabled`] after
+    /// [`crate::Nimble::shutdown`]).
+    ///
+    /// ```ignore
+    /// while let Some(report) = scanner.next().await? {
+    ///     for ad in report.ad_structures() {
+    ///         // ...
+    ///     }
+    /// }
+    /// ```
+    pub async fn next(&mut self) -> Result<Option<ScanReport>, HostError> {
+        check_current(self.generation)?;
+        poll_fn(|cx| {
+            // reports that were queued before the scan ended come first
+            if let Poll::Ready(report) = REPORTS.poll_receive(cx) {
+                return Poll::Ready(Ok(Some(report)));
+            }
+            match COMPLETE.poll_wait(cx) {
+                Poll::Ready(reason) => {
+                    // keep returning the same result
+                    COMPLETE.signal(reason);
+                    Poll::Ready(match reason as u32 {
+                        0 | raw::BLE_HS_ETIMEOUT => Ok(None),
+                        _ => Err(HostError::from(reason)),
+                    })
+                }
+                Poll::Pending => Poll::Pending,
+            }
+        })
+        .await
+    }
+}
+
+impl Drop for Scanner {
+    fn drop(&mut self) {
+        if !is_current(self.generation) {
+            return;
+        }
+        // fails harmlessly if the scan already ended
+        unsafe { raw::ble_gap_disc_cancel() };
+        IN_USE.store(false, Ordering::Release);
+    }
+}
@@ -0,0 +1,339 @@
+#[cfg(feature = "security-sc")]
+use core::cell::RefCell;
+
+use defmt::trace;
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+#[cfg(feature = "security-sc")]
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+
+use super::connection::{Connection, MAX_CONNECTIONS};
+use super::{check, check_current, HostError, NimbleHost};
+use crate::raw;
+
+extern "C" {
+    /// Fills `dst` with random bytes from the controller (HCI LE Rand). Not in NimBLE's public
+    /// headers.
+    fn ble_hs_hci_util_rand(dst: *mut cty::c_void, len: cty::c_int) -> cty::c_int;
+}
+
+/// `BLE_ERR_AUTH_FAIL`
+const AUTHENTICATION_FAILURE: u8 = 0x05;
+
+/// Input and output capabilities of the device, which determine the pairing method.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum IoCapabilities {
+    DisplayOnly,
+    DisplayYesNo,
+    KeyboardOnly,
+    NoInputNoOutput,
+    KeyboardDisplay,
+}
+
+impl IoCapabilities {
+    fn to_raw(self) -> u8 {
+        (match self {
+            IoCapabilities::DisplayOnly => raw::BLE_HS_IO_DISPLAY_ONLY,
+            IoCapabilities::DisplayYesNo => raw::BLE_HS_IO_DISPLAY_YESNO,
+            IoCapabilities::KeyboardOnly => raw::BLE_HS_IO_KEYBOARD_ONLY,
+            IoCapabilities::NoInputNoOutput => raw::BLE_HS_IO_NO_INPUT_OUTPUT,
+            IoCapabilities::KeyboardDisplay => raw::BLE_HS_IO_KEYBOARD_DISPLAY,
+        }) as u8
+    }
+}
+
+/// Pairing parameters, sent to the peer in the pairing request or response.
+#[derive(Debug, Clone, PartialEq, Eq, defmt::Format)]
+pub struct SecurityConfig {
+    pub io_capabilities: IoCapabilities,
+    /// Exchange and store long-term keys (and identity keys), so that later connections can be
+    /// encrypted without pairing again. Needs the `security-bonding` feature.
+    pub bonding: bool,
+    /// Require protection against man-in-the-middle attacks (passkey entry, numeric comparison or
+    /// OOB, rather than "just works").
+    pub mitm: bool,
+    /// Use LE Secure Connections if the peer supports it. Needs the `security-sc` feature.
+    pub secure_connections: bool,
+    /// Whether OOB data was received from the peer (see [`PairingHandler::oob_data`]).
+    pub oob: bool,
+}
+
+impl Default for SecurityConfig {
+    fn default() -> Self {
+        Self {
+            io_capabilities: IoCapabilities::NoInputNoOutput,
+            bonding: cfg!(feature = "security-bonding"),
+            mitm: false,
+            secure_connections: cfg!(feature = "security-sc"),
+            oob: false,
+        }
+    }
+}
+
+/// OOB data for LE Secure Connections: a random number and its confirmation value.
+#[cfg(feature = "security-sc")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct ScOobData {
+    pub random: [u8; 16],
+    pub confirm: [u8; 16],
+}
+
+/// Responds to the user interaction requests of a pairing, for [`Connection::pair`]. Which
+/// methods get called depends on the IO capabilities of both devices.
+///
+/// Methods that return `None` fail the pairing. NimBLE has no way to reject a request, so the
+/// connection is terminated (with "authentication failure") instead.
+#[allow(async_fn_in_trait)]
+pub trait PairingHandler {
+    /// Shows a 6-digit passkey to the user, who enters it on the peer. Returns once it's shown.
+    async fn display_passkey(&mut self, passkey: u32);
+
+    /// Asks the user for the 6-digit passkey displayed by the peer, or returns `None` if there's
+    /// no way to enter it.
+    async fn enter_passkey(&mut self) -> Option<u32>;
+
+    /// Shows a 6-digit number, which is also shown by the peer, and asks the user whether they
+    /// match.
+    async fn confirm_number(&mut self, number: u32) -> bool;
+
+    /// The temporary key for LE legacy OOB pairing, exchanged with the peer out of band, or
+    /// `None` if there's none.
+    async fn oob_data(&mut self) -> Option<[u8; 16]> {
+        None
+    }
+
+    /// The peer's OOB data for LE Secure Connections, received out of band. The local data,
+    /// which the peer needs, comes from [`NimbleHost::generate_sc_oob_data`]. `None` if there's
+    /// none.
+    #[cfg(feature = "security-sc")]
+    async fn sc_oob_data(&mut self) -> Option<ScOobData> {
+        None
+    }
+}
+
+/// A [`PairingHandler`] for devices without input or output ("just works" pairing). It rejects
+/// numeric comparisons and passkey entry, so pairings that need them fail. With
+/// [`IoCapabilities::NoInputNoOutput`], it's never asked for either.
+pub struct JustWorks;
+
+impl PairingHandler for JustWorks {
+    async fn display_passkey(&mut self, _passkey: u32) {}
+
+    async fn enter_passkey(&mut self) -> Option<u32> {
+        None
+    }
+
+    async fn confirm_number(&mut self, _number: u32) -> bool {
+        false
+    }
+}
+
+/// Pairing state of a connection slot, updated from the GAP event callback.
+struct PairingSlot {
+    /// Pending `BLE_GAP_EVENT_PASSKEY_ACTION`: action and number to compare.
+    action: Signal<CriticalSectionRawMutex, (u8, u32)>,
+    /// Result of the pairing (a NimBLE host error code).
+    done: Signal<CriticalSectionRawMutex, i32>,
+    /// The peer's SC OOB data, which NimBLE keeps a pointer to.
+    #[cfg(feature = "security-sc")]
+    remote_oob: Mutex<CriticalSectionRawMutex, RefCell<raw::ble_sm_sc_oob_data>>,
+}
+
+static SLOTS: [PairingSlot; MAX_CONNECTIONS] = [const {
+    PairingSlot {
+        action: Signal::new(),
+        done: Signal::new(),
+        #[cfg(feature = "security-sc")]
+        remote_oob: Mutex::new(RefCell::new(unsafe { core::mem::zeroed() })),
+    }
+}; MAX_CONNECTIONS];
+
+/// Local SC OOB data, from [`NimbleHost::generate_sc_oob_data`]. NimBLE keeps a pointer to it.
+#[cfg(feature = "security-sc")]
+static LOCAL_OOB: Mutex<CriticalSectionRawMutex, RefCell<Option<raw::ble_sm_sc_oob_data>>> =
+    Mutex::new(RefCell::new(None));
+
+/// Forgets the pairing state of a connection slot, when a new connection starts using it.
+pub(crate) fn on_connect(slot: usize) {
+    SLOTS[slot].action.reset();
+    SLOTS[slot].done.reset();
+}
+
+/// Handles `BLE_GAP_EVENT_PASSKEY_ACTION`.
+pub(crate) fn on_passkey_action(slot: usize, action: u8, numcmp: u32) {
+    trace!("passkey action {}", action);
+    SLOTS[slot].action.signal((action, numcmp));
+}
+
+/// Handles `BLE_GAP_EVENT_ENC_CHANGE`, and disconnections during a pairing.
+pub(crate) fn on_done(slot: usize, status: i32) {
+    SLOTS[slot].action.reset();
+    SLOTS[slot].done.signal(status);
+}
+
+/// Handles `BLE_GAP_EVENT_REPEAT_PAIRING`: the peer wants to pair again although it's bonded
+/// (e.g. because it lost its keys). The old bond is deleted, and the pairing goes ahead.
+pub(crate) fn on_repeat_pairing(conn_handle: u16) -> cty::c_int {
+    let mut desc = core::mem::MaybeUninit::<raw::ble_gap_conn_desc>::uninit();
+    if unsafe { raw::ble_gap_conn_find(conn_handle, desc.as_mut_ptr()) } == 0 {
+        let desc = unsafe { desc.assume_init() };
+        trace!("deleting bond of connection {} to pair again", conn_handle);
+        unsafe { raw::ble_store_util_delete_peer(&desc.peer_id_addr) };
+    }
+    raw::BLE_GAP_REPEAT_PAIRING_RETRY as cty::c_int
+}
+
+/// Fails every pairing, after the host was shut down.
+pub(crate) fn reset() {
+    for slot in 0..MAX_CONNECTIONS {
+        on_done(slot, raw::BLE_HS_EDISABLED as i32);
+    }
+}
+
+/// A random 6-digit passkey.
+fn random_passkey() -> Result<u32, HostError> {
+    let mut passkey = 0u32;
+    check(unsafe { ble_hs_hci_util_rand(&mut passkey as *mut u32 as *mut cty::c_void, 4) })?;
+    Ok(passkey % 1_000_000)
+}
+
+impl NimbleHost {
+    /// Sets the parameters used by the following pairings.
+    pub fn set_security(&self, config: &SecurityConfig) -> Result<(), HostError> {
+        check_current(self.generation)?;
+        if (config.bonding && !cfg!(feature = "security-bonding"))
+            || (config.secure_connections && !cfg!(feature = "security-sc"))
+        {
+            return Err(HostError::NotSupported);
+        }
+        let key_dist = if config.bonding {
+            (raw::BLE_SM_PAIR_KEY_DIST_ENC | raw::BLE_SM_PAIR_KEY_DIST_ID) as u8
+        } else {
+            0
+        };
+        unsafe {
+            raw::ble_hs_cfg.sm_io_cap = config.io_capabilities.to_raw();
+            raw::ble_hs_cfg.set_sm_oob_data_flag(config.oob.into());
+            raw::ble_hs_cfg.set_sm_bonding(config.bonding.into());
+            raw::ble_hs_cfg.set_sm_mitm(config.mitm.into());
+            raw::ble_hs_cfg.set_sm_sc(config.secure_connections.into());
+            raw::ble_hs_cfg.sm_our_key_dist = key_dist;
+            raw::ble_hs_cfg.sm_their_key_dist = key_dist;
+        }
+        Ok(())
+    }
+
+    /// Generates the local OOB data for LE Secure Connections, to send to the peer out of band
+    /// before pairing. It stays valid until it's generated again.
+    #[cfg(feature = "security-sc")]
+    pub fn generate_sc_oob_data(&self) -> Result<ScOobData, HostError> {
+        check_current(self.generation)?;
+        let mut data: raw::ble_sm_sc_oob_data = unsafe { core::mem::zeroed() };
+        check(unsafe { raw::ble_sm_sc_oob_generate_data(&mut data) })?;
+        LOCAL_OOB.lock(|oob| *oob.borrow_mut() = Some(data));
+        Ok(ScOobData {
+            random: data.r,
+            confirm: data.c,
+        })
+    }
+}
+
+impl Connection {
+    /// Pairs with the peer (or encrypts the connection with the keys of an existing bond), using
+    /// the parameters from [`NimbleHost::set_security`]. As a central, this sends a pairing
+    /// request; as a peripheral, it sends a security request, which the peer may ignore. If the
+    /// peer already started pairing, this joins it. Returns immediately if the connection is
+    /// already encrypted.
+    ///
+    /// Requests for user interaction are answered by `handler`. If it can't answer one, the
+    /// connection is terminated, and this fails with [`HostError::NotSupported`]. Dropping the
+    /// future doesn't cancel the pairing, but it then times out (after 30 seconds) if it needs user
+    /// interaction.
+    ///
+    /// Only one task can pair a connection at a time.
+    pub async fn pair<H: PairingHandler>(&self, handler: &mut H) -> Result<(), HostError> {
+        if self.info()?.encrypted {
+            return Ok(());
+        }
+        let slot = &SLOTS[self.slot()];
+        slot.done.reset();
+
+        match check(unsafe { raw::ble_gap_security_initiate(self.handle()) }) {
+            Ok(()) | Err(HostError::Already) => {}
+            Err(e) => return Err(e),
+        }
+
+        loop {
+            match select(slot.done.wait(), slot.action.wait()).await {
+                Either::First(status) => {
+                    check_current(self.generation())?;
+                    return check(status);
+                }
+                Either::Second((action, numcmp)) => self.respond(handler, action, numcmp).await?,
+            }
+        }
+    }
+
+    /// Answers a passkey action with the help of `handler`.
+    async fn respond<H: PairingHandler>(
+        &self,
+        handler: &mut H,
+        action: u8,
+        numcmp: u32,
+    ) -> Result<(), HostError> {
+        let mut io: raw::ble_sm_io = unsafe { core::mem::zeroed() };
+        io.action = action;
+        match action as u32 {
+            raw::BLE_SM_IOACT_DISP => {
+                let passkey = random_passkey()?;
+                io.__bindgen_anon_1.passkey = passkey;
+                // the peer can start entering it as soon as it's displayed
+                check(unsafe { raw::ble_sm_inject_io(self.handle(), &mut io) })?;
+                handler.display_passkey(passkey).await;
+                return Ok(());
+            }
+            raw::BLE_SM_IOACT_INPUT => {
+                io.__bindgen_anon_1.passkey =
+                    handler.enter_passkey().await.ok_or_else(|| self.refuse())?;
+            }
+            raw::BLE_SM_IOACT_NUMCMP => {
+                io.__bindgen_anon_1.numcmp_accept = handler.confirm_number(numcmp).await.into();
+            }
+            raw::BLE_SM_IOACT_OOB => {
+                io.__bindgen_anon_1.oob = handler.oob_data().await.ok_or_else(|| self.refuse())?;
+            }
+            #[cfg(feature = "security-sc")]
+            raw::BLE_SM_IOACT_OOB_SC => {
+                let remote = handler.sc_oob_data().await.ok_or_else(|| self.refuse())?;
+                let remote_oob = &SLOTS[self.slot()].remote_oob;
+                remote_oob.lock(|oob| {
+                    *oob.borrow_mut() = raw::ble_sm_sc_oob_data {
+                        r: remote.random,
+                        c: remote.confirm,
+                    }
+                });
+                // NimBLE keeps the pointers until the pairing is done
+                io.__bindgen_anon_1.oob_sc_data.remote = remote_oob.lock(|oob| oob.as_ptr());
+                io.__bindgen_anon_1.oob_sc_data.local =
+                    LOCAL_OOB.lock(|oob| match &mut *oob.borrow_mut() {
+                        Some(data) => data as *mut _,
+                        None => core::ptr::null_mut(),
+                    });
+            }
+            _ => return Ok(()),
+        }
+        check(unsafe { raw::ble_sm_inject_io(self.handle(), &mut io) })
+    }
+
+    /// Fails a pairing whose passkey action the handler can't answer. NimBLE would otherwise wait
+    /// for the answer until the pairing times out.
+    fn refuse(&self) -> HostError {
+        trace!(
+            "terminating connection {} to fail its pairing",
+            self.handle()
+        );
+        unsafe { raw::ble_gap_terminate(self.handle(), AUTHENTICATION_FAILURE) };
+        HostError::NotSupported
+    }
+}
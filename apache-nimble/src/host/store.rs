@@ -0,0 +1,859 @@
+use core::cell::Cell;
+use core::mem::{size_of, MaybeUninit};
+
+use defmt::{trace, warn};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embedded_storage::nor_flash::{
+    check_erase, check_read, check_write, ErrorType, NorFlash, NorFlashError, NorFlashErrorKind,
+    ReadNorFlash,
+};
+
+use super::{check, check_current, Address, HostError, NimbleHost};
+use crate::raw;
+
+/// Maximum number of bonded peers (`BLE_STORE_MAX_BONDS`). When the store is full, the oldest bond
+/// is deleted to make room for a new one.
+pub const MAX_BONDS: usize = raw::MYNEWT_VAL_BLE_STORE_MAX_BONDS as usize;
+
+/// Maximum number of stored CCCD values, across all bonded peers (`BLE_STORE_MAX_CCCDS`).
+pub const MAX_CCCDS: usize = raw::MYNEWT_VAL_BLE_STORE_MAX_CCCDS as usize;
+
+/// Marks the start of a sector that's in use: "NBSS".
+const SECTOR_MAGIC: u32 = 0x5353_424e;
+/// Marks the start of a snapshot: "NBS2". The version changes with the record format.
+const SNAPSHOT_MAGIC: u32 = 0x3253_424e;
+const ERASED: u32 = 0xffff_ffff;
+/// Magic, sequence number or length, and CRC.
+const HEADER_LEN: usize = 12;
+
+const SEC_LEN: usize = size_of::<raw::ble_store_value_sec>();
+const CCCD_LEN: usize = size_of::<raw::ble_store_value_cccd>();
+/// The number of records of each type (padded to 4 bytes), and the sizes of the security and
+/// CCCD records, which change with NimBLE's structs.
+const PAYLOAD_HEADER_LEN: usize = 8;
+/// The payload header, then the records.
+const MAX_PAYLOAD_LEN: usize = PAYLOAD_HEADER_LEN + 2 * MAX_BONDS * SEC_LEN + MAX_CCCDS * CCCD_LEN;
+/// Largest supported `READ_SIZE` and `WRITE_SIZE`.
+const MAX_ALIGN: usize = 256;
+const BUF_LEN: usize = (HEADER_LEN + MAX_PAYLOAD_LEN).div_ceil(MAX_ALIGN) * MAX_ALIGN;
+
+fn round_up(len: usize, align: usize) -> usize {
+    len.div_ceil(align) * align
+}
+
+/// CRC-32 (IEEE), as used by zlib.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+fn header(magic: u32, value: u32, crc: u32) -> [u8; HEADER_LEN] {
+    let mut header = [0; HEADER_LEN];
+    header[0..4].copy_from_slice(&magic.to_le_bytes());
+    header[4..8].copy_from_slice(&value.to_le_bytes());
+    header[8..12].copy_from_slice(&crc.to_le_bytes());
+    header
+}
+
+/// Parses a header into its magic, sequence number or length, and CRC.
+fn parse_header(buf: &[u8]) -> (u32, u32, u32) {
+    let word = |i: usize| u32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+    (word(0), word(4), word(8))
+}
+
+/// Up to `N` NimBLE store values, oldest first.
+///
+/// The values are kept as the bytes NimBLE gave us, padding included (NimBLE zeroes its values
+/// before filling them in). A typed copy of a value leaves its padding undefined, which would end
+/// up in the snapshots.
+#[derive(Clone, Copy)]
+struct Records<T: Copy, const N: usize> {
+    items: [MaybeUninit<T>; N],
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> Records<T, N> {
+    fn new() -> Self {
+        Self {
+            items: [MaybeUninit::zeroed(); N],
+            len: 0,
+        }
+    }
+
+    fn as_slice(&self) -> &[T] {
+        // the first `len` values were copied in
+        unsafe { core::slice::from_raw_parts(self.items.as_ptr() as *const T, self.len) }
+    }
+
+    /// Replaces the value at `index`, or appends it. Returns `BLE_HS_ESTORE_CAP` if it's full.
+    fn put(&mut self, index: Option<usize>, value: &T) -> cty::c_int {
+        let slot = match index {
+            Some(i) => i,
+            None if self.len == N => return raw::BLE_HS_ESTORE_CAP as cty::c_int,
+            None => {
+                self.len += 1;
+                self.len - 1
+            }
+        };
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                value as *const T as *const u8,
+                self.items[slot].as_mut_ptr() as *mut u8,
+                size_of::<T>(),
+            );
+        }
+        0
+    }
+
+    fn remove(&mut self, index: usize) {
+        self.items.copy_within(index + 1..self.len, index);
+        self.len -= 1;
+    }
+
+    fn bytes(&self) -> &[u8] {
+        let items = self.as_slice();
+        unsafe {
+            core::slice::from_raw_parts(items.as_ptr() as *const u8, size_of::<T>() * items.len())
+        }
+    }
+
+    /// Reads `len` values from the start of `data`, and returns the rest.
+    fn load<'a>(&mut self, len: usize, data: &'a [u8]) -> Option<&'a [u8]> {
+        let bytes = len.checked_mul(size_of::<T>())?;
+        if len > N || data.len() < bytes {
+            return None;
+        }
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                data.as_ptr(),
+                self.items.as_mut_ptr() as *mut u8,
+                bytes,
+            );
+        }
+        self.len = len;
+        Some(&data[bytes..])
+    }
+}
+
+/// Everything in the store, which is written to flash as a whole on every change.
+#[derive(Clone, Copy)]
+struct Bonds {
+    our_secs: Records<raw::ble_store_value_sec, MAX_BONDS>,
+    peer_secs: Records<raw::ble_store_value_sec, MAX_BONDS>,
+    cccds: Records<raw::ble_store_value_cccd, MAX_CCCDS>,
+}
+
+impl Bonds {
+    fn new() -> Self {
+        Self {
+            our_secs: Records::new(),
+            peer_secs: Records::new(),
+            cccds: Records::new(),
+        }
+    }
+
+    /// Writes the snapshot payload into `buf`, and returns its length.
+    fn serialize(&self, buf: &mut [u8]) -> usize {
+        buf[..4].copy_from_slice(&[
+            self.our_secs.len as u8,
+            self.peer_secs.len as u8,
+            self.cccds.len as u8,
+            0,
+        ]);
+        buf[4..6].copy_from_slice(&(SEC_LEN as u16).to_le_bytes());
+        buf[6..8].copy_from_slice(&(CCCD_LEN as u16).to_le_bytes());
+        let mut len = PAYLOAD_HEADER_LEN;
+        for bytes in [
+            self.our_secs.bytes(),
+            self.peer_secs.bytes(),
+            self.cccds.bytes(),
+        ] {
+            buf[len..len + bytes.len()].copy_from_slice(bytes);
+            len += bytes.len();
+        }
+        len
+    }
+
+    /// Parses a snapshot payload. Fails if it's malformed, or if it was written with other
+    /// NimBLE structs.
+    fn deserialize(payload: &[u8]) -> Option<Self> {
+        let mut bonds = Self::new();
+        let header = payload.get(..PAYLOAD_HEADER_LEN)?;
+        let record_len = |i: usize| u16::from_le_bytes([header[i], header[i + 1]]) as usize;
+        if record_len(4) != SEC_LEN || record_len(6) != CCCD_LEN {
+            return None;
+        }
+        let counts = &header[..3];
+        let rest = bonds
+            .our_secs
+            .load(counts[0] as usize, &payload[PAYLOAD_HEADER_LEN..])?;
+        let rest = bonds.peer_secs.load(counts[1] as usize, rest)?;
+        let rest = bonds.cccds.load(counts[2] as usize, rest)?;
+        rest.is_empty().then_some(bonds)
+    }
+
+    fn secs(
+        &mut self,
+        obj_type: cty::c_int,
+    ) -> Option<&mut Records<raw::ble_store_value_sec, MAX_BONDS>> {
+        match obj_type as u32 {
+            raw::BLE_STORE_OBJ_TYPE_OUR_SEC => Some(&mut self.our_secs),
+            raw::BLE_STORE_OBJ_TYPE_PEER_SEC => Some(&mut self.peer_secs),
+            _ => None,
+        }
+    }
+}
+
+fn is_any(addr: &raw::ble_addr_t) -> bool {
+    addr.type_ == 0 && addr.val == [0; 6]
+}
+
+fn addr_eq(a: &raw::ble_addr_t, b: &raw::ble_addr_t) -> bool {
+    a.type_ == b.type_ && a.val == b.val
+}
+
+/// Finds the security record matching `key`, like NimBLE's own RAM store does.
+fn find_sec(secs: &[raw::ble_store_value_sec], key: &raw::ble_store_key_sec) -> Option<usize> {
+    let mut skipped = 0;
+    secs.iter().position(|sec| {
+        if !is_any(&key.peer_addr) && !addr_eq(&sec.peer_addr, &key.peer_addr) {
+            return false;
+        }
+        if key.ediv_rand_present() != 0 && (sec.ediv != key.ediv || sec.rand_num != key.rand_num) {
+            return false;
+        }
+        if skipped < key.idx {
+            skipped += 1;
+            return false;
+        }
+        true
+    })
+}
+
+/// Finds the CCCD record matching `key`, like NimBLE's own RAM store does.
+fn find_cccd(cccds: &[raw::ble_store_value_cccd], key: &raw::ble_store_key_cccd) -> Option<usize> {
+    let mut skipped = 0;
+    cccds.iter().position(|cccd| {
+        if !is_any(&key.peer_addr) && !addr_eq(&cccd.peer_addr, &key.peer_addr) {
+            return false;
+        }
+        if key.chr_val_handle != 0 && cccd.chr_val_handle != key.chr_val_handle {
+            return false;
+        }
+        if skipped < key.idx {
+            skipped += 1;
+            return false;
+        }
+        true
+    })
+}
+
+/// A bond store over NOR flash, which keeps NimBLE's security keys and the CCCD values of bonded
+/// peers across resets.
+///
+/// The flash is split into erase sectors (at least two). Every change appends a snapshot of the
+/// whole store, protected by a CRC, to the current sector. When it's full, the next sector is
+/// erased and used instead, so that the sectors wear evenly. When mounting, the newest valid
+/// snapshot wins: a snapshot that was torn by a reset, or corrupted, is skipped in favor of the
+/// previous one.
+///
+/// `F::WRITE_SIZE` and `F::READ_SIZE` must be powers of two of at most 256 bytes, and a sector
+/// must fit at least one full snapshot.
+pub struct FlashBondStore<F> {
+    flash: F,
+    bonds: Bonds,
+    /// Sector holding the latest snapshot.
+    active: Option<usize>,
+    /// Offset of the next snapshot in the active sector, or `None` if the rest of the sector
+    /// can't be trusted (after a failed or torn write).
+    next: Option<usize>,
+    /// Highest sector sequence number in the flash.
+    seq: u32,
+}
+
+impl<F: NorFlash> FlashBondStore<F> {
+    /// Mounts the store, reading the latest snapshot from `flash`. Blank or unreadable flash
+    /// results in an empty store.
+    pub fn new(flash: F) -> Result<Self, F::Error> {
+        assert!(F::WRITE_SIZE.is_power_of_two() && F::WRITE_SIZE <= MAX_ALIGN);
+        assert!(F::READ_SIZE.is_power_of_two() && F::READ_SIZE <= MAX_ALIGN);
+        assert!(
+            round_up(HEADER_LEN, F::WRITE_SIZE) + BUF_LEN <= F::ERASE_SIZE,
+            "a sector can't hold a bond store snapshot"
+        );
+        let mut store = Self {
+            flash,
+            bonds: Bonds::new(),
+            active: None,
+            next: None,
+            seq: 0,
+        };
+        assert!(
+            store.sectors() >= 2,
+            "the bond store needs at least two sectors"
+        );
+        store.mount()?;
+        Ok(store)
+    }
+
+    /// Gives back the flash.
+    pub fn release(self) -> F {
+        self.flash
+    }
+
+    fn sectors(&self) -> usize {
+        self.flash.capacity() / F::ERASE_SIZE
+    }
+
+    /// Sequence number of a sector, if its header is valid.
+    fn sector_seq(&mut self, sector: usize, buf: &mut [u8]) -> Result<Option<u32>, F::Error> {
+        let len = round_up(HEADER_LEN, F::READ_SIZE);
+        self.flash
+            .read((sector * F::ERASE_SIZE) as u32, &mut buf[..len])?;
+        let (magic, seq, crc) = parse_header(buf);
+        Ok((magic == SECTOR_MAGIC && crc == crc32(&buf[..8])).then_some(seq))
+    }
+
+    /// Reads the snapshots of a sector. Returns the latest valid one, and the offset of the next
+    /// one (`None` if the sector ends with garbage).
+    fn load_sector(
+        &mut self,
+        sector: usize,
+        buf: &mut [u8],
+    ) -> Result<(Option<Bonds>, Option<usize>), F::Error> {
+        let base = sector * F::ERASE_SIZE;
+        let mut offset = round_up(HEADER_LEN, F::WRITE_SIZE);
+        let mut latest = None;
+        loop {
+            // a full sector
+            if offset + HEADER_LEN > F::ERASE_SIZE {
+                return Ok((latest, Some(offset)));
+            }
+            let header_len = round_up(HEADER_LEN, F::READ_SIZE);
+            self.flash
+                .read((base + offset) as u32, &mut buf[..header_len])?;
+            let (magic, len, crc) = parse_header(buf);
+            if magic == ERASED {
+                return Ok((latest, Some(offset)));
+            }
+            let len = len as usize;
+            let record_len = round_up(HEADER_LEN + len, F::WRITE_SIZE);
+            if magic != SNAPSHOT_MAGIC
+                || len > MAX_PAYLOAD_LEN
+                || offset + record_len > F::ERASE_SIZE
+            {
+                break;
+            }
+            let read_len = round_up(HEADER_LEN + len, F::READ_SIZE);
+            self.flash
+                .read((base + offset) as u32, &mut buf[..read_len])?;
+            let payload = &buf[HEADER_LEN..HEADER_LEN + len];
+            match Bonds::deserialize(payload) {
+                Some(bonds) if crc32(payload) == crc => latest = Some(bonds),
+                _ => break,
+            }
+            offset += record_len;
+        }
+        warn!("bond store sector {} ends with an invalid snapshot", sector);
+        Ok((latest, None))
+    }
+
+    fn mount(&mut self) -> Result<(), F::Error> {
+        let mut buf = [0; BUF_LEN];
+        let mut seqs_below = None;
+        self.seq = 0;
+        // sectors from newest to oldest, until one of them has a valid snapshot
+        loop {
+            let mut newest: Option<(usize, u32)> = None;
+            for sector in 0..self.sectors() {
+                let Some(seq) = self.sector_seq(sector, &mut buf)? else {
+                    continue;
+                };
+                self.seq = self.seq.max(seq);
+                if seqs_below.is_some_and(|below| seq >= below) {
+                    continue;
+                }
+                if newest.map_or(true, |(_, newest)| seq > newest) {
+                    newest = Some((sector, seq));
+                }
+            }
+            let Some((sector, seq)) = newest else {
+                trace!("bond store is empty");
+                return Ok(());
+            };
+            if let (Some(bonds), next) = self.load_sector(sector, &mut buf)? {
+                trace!("bond store mounted from sector {}", sector);
+                self.bonds = bonds;
+                self.active = Some(sector);
+                self.next = next;
+                return Ok(());
+            }
+            seqs_below = Some(seq);
+        }
+    }
+
+    /// Appends a snapshot of `bonds`, moving to the next sector if needed.
+    fn persist(&mut self, bonds: &Bonds) -> Result<(), F::Error> {
+        let mut buf = [0xff; BUF_LEN];
+        let len = bonds.serialize(&mut buf[HEADER_LEN..]);
+        let crc = crc32(&buf[HEADER_LEN..HEADER_LEN + len]);
+        buf[..HEADER_LEN].copy_from_slice(&header(SNAPSHOT_MAGIC, len as u32, crc));
+        let record_len = round_up(HEADER_LEN + len, F::WRITE_SIZE);
+
+        let (sector, offset) = match (self.active, self.next) {
+            (Some(sector), Some(next)) if next + record_len <= F::ERASE_SIZE => (sector, next),
+            _ => {
+                let sector = self.active.map_or(0, |s| (s + 1) % self.sectors());
+                self.start_sector(sector)?;
+                (sector, round_up(HEADER_LEN, F::WRITE_SIZE))
+            }
+        };
+
+        // if this fails, the rest of the sector can't be trusted
+        self.next = None;
+        self.flash
+            .write((sector * F::ERASE_SIZE + offset) as u32, &buf[..record_len])?;
+        self.next = Some(offset + record_len);
+        Ok(())
+    }
+
+    /// Erases a sector, and writes its header with the next sequence number.
+    fn start_sector(&mut self, sector: usize) -> Result<(), F::Error> {
+        trace!("bond store moving to sector {}", sector);
+        let base = (sector * F::ERASE_SIZE) as u32;
+        self.active = Some(sector);
+        self.next = None;
+        self.flash.erase(base, base + F::ERASE_SIZE as u32)?;
+
+        self.seq = self.seq.wrapping_add(1);
+        let mut buf = [0xff; MAX_ALIGN];
+        let mut seq_bytes = [0; 8];
+        seq_bytes[..4].copy_from_slice(&SECTOR_MAGIC.to_le_bytes());
+        seq_bytes[4..].copy_from_slice(&self.seq.to_le_bytes());
+        buf[..HEADER_LEN].copy_from_slice(&header(SECTOR_MAGIC, self.seq, crc32(&seq_bytes)));
+        self.flash
+            .write(base, &buf[..round_up(HEADER_LEN, F::WRITE_SIZE)])
+    }
+}
+
+/// The parts of [`FlashBondStore`] that don't depend on the flash type, for the store callbacks.
+trait BondStore {
+    fn bonds(&self) -> &Bonds;
+
+    /// Writes `bonds` to flash, and keeps them if that succeeded.
+    fn commit(&mut self, bonds: &Bonds) -> bool;
+}
+
+impl<F: NorFlash> BondStore for FlashBondStore<F> {
+    fn bonds(&self) -> &Bonds {
+        &self.bonds
+    }
+
+    fn commit(&mut self, bonds: &Bonds) -> bool {
+        match self.persist(bonds) {
+            Ok(()) => {
+                self.bonds = *bonds;
+                true
+            }
+            Err(e) => {
+                warn!(
+                    "bond store write failed: {}",
+                    defmt::Debug2Format(&e.kind())
+                );
+                false
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct StorePtr(*mut dyn BondStore);
+
+// Safety: the store is only used by the store callbacks, which run in the host task
+unsafe impl Send for StorePtr {}
+
+static STORE: Mutex<CriticalSectionRawMutex, Cell<Option<StorePtr>>> = Mutex::new(Cell::new(None));
+
+/// Runs `f` with the installed store, or returns `BLE_HS_ENOTSUP` without one.
+fn with_store(f: impl FnOnce(&mut dyn BondStore) -> cty::c_int) -> cty::c_int {
+    match STORE.lock(Cell::get) {
+        // Safety: the store is `'static`, and the callbacks aren't reentrant
+        Some(StorePtr(store)) => f(unsafe { &mut *store }),
+        None => raw::BLE_HS_ENOTSUP as cty::c_int,
+    }
+}
+
+/// Applies a change to a copy of the bonds, and commits it if `f` succeeds.
+fn update(f: impl FnOnce(&mut Bonds) -> cty::c_int) -> cty::c_int {
+    with_store(|store| {
+        let mut bonds = *store.bonds();
+        match f(&mut bonds) {
+            0 if !store.commit(&bonds) => raw::BLE_HS_ESTORE_FAIL as cty::c_int,
+            rc => rc,
+        }
+    })
+}
+
+unsafe extern "C" fn store_read(
+    obj_type: cty::c_int,
+    key: *const raw::ble_store_key,
+    dst: *mut raw::ble_store_value,
+) -> cty::c_int {
+    with_store(|store| {
+        let mut bonds = *store.bonds();
+        let found = if obj_type as u32 == raw::BLE_STORE_OBJ_TYPE_CCCD {
+            find_cccd(bonds.cccds.as_slice(), &(*key).cccd)
+                .map(|i| (*dst).cccd = bonds.cccds.as_slice()[i])
+        } else if let Some(secs) = bonds.secs(obj_type) {
+            find_sec(secs.as_slice(), &(*key).sec).map(|i| (*dst).sec = secs.as_slice()[i])
+        } else {
+            None
+        };
+        match found {
+            Some(()) => 0,
+            None => raw::BLE_HS_ENOENT as cty::c_int,
+        }
+    })
+}
+
+unsafe extern "C" fn store_write(
+    obj_type: cty::c_int,
+    val: *const raw::ble_store_value,
+) -> cty::c_int {
+    trace!("bond store write: type {}", obj_type);
+    update(|bonds| {
+        if obj_type as u32 == raw::BLE_STORE_OBJ_TYPE_CCCD {
+            let value = &(*val).cccd;
+            let mut key = core::mem::zeroed();
+            raw::ble_store_key_from_value_cccd(&mut key, value);
+            let index = find_cccd(bonds.cccds.as_slice(), &key);
+            bonds.cccds.put(index, value)
+        } else if let Some(secs) = bonds.secs(obj_type) {
+            let value = &(*val).sec;
+            let mut key = core::mem::zeroed();
+            raw::ble_store_key_from_value_sec(&mut key, value);
+            let index = find_sec(secs.as_slice(), &key);
+            secs.put(index, value)
+        } else {
+            raw::BLE_HS_ENOTSUP as cty::c_int
+        }
+    })
+}
+
+unsafe extern "C" fn store_delete(
+    obj_type: cty::c_int,
+    key: *const raw::ble_store_key,
+) -> cty::c_int {
+    trace!("bond store delete: type {}", obj_type);
+    update(|bonds| {
+        if obj_type as u32 == raw::BLE_STORE_OBJ_TYPE_CCCD {
+            let Some(index) = find_cccd(bonds.cccds.as_slice(), &(*key).cccd) else {
+                return raw::BLE_HS_ENOENT as cty::c_int;
+            };
+            bonds.cccds.remove(index);
+        } else if let Some(secs) = bonds.secs(obj_type) {
+            let Some(index) = find_sec(secs.as_slice(), &(*key).sec) else {
+                return raw::BLE_HS_ENOENT as cty::c_int;
+            };
+            secs.remove(index);
+        } else {
+            return raw::BLE_HS_ENOENT as cty::c_int;
+        }
+        0
+    })
+}
+
+impl NimbleHost {
+    /// Installs a bond store, which NimBLE uses to save and look up bonds and the CCCD values of
+    /// bonded peers. Without one, bonds can't be stored. The store stays installed across
+    /// [`crate::Nimble::shutdown`].
+    ///
+    /// The store is accessed from the host task, and flash operations block it.
+    pub fn set_bond_store<F: NorFlash + 'static>(
+        &self,
+        store: &'static mut FlashBondStore<F>,
+    ) -> Result<(), HostError> {
+        check_current(self.generation)?;
+        let store: &'static mut dyn BondStore = store;
+        STORE.lock(|s| s.set(Some(StorePtr(store))));
+        unsafe {
+            raw::ble_hs_cfg.store_read_cb = Some(store_read);
+            raw::ble_hs_cfg.store_write_cb = Some(store_write);
+            raw::ble_hs_cfg.store_delete_cb = Some(store_delete);
+            // deletes the oldest bond when the store is full
+            raw::ble_hs_cfg.store_status_cb = Some(raw::ble_store_util_status_rr);
+        }
+        Ok(())
+    }
+
+    /// Deletes the bond (keys and CCCD values) of a peer, given its identity address.
+    pub fn delete_bond(&self, peer: &Address) -> Result<(), HostError> {
+        check_current(self.generation)?;
+        check(unsafe { raw::ble_store_util_delete_peer(&peer.to_raw()) })
+    }
+
+    /// Deletes every bond.
+    pub fn clear_bonds(&self) -> Result<(), HostError> {
+        check_current(self.generation)?;
+        check(unsafe { raw::ble_store_clear() })
+    }
+}
+
+/// A [`NorFlash`] in RAM, which behaves like NOR flash (writes can only clear bits, and erasing
+/// sets them), to run a [`FlashBondStore`] without real flash, e.g. in tests on the host.
+pub struct RamFlash<const SIZE: usize, const ERASE_SIZE: usize> {
+    data: [u8; SIZE],
+}
+
+impl<const SIZE: usize, const ERASE_SIZE: usize> RamFlash<SIZE, ERASE_SIZE> {
+    /// Creates erased flash.
+    pub const fn new() -> Self {
+        Self { data: [0xff; SIZE] }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Gives access to the contents, e.g. to simulate corruption.
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+}
+
+impl<const SIZE: usize, const ERASE_SIZE: usize> Default for RamFlash<SIZE, ERASE_SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const SIZE: usize, const ERASE_SIZE: usize> ErrorType for RamFlash<SIZE, ERASE_SIZE> {
+    type Error = NorFlashErrorKind;
+}
+
+impl<const SIZE: usize, const ERASE_SIZE: usize> ReadNorFlash for RamFlash<SIZE, ERASE_SIZE> {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        check_read(self, offset, bytes.len())?;
+        let offset = offset as usize;
+        bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        SIZE
+    }
+}
+
+impl<const SIZE: usize, const ERASE_SIZE: usize> NorFlash for RamFlash<SIZE, ERASE_SIZE> {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        check_erase(self, from, to)?;
+        self.data[from as usize..to as usize].fill(0xff);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        check_write(self, offset, bytes.len())?;
+        let offset = offset as usize;
+        for (dst, src) in self.data[offset..offset + bytes.len()]
+            .iter_mut()
+            .zip(bytes)
+        {
+            *dst &= *src;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::lock_stack;
+
+    type Flash = RamFlash<8192, 4096>;
+
+    const PEER_SEC: cty::c_int = raw::BLE_STORE_OBJ_TYPE_PEER_SEC as cty::c_int;
+    const ENOENT: cty::c_int = raw::BLE_HS_ENOENT as cty::c_int;
+
+    /// A security record, zeroed (padding included) like NimBLE's.
+    fn sec(peer: u8) -> MaybeUninit<raw::ble_store_value_sec> {
+        let mut sec = MaybeUninit::<raw::ble_store_value_sec>::zeroed();
+        unsafe {
+            (*sec.as_mut_ptr()).peer_addr.val = [peer; 6];
+            (*sec.as_mut_ptr()).key_size = 16;
+        }
+        sec
+    }
+
+    fn bonds(peers: &[u8]) -> Bonds {
+        let mut bonds = Bonds::new();
+        for peer in peers {
+            let sec = sec(*peer);
+            assert_eq!(
+                bonds.peer_secs.put(None, unsafe { sec.assume_init_ref() }),
+                0
+            );
+        }
+        bonds
+    }
+
+    fn peers(store: &FlashBondStore<Flash>) -> Vec<u8> {
+        store
+            .bonds
+            .peer_secs
+            .as_slice()
+            .iter()
+            .map(|sec| sec.peer_addr.val[0])
+            .collect()
+    }
+
+    /// Mounts a copy of the flash, as after a reset.
+    fn remount(flash: &Flash) -> FlashBondStore<Flash> {
+        let mut copy = Flash::new();
+        copy.as_bytes_mut().copy_from_slice(flash.as_bytes());
+        FlashBondStore::new(copy).unwrap()
+    }
+
+    #[test]
+    fn mount_blank_flash() {
+        let mut store = FlashBondStore::new(Flash::new()).unwrap();
+        assert!(peers(&store).is_empty());
+        assert_eq!(store.active, None);
+        // mounting doesn't write anything
+        assert!(store.flash.as_bytes().iter().all(|b| *b == 0xff));
+
+        assert!(store.commit(&bonds(&[1])));
+        assert_eq!(store.active, Some(0));
+        assert_eq!(peers(&remount(&store.flash)), [1]);
+    }
+
+    #[test]
+    fn write_read_delete_round_trip() {
+        let _stack = lock_stack();
+        let store = Box::into_raw(Box::new(FlashBondStore::new(Flash::new()).unwrap()));
+        STORE.lock(|s| s.set(Some(StorePtr(store))));
+
+        let mut value: raw::ble_store_value = unsafe { core::mem::zeroed() };
+        unsafe { core::ptr::copy_nonoverlapping(sec(1).as_ptr(), &mut value.sec, 1) };
+        let mut key: raw::ble_store_key = unsafe { core::mem::zeroed() };
+        unsafe { raw::ble_store_key_from_value_sec(&mut key.sec, &value.sec) };
+        let mut dst: raw::ble_store_value = unsafe { core::mem::zeroed() };
+        unsafe {
+            assert_eq!(store_read(PEER_SEC, &key, &mut dst), ENOENT);
+            assert_eq!(store_write(PEER_SEC, &value), 0);
+            assert_eq!(store_read(PEER_SEC, &key, &mut dst), 0);
+            assert_eq!(dst.sec.peer_addr.val, [1; 6]);
+            assert_eq!(dst.sec.key_size, 16);
+            assert_eq!(peers(&remount(&(*store).flash)), [1]);
+
+            assert_eq!(store_delete(PEER_SEC, &key), 0);
+            assert_eq!(store_read(PEER_SEC, &key, &mut dst), ENOENT);
+            assert_eq!(store_delete(PEER_SEC, &key), ENOENT);
+        }
+
+        STORE.lock(|s| s.set(None));
+        let store = unsafe { Box::from_raw(store) };
+        assert!(peers(&remount(&store.flash)).is_empty());
+    }
+
+    #[test]
+    fn rotates_through_sectors() {
+        let mut store = FlashBondStore::new(Flash::new()).unwrap();
+        let mut sectors = Vec::new();
+        let mut peer = 0;
+        while sectors.len() < 3 {
+            peer = peer % 200 + 1;
+            assert!(store.commit(&bonds(&[peer])));
+            let active = store.active.unwrap();
+            if sectors.last() != Some(&active) {
+                sectors.push(active);
+            }
+        }
+        assert_eq!(sectors, [0, 1, 0]);
+        assert_eq!(store.seq, 3);
+
+        let mut store = remount(&store.flash);
+        assert_eq!(peers(&store), [peer]);
+        assert_eq!(store.active, Some(0));
+        assert!(store.commit(&bonds(&[peer, 201])));
+        assert_eq!(peers(&remount(&store.flash)), [peer, 201]);
+    }
+
+    #[test]
+    fn torn_snapshot_falls_back_to_the_previous_one() {
+        let mut store = FlashBondStore::new(Flash::new()).unwrap();
+        assert!(store.commit(&bonds(&[1])));
+        let start = store.next.unwrap();
+        assert!(store.commit(&bonds(&[1, 2])));
+        let end = store.next.unwrap();
+
+        // the end of the last snapshot was never written
+        store.flash.as_bytes_mut()[start + HEADER_LEN + PAYLOAD_HEADER_LEN..end].fill(0xff);
+        let mut store = remount(&store.flash);
+        assert_eq!(peers(&store), [1]);
+
+        // the rest of the sector isn't used anymore
+        assert_eq!(store.next, None);
+        assert!(store.commit(&bonds(&[1, 3])));
+        assert_eq!(store.active, Some(1));
+        assert_eq!(peers(&remount(&store.flash)), [1, 3]);
+    }
+
+    #[test]
+    fn corrupted_sector_header_falls_back_to_the_older_sector() {
+        let mut store = FlashBondStore::new(Flash::new()).unwrap();
+        let mut peer = 0;
+        while store.active != Some(1) {
+            peer = peer % 200 + 1;
+            assert!(store.commit(&bonds(&[peer])));
+        }
+        let last_in_sector_0 = if peer == 1 { 200 } else { peer - 1 };
+
+        store.flash.as_bytes_mut()[<Flash as NorFlash>::ERASE_SIZE + 4] ^= 0xff;
+        let mut store = remount(&store.flash);
+        assert_eq!(peers(&store), [last_in_sector_0]);
+        assert_eq!(store.active, Some(0));
+
+        assert!(store.commit(&bonds(&[201])));
+        assert_eq!(peers(&remount(&store.flash)), [201]);
+    }
+
+    #[test]
+    fn snapshots_are_deterministic() {
+        let mut first = [0; MAX_PAYLOAD_LEN];
+        let mut second = [0xff; MAX_PAYLOAD_LEN];
+        let bonds = bonds(&[1, 2]);
+        let len = bonds.serialize(&mut first);
+        // a copy keeps the padding bytes
+        let copy = bonds;
+        assert_eq!(copy.serialize(&mut second), len);
+        assert_eq!(first[..len], second[..len]);
+        assert_eq!(len, PAYLOAD_HEADER_LEN + 2 * SEC_LEN);
+    }
+
+    #[test]
+    fn snapshots_with_other_record_sizes_are_rejected() {
+        let mut payload = [0; MAX_PAYLOAD_LEN];
+        let len = bonds(&[1]).serialize(&mut payload);
+        assert!(Bonds::deserialize(&payload[..len]).is_some());
+
+        // as if written by a build whose NimBLE structs are laid out differently
+        let mut other = payload;
+        other[4..6].copy_from_slice(&(SEC_LEN as u16 + 8).to_le_bytes());
+        assert!(Bonds::deserialize(&other[..len]).is_none());
+        let mut other = payload;
+        other[6..8].copy_from_slice(&(CCCD_LEN as u16 - 1).to_le_bytes());
+        assert!(Bonds::deserialize(&other[..len]).is_none());
+    }
+}
@@ -1,12 +1,15 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
 use core::future::Future;
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
 use core::task::Poll;
 
 pub use apache_nimble_sys as raw;
-use embassy_sync::blocking_mutex::raw::RawMutex;
+use bt_hci::param::PhyKind;
+use embassy_futures::select::select;
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, RawMutex};
 use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
 
 #[cfg(feature = "controller")]
 pub mod controller;
@@ -14,20 +17,183 @@ pub mod controller;
 #[cfg(feature = "host")]
 pub mod host;
 
+pub mod mbuf;
+pub mod mempool;
+pub mod stats;
+
+#[cfg(test)]
+mod test_support;
+
+pub use mbuf::{Mbuf, SharedMbuf};
+pub use mempool::{MbufPool, MemPool, PoolBox};
+
 extern "C" {
     pub(crate) fn ble_ll_init();
     fn os_msys_init();
     fn os_mempool_module_init();
 }
 
-static NIMBLE_INITIALIZED: AtomicBool = AtomicBool::new(false);
+const STOPPED: u8 = 0;
+const RUNNING: u8 = 1;
+const STOPPING: u8 = 2;
+
+static NIMBLE_STATE: AtomicU8 = AtomicU8::new(STOPPED);
+/// Incremented on every shutdown, so that objects from a previous [`NimbleHandle`] can tell that
+/// they're stale.
+static GENERATION: AtomicU32 = AtomicU32::new(0);
+
+fn is_initialized() -> bool {
+    NIMBLE_STATE.load(Ordering::Acquire) == RUNNING
+}
+
+/// Whether objects created with `generation` can still be used.
+pub(crate) fn is_current(generation: u32) -> bool {
+    is_initialized() && GENERATION.load(Ordering::Acquire) == generation
+}
+
+/// Configuration for [`Nimble::init`].
+///
+/// The cputime timer and frequency are compiled into NimBLE through the `OS_CPUTIME_TIMER_NUM` and
+/// `OS_CPUTIME_FREQ` syscfg values (see the README), so they default to those, and
+/// [`Nimble::init`] fails with [`InitError::CputimeMismatch`] if they're set to anything else.
+#[derive(Debug, Clone)]
+pub struct Config {
+    cputime_timer: u32,
+    cputime_freq: u32,
+    #[cfg(feature = "controller")]
+    command_timeout: embassy_time::Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            cputime_timer: raw::MYNEWT_VAL_OS_CPUTIME_TIMER_NUM,
+            cputime_freq: raw::MYNEWT_VAL_OS_CPUTIME_FREQ,
+            #[cfg(feature = "controller")]
+            command_timeout: embassy_time::Duration::from_secs(2),
+        }
+    }
+}
 
-pub fn initialize_nimble() {
-    NIMBLE_INITIALIZED
-        .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
-        .expect("attempted to initialize nimble more than once");
+impl Config {
+    /// HAL timer used for cputime.
+    pub fn cputime_timer(mut self, timer: u32) -> Self {
+        self.cputime_timer = timer;
+        self
+    }
+
+    /// Frequency of the cputime timer, in Hz.
+    pub fn cputime_freq(mut self, freq: u32) -> Self {
+        self.cputime_freq = freq;
+        self
+    }
+
+    /// How long the controller waits for the response to an HCI command before giving up with
+    /// [`controller::ControllerError::Timeout`]. Defaults to 2 seconds. Individual commands can use a different timeout
+    /// with [`controller::NimbleController::exec_with_timeout`].
+    #[cfg(feature = "controller")]
+    pub fn command_timeout(mut self, timeout: embassy_time::Duration) -> Self {
+        self.command_timeout = timeout;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum InitError {
+    /// NimBLE has already been initialized, or is being shut down.
+    AlreadyInitialized,
+    /// The cputime timer or frequency in the [`Config`] don't match the ones NimBLE was compiled
+    /// with.
+    CputimeMismatch,
+    /// `hal_timer_init` failed with the given error code.
+    Timer(i32),
+    /// `os_cputime_init` failed with the given error code.
+    Cputime(i32),
+    /// `ble_phy_init` failed with the given error code.
+    Phy(i32),
+}
 
-    unsafe {
+/// Initialized NimBLE components. Since these can only be obtained from [`Nimble::init`], they
+/// also serve as proof that NimBLE is initialized.
+pub struct NimbleHandle {
+    #[cfg(feature = "controller")]
+    pub controller: controller::NimbleController,
+    #[cfg(feature = "controller")]
+    pub controller_task: controller::NimbleControllerTask,
+    #[cfg(feature = "host")]
+    pub host: host::NimbleHost,
+    #[cfg(feature = "host")]
+    pub host_task: host::NimbleHostTask,
+}
+
+pub struct Nimble;
+
+impl Nimble {
+    /// Initializes NimBLE. This can only be done again after [`Nimble::shutdown`].
+    pub fn init(config: Config) -> Result<NimbleHandle, InitError> {
+        if config.cputime_timer != raw::MYNEWT_VAL_OS_CPUTIME_TIMER_NUM
+            || config.cputime_freq != raw::MYNEWT_VAL_OS_CPUTIME_FREQ
+        {
+            return Err(InitError::CputimeMismatch);
+        }
+
+        NIMBLE_STATE
+            .compare_exchange(STOPPED, RUNNING, Ordering::AcqRel, Ordering::Acquire)
+            .map_err(|_| InitError::AlreadyInitialized)?;
+
+        let result = unsafe { Self::init_stack(&config) };
+        if let Err(e) = result {
+            unsafe { Self::deinit_stack() };
+            NIMBLE_STATE.store(STOPPED, Ordering::Release);
+            return Err(e);
+        }
+
+        let generation = GENERATION.load(Ordering::Acquire);
+        Ok(NimbleHandle {
+            #[cfg(feature = "controller")]
+            controller: controller::NimbleController::new(generation, config.command_timeout),
+            #[cfg(feature = "controller")]
+            controller_task: controller::NimbleControllerTask::new(generation),
+            #[cfg(feature = "host")]
+            host: host::NimbleHost::new(generation),
+            #[cfg(feature = "host")]
+            host_task: host::NimbleHostTask::new(generation),
+        })
+    }
+
+    /// Stops NimBLE, so that it can be initialized again later. This stops the tasks from
+    /// [`NimbleHandle`] (their `run` functions return), resets the link layer, turns off the radio
+    /// and its interrupts, and drops any queued events and data.
+    ///
+    /// Objects from the previous [`NimbleHandle`] can't be used afterwards: commands fail with
+    /// `ControllerError::NotStarted`, and the tasks return immediately. Pools registered with msys
+    /// need to be registered again after the next [`Nimble::init`].
+    ///
+    /// A command waiting for its response fails with `ControllerError::NotStarted` as well. This
+    /// waits for it to return, so its future needs to keep being polled (or be dropped).
+    pub async fn shutdown() -> Result<(), OsError> {
+        NIMBLE_STATE
+            .compare_exchange(RUNNING, STOPPING, Ordering::AcqRel, Ordering::Acquire)
+            .map_err(|_| OsError::NotStarted)?;
+        GENERATION.fetch_add(1, Ordering::AcqRel);
+
+        // a command waiting for its response fails with `ControllerError::NotStarted`, and no other
+        // command can start until the stack is deinitialized
+        #[cfg(feature = "controller")]
+        let _cmd_lock = controller::abort_commands().await;
+
+        #[cfg(feature = "host")]
+        host::HOST_TASK.stop().await;
+        #[cfg(feature = "controller")]
+        controller::LL_TASK.stop().await;
+
+        unsafe { Self::deinit_stack() };
+        NIMBLE_STATE.store(STOPPED, Ordering::Release);
+        Ok(())
+    }
+
+    #[cfg_attr(not(feature = "controller"), allow(unused_variables))]
+    unsafe fn init_stack(config: &Config) -> Result<(), InitError> {
         #[cfg(feature = "controller")]
         {
             ble_ll_init();
@@ -39,19 +205,76 @@ pub fn initialize_nimble() {
         }
         os_mempool_module_init();
         os_msys_init();
+        stats::reset_msys_pools();
         raw::ble_transport_init();
 
         #[cfg(feature = "host")]
         {
+            host::init();
             raw::ble_transport_hs_init();
         }
 
         #[cfg(feature = "controller")]
         {
-            raw::hal_timer_init(5, core::ptr::null_mut());
-            raw::os_cputime_init(32768);
+            let rc = raw::hal_timer_init(config.cputime_timer as i32, core::ptr::null_mut());
+            if rc != 0 {
+                return Err(InitError::Timer(rc));
+            }
+            let rc = raw::os_cputime_init(config.cputime_freq);
+            if rc != 0 {
+                return Err(InitError::Cputime(rc));
+            }
             raw::ble_transport_ll_init();
+            controller::init_phy().map_err(InitError::Phy)?;
         }
+
+        Ok(())
+    }
+
+    /// Undoes [`Nimble::init_stack`], once the tasks have stopped.
+    unsafe fn deinit_stack() {
+        #[cfg(feature = "controller")]
+        controller::deinit();
+        #[cfg(feature = "host")]
+        host::deinit();
+    }
+}
+
+/// Lets [`Nimble::shutdown`] stop one of the tasks handed out by [`Nimble::init`].
+pub(crate) struct TaskControl {
+    running: AtomicBool,
+    stop: Signal<CriticalSectionRawMutex, ()>,
+    stopped: Signal<CriticalSectionRawMutex, ()>,
+}
+
+impl TaskControl {
+    pub(crate) const fn new() -> Self {
+        Self {
+            running: AtomicBool::new(false),
+            stop: Signal::new(),
+            stopped: Signal::new(),
+        }
+    }
+
+    /// Runs `task` until [`TaskControl::stop`] is called. Returns immediately if `generation` is
+    /// stale, or if the task is already running.
+    pub(crate) async fn run(&self, generation: u32, task: impl Future) {
+        if !is_current(generation) || self.running.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        select(task, self.stop.wait()).await;
+        self.running.store(false, Ordering::Release);
+        self.stopped.signal(());
+    }
+
+    /// Stops the task if it's running, and waits for it to return.
+    pub(crate) async fn stop(&self) {
+        if self.running.load(Ordering::Acquire) {
+            self.stop.signal(());
+            self.stopped.wait().await;
+        }
+        self.stop.reset();
+        self.stopped.reset();
     }
 }
 
@@ -76,6 +299,64 @@ impl<'ch, M: RawMutex, T, const N: usize> Future for ReadyToSend<'ch, M, T, N> {
     }
 }
 
+/// A LE PHY supported by the radio. The 2M and Coded PHYs are only available when the `phy-2m` and
+/// `phy-coded` features are enabled.
+///
+/// Can be converted into a [`PhyKind`] to select the PHY used by extended advertising, scanning
+/// and connection commands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Phy {
+    Le1M,
+    #[cfg(feature = "phy-2m")]
+    Le2M,
+    #[cfg(feature = "phy-coded")]
+    LeCoded,
+}
+
+impl Phy {
+    /// The PHY's value in HCI commands and events (`BLE_HCI_LE_PHY_*`).
+    #[cfg_attr(
+        not(all(feature = "host", feature = "role-broadcaster")),
+        allow(dead_code)
+    )]
+    pub(crate) fn to_raw(self) -> u8 {
+        match self {
+            Phy::Le1M => 1,
+            #[cfg(feature = "phy-2m")]
+            Phy::Le2M => 2,
+            #[cfg(feature = "phy-coded")]
+            Phy::LeCoded => 3,
+        }
+    }
+
+    #[cfg_attr(
+        not(all(feature = "host", feature = "role-observer")),
+        allow(dead_code)
+    )]
+    pub(crate) fn from_raw(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Phy::Le1M),
+            #[cfg(feature = "phy-2m")]
+            2 => Some(Phy::Le2M),
+            #[cfg(feature = "phy-coded")]
+            3 => Some(Phy::LeCoded),
+            _ => None,
+        }
+    }
+}
+
+impl From<Phy> for PhyKind {
+    fn from(value: Phy) -> Self {
+        match value {
+            Phy::Le1M => PhyKind::Le1M,
+            #[cfg(feature = "phy-2m")]
+            Phy::Le2M => PhyKind::Le2M,
+            #[cfg(feature = "phy-coded")]
+            Phy::LeCoded => PhyKind::LeCoded,
+        }
+    }
+}
+
 #[derive(Debug)]
 #[repr(u32)]
 pub enum OsError {
@@ -115,51 +396,72 @@ impl From<u32> for OsError {
 
 impl embedded_io::Error for OsError {
     fn kind(&self) -> embedded_io::ErrorKind {
-        embedded_io::ErrorKind::WriteZero
-    }
-}
-
-struct OsMbuf(*mut raw::os_mbuf);
+        use embedded_io::ErrorKind;
 
-impl From<*mut raw::os_mbuf> for OsMbuf {
-    fn from(value: *mut raw::os_mbuf) -> Self {
-        OsMbuf(value)
+        match self {
+            OsError::NoMem => ErrorKind::OutOfMemory,
+            OsError::Invalid | OsError::InvalidParameter | OsError::NotAligned => {
+                ErrorKind::InvalidInput
+            }
+            OsError::Timeout => ErrorKind::TimedOut,
+            OsError::ErrPriviliged => ErrorKind::PermissionDenied,
+            OsError::NotStarted => ErrorKind::NotConnected,
+            OsError::NoEnt => ErrorKind::NotFound,
+            OsError::BadMutex | OsError::ErrInISR | OsError::Busy | OsError::Error => {
+                ErrorKind::Other
+            }
+        }
     }
 }
 
-impl embedded_io::ErrorType for OsMbuf {
-    type Error = OsError;
-}
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
 
-impl embedded_io::Write for OsMbuf {
-    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
-        let amt = buf.len();
-        let ret =
-            unsafe { raw::os_mbuf_append(self.0, buf.as_ptr() as *const cty::c_void, amt as u16) };
-        if ret == 0 {
-            Ok(amt)
-        } else {
-            Err(OsError::from(ret as u32))
+    use super::*;
+    use crate::test_support::lock_stack;
+
+    #[test]
+    fn init_checks_the_cputime_config() {
+        let _stack = lock_stack();
+        let timer = raw::MYNEWT_VAL_OS_CPUTIME_TIMER_NUM;
+        let freq = raw::MYNEWT_VAL_OS_CPUTIME_FREQ;
+        for config in [
+            Config::default().cputime_timer(timer + 1),
+            Config::default().cputime_freq(freq / 2),
+        ] {
+            assert!(matches!(
+                Nimble::init(config),
+                Err(InitError::CputimeMismatch)
+            ));
         }
-    }
 
-    fn flush(&mut self) -> Result<(), Self::Error> {
-        Ok(())
+        // nothing was initialized
+        let _nimble =
+            Nimble::init(Config::default().cputime_timer(timer).cputime_freq(freq)).unwrap();
+        block_on(Nimble::shutdown()).unwrap();
     }
-}
 
-impl embedded_io::Read for OsMbuf {
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
-        let len = buf.len();
-        unsafe {
-            if raw::os_mbuf_copydata(self.0, 0, len as i32, buf.as_mut_ptr() as *mut cty::c_void)
-                == -1
-            {
-                // Could not read all bytes
-                return Err(OsError::NoMem);
-            };
-            raw::os_mbuf_adj(self.0, len as i32);
+    #[test]
+    fn init_and_shutdown_repeatedly() {
+        let _stack = lock_stack();
+        for _ in 0..100 {
+            let nimble = Nimble::init(Config::default()).unwrap();
+            assert!(matches!(
+                Nimble::init(Config::default()),
+                Err(InitError::AlreadyInitialized)
+            ));
+            block_on(Nimble::shutdown()).unwrap();
+
+            // the tasks of the previous generation return right away
+            #[cfg(feature = "controller")]
+            block_on(nimble.controller_task.run());
+            #[cfg(feature = "host")]
+            block_on(nimble.host_task.run());
         }
-        Ok(len)
+        assert!(matches!(
+            block_on(Nimble::shutdown()),
+            Err(OsError::NotStarted)
+        ));
     }
 }
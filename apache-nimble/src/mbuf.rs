@@ -0,0 +1,642 @@
+use core::marker::PhantomData;
+use core::mem::size_of;
+use core::ptr::NonNull;
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
+
+use crate::stats::{self, Pool};
+use crate::{raw, OsError};
+
+/// An owned chain of NimBLE `os_mbuf`s. The chain is freed when this is dropped.
+///
+/// Reading through [`embedded_io::Read`] or [`embedded_io::BufRead`] consumes data from the front
+/// of the chain, and writing through [`embedded_io::Write`] appends to the end of it, allocating
+/// more mbufs from the same pool as needed.
+pub struct Mbuf {
+    om: NonNull<raw::os_mbuf>,
+}
+
+// Safety: we have unique ownership of the chain, and NimBLE's pools are protected by critical
+// sections.
+unsafe impl Send for Mbuf {}
+
+impl Mbuf {
+    /// Takes ownership of a mbuf chain. Returns `None` if `om` is null.
+    ///
+    /// # Safety
+    ///
+    /// `om` must point to a valid mbuf chain that isn't owned by anything else.
+    pub unsafe fn from_raw(om: *mut raw::os_mbuf) -> Option<Self> {
+        NonNull::new(om).map(|om| Self { om })
+    }
+
+    /// Gives up ownership of the chain, without freeing it.
+    pub fn into_raw(self) -> *mut raw::os_mbuf {
+        let om = self.om.as_ptr();
+        core::mem::forget(self);
+        om
+    }
+
+    pub fn as_ptr(&self) -> *mut raw::os_mbuf {
+        self.om.as_ptr()
+    }
+
+    /// Allocates an empty packet header mbuf from msys.
+    pub fn new() -> Result<Self, OsError> {
+        unsafe { Self::from_raw(raw::os_msys_get_pkthdr(0, 0)) }.ok_or_else(|| {
+            stats::record_alloc_failure(Pool::Msys);
+            OsError::NoMem
+        })
+    }
+
+    /// Allocates a packet header mbuf from msys, and copies `data` into it.
+    pub fn from_slice(data: &[u8]) -> Result<Self, OsError> {
+        let mut om = Self::new()?;
+        om.append(data)?;
+        Ok(om)
+    }
+
+    /// Total length of the data in the chain.
+    pub fn len(&self) -> usize {
+        unsafe { raw::os_mbuf_len(self.om.as_ptr()) as usize }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterates over the data in each mbuf of the chain.
+    pub fn segments(&self) -> Segments<'_> {
+        Segments {
+            om: self.om.as_ptr(),
+            _chain: PhantomData,
+        }
+    }
+
+    /// Appends `data` to the end of the chain.
+    pub fn append(&mut self, data: &[u8]) -> Result<(), OsError> {
+        let len = u16::try_from(data.len()).map_err(|_| OsError::InvalidParameter)?;
+        let ret = unsafe {
+            raw::os_mbuf_append(self.om.as_ptr(), data.as_ptr() as *const cty::c_void, len)
+        };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(OsError::from(ret as u32))
+        }
+    }
+
+    /// Copies `buf.len()` bytes starting at `offset` into `buf`, without consuming them. Fails if
+    /// the chain doesn't have enough data.
+    pub fn copy_to(&self, offset: usize, buf: &mut [u8]) -> Result<(), OsError> {
+        let ret = unsafe {
+            raw::os_mbuf_copydata(
+                self.om.as_ptr(),
+                offset as i32,
+                buf.len() as i32,
+                buf.as_mut_ptr() as *mut cty::c_void,
+            )
+        };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(OsError::InvalidParameter)
+        }
+    }
+
+    /// Removes `len` bytes from the front of the chain.
+    pub fn trim_front(&mut self, len: usize) {
+        unsafe { raw::os_mbuf_adj(self.om.as_ptr(), len as i32) };
+    }
+
+    /// Removes `len` bytes from the end of the chain.
+    pub fn trim_back(&mut self, len: usize) {
+        unsafe { raw::os_mbuf_adj(self.om.as_ptr(), -(len as i32)) };
+    }
+
+    /// Prepends `data` to the front of the chain, allocating a new mbuf if there isn't enough
+    /// leading space.
+    ///
+    /// Note: NimBLE frees the whole chain if the allocation fails, which is why this consumes
+    /// `self`.
+    pub fn prepend(self, data: &[u8]) -> Result<Self, OsError> {
+        let len = u16::try_from(data.len()).map_err(|_| OsError::InvalidParameter)?;
+        let om = unsafe { raw::os_mbuf_prepend(self.into_raw(), len as i32) };
+        let om = unsafe { Self::from_raw(om) }.ok_or(OsError::NoMem)?;
+        let ret = unsafe {
+            raw::os_mbuf_copyinto(
+                om.om.as_ptr(),
+                0,
+                data.as_ptr() as *const cty::c_void,
+                len as i32,
+            )
+        };
+        if ret == 0 {
+            Ok(om)
+        } else {
+            Err(OsError::from(ret as u32))
+        }
+    }
+
+    /// Rearranges the chain so that the first `len` bytes are contiguous in the first mbuf, and
+    /// can be accessed with [`Mbuf::first_segment`].
+    ///
+    /// Note: NimBLE frees the whole chain if this fails, which is why this consumes `self`.
+    pub fn pullup(self, len: usize) -> Result<Self, OsError> {
+        let len = u16::try_from(len).map_err(|_| OsError::InvalidParameter)?;
+        let om = unsafe { raw::os_mbuf_pullup(self.into_raw(), len) };
+        unsafe { Self::from_raw(om) }.ok_or(OsError::NoMem)
+    }
+
+    /// Data in the first mbuf of the chain.
+    pub fn first_segment(&self) -> &[u8] {
+        self.segments().next().unwrap_or(&[])
+    }
+
+    /// Splits the chain in two at `at`. `self` keeps the data before `at`, and the data after it
+    /// is returned in a new chain allocated from the same pool.
+    ///
+    /// Fails with [`OsError::InvalidParameter`] if `at` is past the end of the chain, or if the
+    /// chain is longer than `u16::MAX` bytes, which NimBLE can't split.
+    pub fn split_off(&mut self, at: usize) -> Result<Self, OsError> {
+        let len = self.len();
+        if at > len || len > u16::MAX as usize {
+            return Err(OsError::InvalidParameter);
+        }
+
+        let tail = self.alloc_from_pool()?;
+        let tail_len = len - at;
+        let ret = unsafe {
+            raw::os_mbuf_appendfrom(
+                tail.om.as_ptr(),
+                self.om.as_ptr(),
+                at as u16,
+                tail_len as u16,
+            )
+        };
+        if ret != 0 {
+            return Err(OsError::from(ret as u32));
+        }
+        self.trim_back(tail_len);
+        Ok(tail)
+    }
+
+    /// Copies the chain into a new chain allocated from the same pool, with `os_mbuf_dup`. This is
+    /// an eager deep copy: every mbuf of the chain is duplicated (so the pool needs as many free
+    /// mbufs as the chain uses), and changes made to one of the chains afterwards aren't visible in
+    /// the other. [`Mbuf::into_shared`] avoids the copy until it's needed.
+    pub fn deep_copy(&self) -> Result<Self, OsError> {
+        unsafe { Self::from_raw(raw::os_mbuf_dup(self.om.as_ptr())) }.ok_or(OsError::NoMem)
+    }
+
+    /// Turns the chain into a [`SharedMbuf`], which can be cloned without copying the data. This
+    /// takes one more mbuf from the same pool, to hold the reference count.
+    ///
+    /// Note: the chain is freed if this fails, like with [`Mbuf::prepend`].
+    pub fn into_shared(self) -> Result<SharedMbuf, OsError> {
+        unsafe {
+            let pool = (*self.om.as_ptr()).om_omp;
+            if ((*pool).omp_databuf_len as usize) < size_of::<SharedChain>() {
+                return Err(OsError::InvalidParameter);
+            }
+            let ctrl = NonNull::new(raw::os_mbuf_get(pool, 0)).ok_or(OsError::NoMem)?;
+            // without leading space, the data starts right after the mbuf header, which is
+            // pointer-aligned
+            let shared = (*ctrl.as_ptr()).om_data as *mut SharedChain;
+            debug_assert!(shared.is_aligned());
+            shared.write(SharedChain {
+                refs: AtomicUsize::new(1),
+                om: self.om,
+            });
+            core::mem::forget(self);
+            Ok(SharedMbuf { ctrl })
+        }
+    }
+
+    /// Allocates an empty mbuf from the same pool as this chain, with the same user header length.
+    fn alloc_from_pool(&self) -> Result<Self, OsError> {
+        unsafe {
+            let om = self.om.as_ptr();
+            let pool = (*om).om_omp;
+            let new = if (*om).om_pkthdr_len as usize >= core::mem::size_of::<raw::os_mbuf_pkthdr>()
+            {
+                let user_hdr_len =
+                    (*om).om_pkthdr_len as usize - core::mem::size_of::<raw::os_mbuf_pkthdr>();
+                raw::os_mbuf_get_pkthdr(pool, user_hdr_len as u8)
+            } else {
+                raw::os_mbuf_get(pool, 0)
+            };
+            Self::from_raw(new).ok_or(OsError::NoMem)
+        }
+    }
+}
+
+impl Drop for Mbuf {
+    fn drop(&mut self) {
+        unsafe { raw::os_mbuf_free_chain(self.om.as_ptr()) };
+    }
+}
+
+impl core::fmt::Debug for Mbuf {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Mbuf").field("len", &self.len()).finish()
+    }
+}
+
+impl defmt::Format for Mbuf {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "Mbuf {{ len: {} }}", self.len())
+    }
+}
+
+/// The reference count and the chain of a [`SharedMbuf`]. It's stored in the data of an extra mbuf
+/// from the chain's pool.
+#[repr(C)]
+struct SharedChain {
+    refs: AtomicUsize,
+    om: NonNull<raw::os_mbuf>,
+}
+
+/// A reference-counted mbuf chain, created with [`Mbuf::into_shared`].
+///
+/// Clones share the chain, which can't be modified while it's shared. [`SharedMbuf::into_mut`]
+/// gives back a [`Mbuf`] to modify: the last reference gets the chain itself, and the others get
+/// a copy of it (clone-on-write).
+pub struct SharedMbuf {
+    /// The mbuf holding the [`SharedChain`].
+    ctrl: NonNull<raw::os_mbuf>,
+}
+
+// Safety: the chain isn't modified while it's shared, and the reference count is atomic
+unsafe impl Send for SharedMbuf {}
+unsafe impl Sync for SharedMbuf {}
+
+impl SharedMbuf {
+    fn shared(&self) -> &SharedChain {
+        unsafe { &*((*self.ctrl.as_ptr()).om_data as *const SharedChain) }
+    }
+
+    fn chain(&self) -> *mut raw::os_mbuf {
+        self.shared().om.as_ptr()
+    }
+
+    /// Total length of the data in the chain.
+    pub fn len(&self) -> usize {
+        unsafe { raw::os_mbuf_len(self.chain()) as usize }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterates over the data in each mbuf of the chain.
+    pub fn segments(&self) -> Segments<'_> {
+        Segments {
+            om: self.chain(),
+            _chain: PhantomData,
+        }
+    }
+
+    /// Copies `buf.len()` bytes starting at `offset` into `buf`. Fails if the chain doesn't have
+    /// enough data.
+    pub fn copy_to(&self, offset: usize, buf: &mut [u8]) -> Result<(), OsError> {
+        let ret = unsafe {
+            raw::os_mbuf_copydata(
+                self.chain(),
+                offset as i32,
+                buf.len() as i32,
+                buf.as_mut_ptr() as *mut cty::c_void,
+            )
+        };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(OsError::InvalidParameter)
+        }
+    }
+
+    /// Number of references to the chain.
+    pub fn ref_count(&self) -> usize {
+        self.shared().refs.load(Ordering::Acquire)
+    }
+
+    /// Gives back a chain that can be modified. If this is the last reference, that's the shared
+    /// chain itself. Otherwise, the chain is copied with [`Mbuf::deep_copy`], and this reference
+    /// is dropped.
+    pub fn into_mut(self) -> Result<Mbuf, OsError> {
+        // no other reference can appear while this is the last one
+        if self.ref_count() == 1 {
+            let om = self.shared().om;
+            unsafe { raw::os_mbuf_free(self.ctrl.as_ptr()) };
+            core::mem::forget(self);
+            return Ok(Mbuf { om });
+        }
+        unsafe { Mbuf::from_raw(raw::os_mbuf_dup(self.chain())) }.ok_or(OsError::NoMem)
+    }
+}
+
+impl Clone for SharedMbuf {
+    fn clone(&self) -> Self {
+        self.shared().refs.fetch_add(1, Ordering::Relaxed);
+        Self { ctrl: self.ctrl }
+    }
+}
+
+impl Drop for SharedMbuf {
+    fn drop(&mut self) {
+        if self.shared().refs.fetch_sub(1, Ordering::Release) != 1 {
+            return;
+        }
+        // see the other clones' accesses to the chain before freeing it
+        fence(Ordering::Acquire);
+        unsafe {
+            raw::os_mbuf_free_chain(self.chain());
+            raw::os_mbuf_free(self.ctrl.as_ptr());
+        }
+    }
+}
+
+impl core::fmt::Debug for SharedMbuf {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SharedMbuf")
+            .field("len", &self.len())
+            .field("refs", &self.ref_count())
+            .finish()
+    }
+}
+
+impl defmt::Format for SharedMbuf {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "SharedMbuf {{ len: {}, refs: {} }}",
+            self.len(),
+            self.ref_count()
+        )
+    }
+}
+
+/// Iterator over the data in each mbuf of a chain. See [`Mbuf::segments`].
+pub struct Segments<'a> {
+    om: *mut raw::os_mbuf,
+    _chain: PhantomData<&'a ()>,
+}
+
+impl<'a> Iterator for Segments<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.om.is_null() {
+            return None;
+        }
+        unsafe {
+            let om = self.om;
+            self.om = (*om).om_next.sle_next;
+            Some(core::slice::from_raw_parts(
+                (*om).om_data,
+                (*om).om_len as usize,
+            ))
+        }
+    }
+}
+
+impl embedded_io::ErrorType for Mbuf {
+    type Error = OsError;
+}
+
+impl embedded_io::Write for Mbuf {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.append(buf).map(|_| buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl embedded_io::Read for Mbuf {
+    /// Reads up to `buf.len()` bytes from the front of the chain. Returns `Ok(0)` once the chain
+    /// is empty.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let len = buf.len().min(self.len());
+        self.copy_to(0, &mut buf[..len])?;
+        self.trim_front(len);
+        Ok(len)
+    }
+}
+
+impl embedded_io::BufRead for Mbuf {
+    /// Returns the data in the first non-empty mbuf of the chain.
+    fn fill_buf(&mut self) -> Result<&[u8], Self::Error> {
+        Ok(self.segments().find(|s| !s.is_empty()).unwrap_or(&[]))
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.trim_front(amt);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_io::{Read, Write};
+
+    use super::{Mbuf, SharedMbuf};
+    use crate::{MbufPool, OsError};
+
+    type Pool = MbufPool<128, 16>;
+
+    fn contents(om: &Mbuf) -> Vec<u8> {
+        om.segments().flatten().copied().collect()
+    }
+
+    fn shared_contents(om: &SharedMbuf) -> Vec<u8> {
+        om.segments().flatten().copied().collect()
+    }
+
+    /// Enough data to span three mbufs.
+    fn data() -> Vec<u8> {
+        (0..(2 * Pool::DATA_LEN + 10) as u32)
+            .map(|i| i as u8)
+            .collect()
+    }
+
+    #[test]
+    fn read_is_chain_aware() {
+        static POOL: Pool = Pool::new(c"read");
+        let data = data();
+        let mut om = POOL.alloc_from_slice(&data).unwrap();
+        assert_eq!(om.segments().count(), 3);
+
+        // short reads that straddle the mbuf boundaries
+        let mut read = Vec::new();
+        let mut buf = [0; 7];
+        loop {
+            let n = om.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            assert!(n == buf.len() || om.is_empty());
+            read.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(read, data);
+        assert!(om.is_empty());
+    }
+
+    #[test]
+    fn write_appends_to_the_chain() {
+        static POOL: Pool = Pool::new(c"write");
+        let data = data();
+        let mut om = POOL.get_pkthdr(0).unwrap();
+        for chunk in data.chunks(33) {
+            om.write_all(chunk).unwrap();
+        }
+        assert_eq!(om.len(), data.len());
+        assert_eq!(contents(&om), data);
+    }
+
+    #[test]
+    fn segments_cover_the_chain() {
+        static POOL: Pool = Pool::new(c"segments");
+        let data = data();
+        let om = POOL.alloc_from_slice(&data).unwrap();
+        let lens: Vec<usize> = om.segments().map(|s| s.len()).collect();
+        assert_eq!(lens, [Pool::DATA_LEN, Pool::DATA_LEN, 10]);
+        assert_eq!(om.first_segment(), &data[..Pool::DATA_LEN]);
+    }
+
+    #[test]
+    fn prepend_adds_to_the_front() {
+        static POOL: Pool = Pool::new(c"prepend");
+        let om = POOL.alloc_from_slice(&[4, 5, 6]).unwrap();
+        let om = om.prepend(&[1, 2, 3]).unwrap();
+        assert_eq!(contents(&om), [1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn pullup_makes_the_front_contiguous() {
+        static POOL: Pool = Pool::new(c"pullup");
+        let data = data();
+        let mut om = POOL.alloc_from_slice(&data).unwrap();
+        om.trim_front(10);
+        assert_eq!(om.first_segment().len(), Pool::DATA_LEN - 10);
+
+        let om = om.pullup(Pool::DATA_LEN - 4).unwrap();
+        assert!(om.first_segment().len() >= Pool::DATA_LEN - 4);
+        assert_eq!(contents(&om), data[10..]);
+    }
+
+    #[test]
+    fn split_off_keeps_the_front() {
+        static POOL: Pool = Pool::new(c"split");
+        let data = data();
+        let mut om = POOL.alloc_from_slice(&data).unwrap();
+        let tail = om.split_off(Pool::DATA_LEN + 5).unwrap();
+        assert_eq!(contents(&om), data[..Pool::DATA_LEN + 5]);
+        assert_eq!(contents(&tail), data[Pool::DATA_LEN + 5..]);
+
+        assert!(om.split_off(om.len() + 1).is_err());
+    }
+
+    #[test]
+    fn split_off_rejects_long_chains() {
+        static POOL: MbufPool<4096, 20> = MbufPool::new(c"split-long");
+        let mut om = POOL.get_pkthdr(0).unwrap();
+        let chunk = [0x5a; 4096];
+        while om.len() <= u16::MAX as usize {
+            om.append(&chunk).unwrap();
+        }
+        let len = om.len();
+
+        // the offsets would be truncated to 16 bits
+        assert!(matches!(om.split_off(10), Err(OsError::InvalidParameter)));
+        assert!(matches!(
+            om.split_off(u16::MAX as usize + 1),
+            Err(OsError::InvalidParameter)
+        ));
+        assert_eq!(om.len(), len);
+    }
+
+    #[test]
+    fn trim_removes_from_both_ends() {
+        static POOL: Pool = Pool::new(c"trim");
+        let data = data();
+        let mut om = POOL.alloc_from_slice(&data).unwrap();
+        om.trim_front(Pool::DATA_LEN + 1);
+        om.trim_back(3);
+        assert_eq!(contents(&om), data[Pool::DATA_LEN + 1..data.len() - 3]);
+    }
+
+    #[test]
+    fn deep_copy_is_independent() {
+        static POOL: Pool = Pool::new(c"copy");
+        let data = data();
+        let mut om = POOL.alloc_from_slice(&data).unwrap();
+        let copy = om.deep_copy().unwrap();
+        assert_eq!(POOL.free_blocks(), POOL.capacity() - 6);
+
+        om.trim_front(5);
+        assert_eq!(contents(&copy), data);
+    }
+
+    #[test]
+    fn shared_clones_share_the_chain() {
+        static POOL: Pool = Pool::new(c"shared");
+        let data = data();
+        let shared = POOL.alloc_from_slice(&data).unwrap().into_shared().unwrap();
+        // the chain, and the reference count
+        assert_eq!(POOL.free_blocks(), POOL.capacity() - 4);
+
+        let clones = [shared.clone(), shared.clone()];
+        assert_eq!(shared.ref_count(), 3);
+        assert_eq!(POOL.free_blocks(), POOL.capacity() - 4);
+        for clone in &clones {
+            assert_eq!(shared_contents(clone), data);
+        }
+
+        drop(clones);
+        assert_eq!(shared.ref_count(), 1);
+        drop(shared);
+        assert_eq!(POOL.free_blocks(), POOL.capacity());
+    }
+
+    #[test]
+    fn into_mut_copies_a_shared_chain() {
+        static POOL: Pool = Pool::new(c"cow");
+        let data = data();
+        let shared = POOL.alloc_from_slice(&data).unwrap().into_shared().unwrap();
+        let other = shared.clone();
+
+        let mut om = shared.into_mut().unwrap();
+        assert_eq!(POOL.free_blocks(), POOL.capacity() - 7);
+        om.trim_front(5);
+        om.append(&[1, 2, 3]).unwrap();
+        assert_eq!(other.ref_count(), 1);
+        assert_eq!(shared_contents(&other), data);
+
+        drop(om);
+        drop(other);
+        assert_eq!(POOL.free_blocks(), POOL.capacity());
+    }
+
+    #[test]
+    fn into_mut_takes_back_the_last_reference() {
+        static POOL: Pool = Pool::new(c"unshare");
+        let data = data();
+        let om = POOL.alloc_from_slice(&data).unwrap();
+        let chain = om.as_ptr();
+        let shared = om.into_shared().unwrap();
+
+        let om = shared.into_mut().unwrap();
+        assert_eq!(om.as_ptr(), chain);
+        assert_eq!(POOL.free_blocks(), POOL.capacity() - 3);
+        assert_eq!(contents(&om), data);
+    }
+
+    #[test]
+    fn drop_frees_the_chain() {
+        static POOL: Pool = Pool::new(c"drop");
+        let om = POOL.alloc_from_slice(&data()).unwrap();
+        assert_eq!(POOL.free_blocks(), POOL.capacity() - 3);
+        drop(om);
+        assert_eq!(POOL.free_blocks(), POOL.capacity());
+    }
+}
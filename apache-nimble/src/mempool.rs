@@ -0,0 +1,373 @@
+use core::cell::UnsafeCell;
+use core::ffi::CStr;
+use core::mem::{size_of, ManuallyDrop, MaybeUninit};
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+
+use crate::stats::{self, MsysLink};
+use crate::{is_current, raw, Mbuf, OsError, GENERATION};
+
+const UNINIT: u8 = 0;
+const INITIALIZING: u8 = 1;
+const READY: u8 = 2;
+
+/// Initializes a pool the first time it's used. Returns [`OsError::Busy`] if the pool is being
+/// initialized from another context at the same time.
+fn init_once(state: &AtomicU8, init: impl FnOnce() -> Result<(), OsError>) -> Result<(), OsError> {
+    match state.compare_exchange(UNINIT, INITIALIZING, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => match init() {
+            Ok(()) => {
+                state.store(READY, Ordering::Release);
+                Ok(())
+            }
+            Err(e) => {
+                state.store(UNINIT, Ordering::Release);
+                Err(e)
+            }
+        },
+        Err(READY) => Ok(()),
+        Err(_) => Err(OsError::Busy),
+    }
+}
+
+/// A block of a [`MemPool`]. NimBLE keeps its free list in the first word of each free block, so
+/// blocks need to be at least as large (and as aligned) as a pointer.
+#[repr(C)]
+union Block<T> {
+    _value: ManuallyDrop<MaybeUninit<T>>,
+    _next: *mut u8,
+}
+
+/// A fixed-size pool of `N` values of type `T`, backed by a NimBLE `os_mempool`. Intended to be
+/// stored in a `static`:
+///
+/// ```ignore
+/// static PAYLOADS: MemPool<[u8; 64], 8> = MemPool::new(c"payloads");
+///
+/// let payload = PAYLOADS.alloc([0; 64]).unwrap();
+/// ```
+///
+/// The pool is initialized on first use.
+pub struct MemPool<T, const N: usize> {
+    pool: UnsafeCell<MaybeUninit<raw::os_mempool>>,
+    storage: UnsafeCell<[MaybeUninit<Block<T>>; N]>,
+    state: AtomicU8,
+    name: &'static CStr,
+}
+
+// Safety: the pool is only initialized once, and NimBLE protects allocation with a critical
+// section.
+unsafe impl<T: Send, const N: usize> Sync for MemPool<T, N> {}
+
+impl<T, const N: usize> MemPool<T, N> {
+    const BLOCK_SIZE: usize = size_of::<Block<T>>();
+    const VALID: () = assert!(N > 0 && N <= u16::MAX as usize);
+
+    pub const fn new(name: &'static CStr) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::VALID;
+
+        Self {
+            pool: UnsafeCell::new(MaybeUninit::uninit()),
+            storage: UnsafeCell::new([const { MaybeUninit::uninit() }; N]),
+            state: AtomicU8::new(UNINIT),
+            name,
+        }
+    }
+
+    fn raw(&'static self) -> Result<*mut raw::os_mempool, OsError> {
+        let pool = self.pool.get() as *mut raw::os_mempool;
+        init_once(&self.state, || {
+            let ret = unsafe {
+                raw::os_mempool_init(
+                    pool,
+                    N as u16,
+                    Self::BLOCK_SIZE as u32,
+                    self.storage.get() as *mut cty::c_void,
+                    self.name.as_ptr() as *const cty::c_char,
+                )
+            };
+            if ret == 0 {
+                Ok(())
+            } else {
+                Err(OsError::from(ret))
+            }
+        })?;
+        Ok(pool)
+    }
+
+    /// Moves `value` into a free block of the pool. If the pool is exhausted (or not available
+    /// yet), `value` is given back.
+    pub fn alloc(&'static self, value: T) -> Result<PoolBox<T>, T> {
+        let Ok(pool) = self.raw() else {
+            return Err(value);
+        };
+
+        let block = unsafe { raw::os_memblock_get(pool) } as *mut T;
+        match NonNull::new(block) {
+            Some(ptr) => {
+                unsafe { ptr.as_ptr().write(value) };
+                Ok(PoolBox { ptr, pool })
+            }
+            None => Err(value),
+        }
+    }
+
+    /// Number of blocks that can still be allocated.
+    pub fn free_blocks(&'static self) -> usize {
+        match self.raw() {
+            Ok(pool) => unsafe { (*pool).mp_num_free as usize },
+            Err(_) => N,
+        }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+}
+
+/// A value allocated from a [`MemPool`]. The block is returned to the pool when this is dropped.
+pub struct PoolBox<T: 'static> {
+    ptr: NonNull<T>,
+    pool: *mut raw::os_mempool,
+}
+
+// Safety: the value is uniquely owned, and returning the block is protected by a critical section.
+unsafe impl<T: Send> Send for PoolBox<T> {}
+
+impl<T> Deref for PoolBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for PoolBox<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for PoolBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            core::ptr::drop_in_place(self.ptr.as_ptr());
+            raw::os_memblock_put(self.pool, self.ptr.as_ptr() as *mut cty::c_void);
+        }
+    }
+}
+
+impl<T: core::fmt::Debug> core::fmt::Debug for PoolBox<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<T: defmt::Format> defmt::Format for PoolBox<T> {
+    fn format(&self, fmt: defmt::Formatter) {
+        (**self).format(fmt)
+    }
+}
+
+#[repr(C, align(4))]
+struct MbufStorage<const BLOCK_SIZE: usize, const N: usize>([[u8; BLOCK_SIZE]; N]);
+
+/// A pool of `N` mbufs of `BLOCK_SIZE` bytes each (including the `os_mbuf` header), backed by a
+/// NimBLE `os_mbuf_pool`. Intended to be stored in a `static`, and initialized on first use.
+///
+/// The pool can also be registered with msys, which lets NimBLE allocate from it (see
+/// [`MbufPool::register_msys`]).
+pub struct MbufPool<const BLOCK_SIZE: usize, const N: usize> {
+    mempool: UnsafeCell<MaybeUninit<raw::os_mempool>>,
+    mbuf_pool: UnsafeCell<MaybeUninit<raw::os_mbuf_pool>>,
+    storage: UnsafeCell<MbufStorage<BLOCK_SIZE, N>>,
+    state: AtomicU8,
+    /// The generation the pool was last registered with msys in, plus one (0 if it never was).
+    msys_generation: AtomicU32,
+    msys_link: MsysLink,
+    name: &'static CStr,
+}
+
+// Safety: the pool is only initialized once, and NimBLE protects allocation with a critical
+// section.
+unsafe impl<const BLOCK_SIZE: usize, const N: usize> Sync for MbufPool<BLOCK_SIZE, N> {}
+
+impl<const BLOCK_SIZE: usize, const N: usize> MbufPool<BLOCK_SIZE, N> {
+    /// Amount of data that fits in a single packet header mbuf from this pool.
+    pub const DATA_LEN: usize =
+        BLOCK_SIZE - size_of::<raw::os_mbuf>() - size_of::<raw::os_mbuf_pkthdr>();
+
+    const VALID: () = assert!(
+        BLOCK_SIZE.is_multiple_of(4)
+            && BLOCK_SIZE > size_of::<raw::os_mbuf>() + size_of::<raw::os_mbuf_pkthdr>()
+            && BLOCK_SIZE <= u16::MAX as usize
+            && N > 0
+            && N <= u16::MAX as usize,
+        "mbuf pool blocks must be 4-byte aligned and larger than the mbuf headers"
+    );
+
+    pub const fn new(name: &'static CStr) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::VALID;
+
+        Self {
+            mempool: UnsafeCell::new(MaybeUninit::uninit()),
+            mbuf_pool: UnsafeCell::new(MaybeUninit::uninit()),
+            storage: UnsafeCell::new(MbufStorage([[0; BLOCK_SIZE]; N])),
+            state: AtomicU8::new(UNINIT),
+            msys_generation: AtomicU32::new(0),
+            msys_link: MsysLink::new(),
+            name,
+        }
+    }
+
+    /// Pointer to the underlying `os_mbuf_pool`, initializing it if needed.
+    pub fn raw(&'static self) -> Result<*mut raw::os_mbuf_pool, OsError> {
+        let mempool = self.mempool.get() as *mut raw::os_mempool;
+        let mbuf_pool = self.mbuf_pool.get() as *mut raw::os_mbuf_pool;
+        init_once(&self.state, || unsafe {
+            let ret = raw::os_mempool_init(
+                mempool,
+                N as u16,
+                BLOCK_SIZE as u32,
+                self.storage.get() as *mut cty::c_void,
+                self.name.as_ptr() as *const cty::c_char,
+            );
+            if ret != 0 {
+                return Err(OsError::from(ret));
+            }
+            let ret = raw::os_mbuf_pool_init(mbuf_pool, mempool, BLOCK_SIZE as u16, N as u16);
+            if ret != 0 {
+                return Err(OsError::from(ret as u32));
+            }
+            Ok(())
+        })?;
+        Ok(mbuf_pool)
+    }
+
+    /// Allocates a packet header mbuf, with `user_hdr_len` bytes reserved for a user header.
+    pub fn get_pkthdr(&'static self, user_hdr_len: u8) -> Result<Mbuf, OsError> {
+        let pool = self.raw()?;
+        unsafe { Mbuf::from_raw(raw::os_mbuf_get_pkthdr(pool, user_hdr_len)) }.ok_or(OsError::NoMem)
+    }
+
+    /// Allocates a packet header mbuf and copies `data` into it, chaining more mbufs from this
+    /// pool if needed.
+    pub fn alloc_from_slice(&'static self, data: &[u8]) -> Result<Mbuf, OsError> {
+        let mut om = self.get_pkthdr(0)?;
+        om.append(data)?;
+        Ok(om)
+    }
+
+    /// Registers this pool with msys, so that `os_msys_get` (and NimBLE's own allocations) can use
+    /// it. msys picks the smallest registered pool that fits the requested size.
+    ///
+    /// This needs to be done after NimBLE is initialized, since initializing msys resets the list
+    /// of registered pools. A pool can only be registered once per [`crate::Nimble::init`]: after
+    /// that, this fails with [`OsError::Invalid`].
+    pub fn register_msys(&'static self) -> Result<(), OsError> {
+        let generation = GENERATION.load(Ordering::Acquire);
+        if !is_current(generation) {
+            return Err(OsError::NotStarted);
+        }
+        let pool = self.raw()?;
+        // msys doesn't check for duplicates, and registering the pool twice would corrupt its list
+        let registered = generation.wrapping_add(1);
+        let previous = self.msys_generation.swap(registered, Ordering::AcqRel);
+        if previous == registered {
+            return Err(OsError::Invalid);
+        }
+        let ret = unsafe { raw::os_msys_register(pool) };
+        if ret == 0 {
+            stats::add_msys_pool(
+                &self.msys_link,
+                self.mempool.get() as *const raw::os_mempool,
+            );
+            Ok(())
+        } else {
+            self.msys_generation.store(previous, Ordering::Release);
+            Err(OsError::from(ret as u32))
+        }
+    }
+
+    /// Number of mbufs that can still be allocated.
+    pub fn free_blocks(&'static self) -> usize {
+        match self.raw() {
+            Ok(_) => unsafe {
+                (*(self.mempool.get() as *mut raw::os_mempool)).mp_num_free as usize
+            },
+            Err(_) => N,
+        }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+
+    use super::{MbufPool, MemPool};
+    use crate::test_support::lock_stack;
+    use crate::{raw, Config, Nimble, OsError};
+
+    #[test]
+    fn mempool_alloc_until_exhausted() {
+        static POOL: MemPool<[u32; 4], 3> = MemPool::new(c"values");
+        assert_eq!(POOL.free_blocks(), 3);
+
+        let values: Vec<_> = (0..3).map(|i| POOL.alloc([i; 4]).unwrap()).collect();
+        assert_eq!(POOL.free_blocks(), 0);
+        assert_eq!(POOL.alloc([7; 4]).unwrap_err(), [7; 4]);
+        for (i, value) in values.iter().enumerate() {
+            assert_eq!(**value, [i as u32; 4]);
+        }
+
+        drop(values);
+        assert_eq!(POOL.free_blocks(), 3);
+        let mut value = POOL.alloc([0; 4]).unwrap();
+        value[1] = 5;
+        assert_eq!(*value, [0, 5, 0, 0]);
+    }
+
+    #[test]
+    fn mbuf_pool_alloc_until_exhausted() {
+        static POOL: MbufPool<64, 4> = MbufPool::new(c"mbufs");
+        let mbufs: Vec<_> = (0..4).map(|_| POOL.get_pkthdr(0).unwrap()).collect();
+        assert_eq!(POOL.free_blocks(), 0);
+        assert!(matches!(POOL.get_pkthdr(0), Err(OsError::NoMem)));
+
+        drop(mbufs);
+        assert_eq!(POOL.free_blocks(), POOL.capacity());
+        assert!(POOL.get_pkthdr(0).is_ok());
+    }
+
+    #[test]
+    fn msys_serves_from_a_registered_pool() {
+        // smaller than the default msys pool, so that msys picks it for small allocations
+        static POOL: MbufPool<64, 4> = MbufPool::new(c"msys");
+        let _stack = lock_stack();
+        assert!(matches!(POOL.register_msys(), Err(OsError::NotStarted)));
+
+        for _ in 0..2 {
+            let _nimble = Nimble::init(Config::default()).unwrap();
+            POOL.register_msys().unwrap();
+            // a second registration would insert the pool into the msys list twice
+            assert!(matches!(POOL.register_msys(), Err(OsError::Invalid)));
+
+            let om = unsafe { raw::os_msys_get(8, 0) };
+            assert!(!om.is_null());
+            assert_eq!(unsafe { (*om).om_omp }, POOL.raw().unwrap());
+            assert_eq!(POOL.free_blocks(), POOL.capacity() - 1);
+            unsafe { raw::os_mbuf_free_chain(om) };
+            assert_eq!(POOL.free_blocks(), POOL.capacity());
+
+            // msys is reset by the next init, so the pool can be registered again
+            block_on(Nimble::shutdown()).unwrap();
+        }
+    }
+}
@@ -0,0 +1,279 @@
+use core::cell::Cell;
+use core::ffi::CStr;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+
+use crate::raw;
+
+/// NimBLE's own memory pools.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Pool {
+    /// The first msys pool (`msys_1`), used for mbufs allocated by the host and by
+    /// [`crate::Mbuf::new`]. Pools added with [`crate::MbufPool::register_msys`] are reported by
+    /// [`for_each_msys_pool`], along with this one.
+    Msys,
+    /// HCI commands.
+    TransportCmd,
+    /// HCI events.
+    TransportEvt,
+    /// HCI events that can be dropped when the pool is exhausted, such as advertising reports.
+    TransportEvtDiscardable,
+    /// ACL data sent by the host.
+    TransportAclFromHs,
+    /// ACL data received by the link layer.
+    TransportAclFromLl,
+}
+
+impl Pool {
+    const ALL: [Pool; 6] = [
+        Pool::Msys,
+        Pool::TransportCmd,
+        Pool::TransportEvt,
+        Pool::TransportEvtDiscardable,
+        Pool::TransportAclFromHs,
+        Pool::TransportAclFromLl,
+    ];
+
+    /// Name the pool is registered with in NimBLE.
+    pub fn name(self) -> &'static CStr {
+        match self {
+            Pool::Msys => c"msys_1",
+            Pool::TransportCmd => c"transport_pool_cmd",
+            Pool::TransportEvt => c"transport_pool_evt",
+            Pool::TransportEvtDiscardable => c"transport_pool_evt_lo",
+            Pool::TransportAclFromHs => c"transport_pool_acl_from_hs",
+            Pool::TransportAclFromLl => c"transport_pool_acl_from_ll",
+        }
+    }
+}
+
+static ALLOC_FAILURES: [AtomicU32; Pool::ALL.len()] =
+    [const { AtomicU32::new(0) }; Pool::ALL.len()];
+
+/// Records that an allocation from `pool` made by this crate failed. NimBLE doesn't count its own
+/// failed allocations.
+pub(crate) fn record_alloc_failure(pool: Pool) {
+    ALLOC_FAILURES[pool as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Usage of a single memory pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct PoolStats {
+    pub block_size: usize,
+    /// Number of blocks in the pool.
+    pub total: usize,
+    /// Number of blocks that are currently free.
+    pub free: usize,
+    /// Lowest number of free blocks since the pool was initialized. A value of 0 means the pool
+    /// has been exhausted at some point.
+    pub min_free: usize,
+    /// Number of failed allocations made by this crate: HCI commands and ACL data written to the
+    /// controller, and `Mbuf`s allocated from msys. NimBLE's pools don't count failures, so
+    /// allocations made inside NimBLE itself (by the host or the link layer) aren't included. A
+    /// `min_free` of 0 is the only sign that those have run out of blocks.
+    pub crate_alloc_failures: u32,
+}
+
+/// Usage of all of NimBLE's memory pools. Pools that aren't used with the enabled features are
+/// `None`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Stats {
+    pub msys: Option<PoolStats>,
+    pub transport_cmd: Option<PoolStats>,
+    pub transport_evt: Option<PoolStats>,
+    pub transport_evt_discardable: Option<PoolStats>,
+    pub transport_acl_from_hs: Option<PoolStats>,
+    pub transport_acl_from_ll: Option<PoolStats>,
+}
+
+/// Reads the current usage of NimBLE's memory pools.
+pub fn stats() -> Stats {
+    Stats {
+        msys: pool_stats(Pool::Msys),
+        transport_cmd: pool_stats(Pool::TransportCmd),
+        transport_evt: pool_stats(Pool::TransportEvt),
+        transport_evt_discardable: pool_stats(Pool::TransportEvtDiscardable),
+        transport_acl_from_hs: pool_stats(Pool::TransportAclFromHs),
+        transport_acl_from_ll: pool_stats(Pool::TransportAclFromLl),
+    }
+}
+
+/// Reads the current usage of one of NimBLE's memory pools. Returns `None` if the pool isn't
+/// initialized.
+pub fn pool_stats(pool: Pool) -> Option<PoolStats> {
+    let mut stats = find_pool(pool.name())?;
+    stats.crate_alloc_failures = ALLOC_FAILURES[pool as usize].load(Ordering::Relaxed);
+    Some(stats)
+}
+
+/// Reads the current usage of any initialized pool, including the ones created with
+/// [`crate::MemPool`] and [`crate::MbufPool`], by name.
+pub fn find_pool(name: &CStr) -> Option<PoolStats> {
+    let mut found = None;
+    for_each_pool(|pool_name, stats| {
+        if found.is_none() && pool_name == name {
+            found = Some(stats);
+        }
+    });
+    found
+}
+
+/// A pool registered with msys by [`crate::MbufPool::register_msys`], in the list of pools that
+/// [`for_each_msys_pool`] reports.
+pub(crate) struct MsysLink {
+    mempool: Cell<*const raw::os_mempool>,
+    next: Cell<Option<&'static MsysLink>>,
+}
+
+// Safety: the links are only accessed with `MSYS_POOLS` locked
+unsafe impl Sync for MsysLink {}
+unsafe impl Send for MsysLink {}
+
+impl MsysLink {
+    pub(crate) const fn new() -> Self {
+        Self {
+            mempool: Cell::new(core::ptr::null()),
+            next: Cell::new(None),
+        }
+    }
+}
+
+/// Pools registered with msys since it was last initialized, newest first.
+static MSYS_POOLS: Mutex<CriticalSectionRawMutex, Cell<Option<&'static MsysLink>>> =
+    Mutex::new(Cell::new(None));
+
+/// Adds a pool to the ones reported by [`for_each_msys_pool`]. Each link can only be added once
+/// per msys initialization.
+pub(crate) fn add_msys_pool(link: &'static MsysLink, mempool: *const raw::os_mempool) {
+    MSYS_POOLS.lock(|pools| {
+        link.mempool.set(mempool);
+        link.next.set(pools.get());
+        pools.set(Some(link));
+    });
+}
+
+/// Forgets the registered pools, when msys is initialized again.
+pub(crate) fn reset_msys_pools() {
+    MSYS_POOLS.lock(|pools| pools.set(None));
+}
+
+fn mempool_stats(mp: *const raw::os_mempool) -> PoolStats {
+    let mp = unsafe { &*mp };
+    PoolStats {
+        block_size: mp.mp_block_size as usize,
+        total: mp.mp_num_blocks as usize,
+        free: mp.mp_num_free as usize,
+        min_free: mp.mp_min_free as usize,
+        crate_alloc_failures: 0,
+    }
+}
+
+/// Calls `f` with the name and usage of every msys pool: NimBLE's own (`msys_1`, and `msys_2` if
+/// it's enabled), then the ones added with [`crate::MbufPool::register_msys`], newest first.
+/// Allocation failures are only counted for msys as a whole, in [`Pool::Msys`].
+pub fn for_each_msys_pool(mut f: impl FnMut(&CStr, PoolStats)) {
+    for_each_pool(|name, stats| {
+        if name == c"msys_1" || name == c"msys_2" {
+            f(name, stats);
+        }
+    });
+    let mut link = MSYS_POOLS.lock(Cell::get);
+    while let Some(pool) = link {
+        let (mempool, next) = MSYS_POOLS.lock(|_| (pool.mempool.get(), pool.next.get()));
+        let name = unsafe { CStr::from_ptr((*mempool).name) };
+        f(name, mempool_stats(mempool));
+        link = next;
+    }
+}
+
+/// Calls `f` with the name and usage of every initialized pool.
+pub fn for_each_pool(mut f: impl FnMut(&CStr, PoolStats)) {
+    let mut mp = core::ptr::null_mut();
+    loop {
+        let mut info = MaybeUninit::<raw::os_mempool_info>::uninit();
+        mp = unsafe { raw::os_mempool_info_get_next(mp, info.as_mut_ptr()) };
+        if mp.is_null() {
+            break;
+        }
+
+        let info = unsafe { info.assume_init_ref() };
+        // Names that don't fit are truncated without a nul terminator.
+        let name = unsafe {
+            core::slice::from_raw_parts(info.omi_name.as_ptr() as *const u8, info.omi_name.len())
+        };
+        let name = CStr::from_bytes_until_nul(name).unwrap_or_default();
+        f(
+            name,
+            PoolStats {
+                block_size: info.omi_block_size as usize,
+                total: info.omi_num_blocks as usize,
+                free: info.omi_num_free as usize,
+                min_free: info.omi_min_free as usize,
+                crate_alloc_failures: 0,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+
+    use super::{for_each_msys_pool, pool_stats, Pool};
+    use crate::test_support::lock_stack;
+    use crate::{Config, Mbuf, MbufPool, Nimble};
+
+    #[test]
+    fn msys_stats_track_exhaustion() {
+        let _stack = lock_stack();
+        let _nimble = Nimble::init(Config::default()).unwrap();
+        let before = pool_stats(Pool::Msys).unwrap();
+        assert_eq!(before.free, before.total);
+
+        let mut mbufs = Vec::new();
+        while let Ok(om) = Mbuf::new() {
+            mbufs.push(om);
+        }
+        let exhausted = pool_stats(Pool::Msys).unwrap();
+        assert_eq!(mbufs.len(), before.total);
+        assert_eq!(exhausted.free, 0);
+        assert_eq!(exhausted.min_free, 0);
+        assert_eq!(
+            exhausted.crate_alloc_failures,
+            before.crate_alloc_failures + 1
+        );
+
+        drop(mbufs);
+        let after = pool_stats(Pool::Msys).unwrap();
+        assert_eq!(after.free, after.total);
+        assert_eq!(after.min_free, 0);
+        block_on(Nimble::shutdown()).unwrap();
+    }
+
+    #[test]
+    fn msys_stats_cover_registered_pools() {
+        static POOL: MbufPool<64, 3> = MbufPool::new(c"extra_msys");
+        let _stack = lock_stack();
+        for _ in 0..2 {
+            let _nimble = Nimble::init(Config::default()).unwrap();
+            let mut names = Vec::new();
+            for_each_msys_pool(|name, _| names.push(name.to_owned()));
+            assert_eq!(names, [c"msys_1".to_owned()]);
+
+            POOL.register_msys().unwrap();
+            let _om = POOL.get_pkthdr(0).unwrap();
+            let mut pools = Vec::new();
+            for_each_msys_pool(|name, stats| pools.push((name.to_owned(), stats)));
+            assert_eq!(pools.len(), 2);
+            assert_eq!(pools[0].0.as_c_str(), c"msys_1");
+            assert_eq!(pools[1].0.as_c_str(), c"extra_msys");
+            assert_eq!((pools[1].1.total, pools[1].1.free), (3, 2));
+
+            // msys is initialized again, without the registered pools
+            block_on(Nimble::shutdown()).unwrap();
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! Support for the unit tests, which run on the machine doing the build (see `is_native` in
+//! build.rs).
+
+use std::sync::{Mutex, MutexGuard};
+
+/// NimBLE's state is global, so the tests that use it can't run in parallel.
+static STACK: Mutex<()> = Mutex::new(());
+
+/// Serializes the tests that use NimBLE. A test that panicked doesn't poison the lock for the
+/// others.
+pub(crate) fn lock_stack() -> MutexGuard<'static, ()> {
+    STACK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[defmt::global_logger]
+struct Logger;
+
+unsafe impl defmt::Logger for Logger {
+    fn acquire() {}
+    unsafe fn flush() {}
+    unsafe fn release() {}
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+/// Stands in for the link layer and its driver, which can't run without a radio. Commands are
+/// recorded, so that the tests can answer them in whatever order they need.
+#[cfg(feature = "controller")]
+pub(crate) mod fake_ll {
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    use crate::raw;
+
+    static SENT_CMDS: Mutex<VecDeque<usize>> = Mutex::new(VecDeque::new());
+
+    /// Takes the buffer of the oldest command sent to the link layer. The response needs to be
+    /// written to the same buffer, as the real link layer does.
+    pub(crate) fn take_cmd() -> Option<*mut cty::c_void> {
+        SENT_CMDS
+            .lock()
+            .unwrap()
+            .pop_front()
+            .map(|buf| buf as *mut cty::c_void)
+    }
+
+    #[no_mangle]
+    static mut g_ble_ll_data: raw::ble_ll_obj = unsafe { core::mem::zeroed() };
+    #[no_mangle]
+    static mut g_ble_ll_tx_power: cty::int8_t = 0;
+
+    #[no_mangle]
+    unsafe extern "C" fn ble_ll_init() {
+        SENT_CMDS.lock().unwrap().clear();
+        raw::ble_npl_eventq_init(core::ptr::addr_of_mut!(g_ble_ll_data.ll_evq));
+    }
+
+    #[no_mangle]
+    extern "C" fn ble_ll_reset() -> cty::c_int {
+        0
+    }
+
+    #[no_mangle]
+    extern "C" fn ble_ll_tx_power_round(power: cty::c_int) -> cty::c_int {
+        power
+    }
+
+    #[no_mangle]
+    extern "C" fn ble_transport_ll_init() {}
+
+    #[no_mangle]
+    extern "C" fn ble_transport_to_ll_cmd_impl(buf: *mut cty::c_void) -> cty::c_int {
+        SENT_CMDS.lock().unwrap().push_back(buf as usize);
+        0
+    }
+
+    #[no_mangle]
+    unsafe extern "C" fn ble_transport_to_ll_acl_impl(om: *mut raw::os_mbuf) -> cty::c_int {
+        raw::os_mbuf_free_chain(om);
+        0
+    }
+
+    #[no_mangle]
+    unsafe extern "C" fn ble_transport_to_ll_iso_impl(om: *mut raw::os_mbuf) -> cty::c_int {
+        raw::os_mbuf_free_chain(om);
+        0
+    }
+
+    #[no_mangle]
+    extern "C" fn ble_phy_init() -> cty::c_int {
+        0
+    }
+
+    #[no_mangle]
+    extern "C" fn ble_phy_disable() {}
+
+    #[repr(C)]
+    struct AesKeySched {
+        words: [u32; 44],
+    }
+
+    extern "C" {
+        fn tc_aes128_set_encrypt_key(s: *mut AesKeySched, k: *const u8) -> cty::c_int;
+        fn tc_aes_encrypt(out: *mut u8, input: *const u8, s: *const AesKeySched) -> cty::c_int;
+    }
+
+    /// Software AES-128 with tinycrypt, which takes the key and data in the same order as the
+    /// nRF5x ECB peripheral.
+    #[no_mangle]
+    unsafe extern "C" fn ble_hw_encrypt_block(ecb: *mut raw::ble_encryption_block) -> cty::c_int {
+        let mut sched = AesKeySched { words: [0; 44] };
+        if tc_aes128_set_encrypt_key(&mut sched, (*ecb).key.as_ptr()) != 1
+            || tc_aes_encrypt(
+                (*ecb).cipher_text.as_mut_ptr(),
+                (*ecb).plain_text.as_ptr(),
+                &sched,
+            ) != 1
+        {
+            return -1;
+        }
+        0
+    }
+
+    // hal_timer.c drives the nRF RTC, so cputime gets a timer that never fires
+
+    #[no_mangle]
+    extern "C" fn hal_timer_init(_timer_num: cty::c_int, _cfg: *mut cty::c_void) -> cty::c_int {
+        0
+    }
+
+    #[no_mangle]
+    extern "C" fn hal_timer_deinit(_timer_num: cty::c_int) -> cty::c_int {
+        0
+    }
+
+    #[no_mangle]
+    extern "C" fn hal_timer_config(_timer_num: cty::c_int, _freq_hz: u32) -> cty::c_int {
+        0
+    }
+
+    #[no_mangle]
+    extern "C" fn hal_timer_get_resolution(_timer_num: cty::c_int) -> u32 {
+        1
+    }
+
+    #[no_mangle]
+    extern "C" fn hal_timer_read(_timer_num: cty::c_int) -> u32 {
+        0
+    }
+
+    #[no_mangle]
+    extern "C" fn hal_timer_delay(_timer_num: cty::c_int, _ticks: u32) -> cty::c_int {
+        0
+    }
+
+    #[no_mangle]
+    extern "C" fn hal_timer_set_cb(
+        _timer_num: cty::c_int,
+        _timer: *mut cty::c_void,
+        _cb: *mut cty::c_void,
+        _arg: *mut cty::c_void,
+    ) -> cty::c_int {
+        0
+    }
+
+    #[no_mangle]
+    extern "C" fn hal_timer_start(_timer: *mut cty::c_void, _ticks: u32) -> cty::c_int {
+        0
+    }
+
+    #[no_mangle]
+    extern "C" fn hal_timer_start_at(_timer: *mut cty::c_void, _tick: u32) -> cty::c_int {
+        0
+    }
+
+    #[no_mangle]
+    extern "C" fn hal_timer_stop(_timer: *mut cty::c_void) -> cty::c_int {
+        0
+    }
+}